@@ -0,0 +1,45 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Output of the command if it succeeded, otherwise `unknown`, e.g. when building from a
+/// source archive without the git history.
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_owned())
+        .filter(|output| !output.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Provides the values of the `recentmessages_build_info` metric.
+fn main() {
+    let git_commit = command_output("git", &["rev-parse", "HEAD"]);
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = command_output(&rustc, &["--version"]);
+    println!("cargo:rustc-env=RECENT_MESSAGES_GIT_COMMIT={}", git_commit);
+    println!(
+        "cargo:rustc-env=RECENT_MESSAGES_RUSTC_VERSION={}",
+        rustc_version
+    );
+    for path in git_commit_files() {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
+
+/// The files that change when a commit is made or checked out: `HEAD`, the ref of the current
+/// branch it points to, and the packed refs (where the ref is if it wasn't changed since the
+/// last `git gc`). Files that don't exist are left out, cargo would rerun this every time.
+fn git_commit_files() -> Vec<String> {
+    let mut paths = vec![".git/HEAD".to_owned(), ".git/packed-refs".to_owned()];
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(current_ref) = head.trim().strip_prefix("ref: ") {
+            paths.push(format!(".git/{}", current_ref));
+        }
+    }
+    paths.retain(|path| Path::new(path).exists());
+    paths
+}
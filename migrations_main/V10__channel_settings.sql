@@ -0,0 +1,9 @@
+-- export options the channel owner forces on for all requests for their channel's messages.
+-- Kept separately from the channel table, so the settings survive the channel expiring.
+CREATE TABLE channel_settings
+(
+    channel_login            TEXT PRIMARY KEY NOT NULL,
+    hide_moderation_messages BOOLEAN          NOT NULL DEFAULT FALSE,
+    hide_moderated_messages  BOOLEAN          NOT NULL DEFAULT FALSE,
+    clearchat_to_notice      BOOLEAN          NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE channel_settings
+    ADD COLUMN hide_room_mode_notices BOOLEAN NOT NULL DEFAULT FALSE;
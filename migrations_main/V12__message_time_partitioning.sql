@@ -0,0 +1,25 @@
+-- Only used with app.message_partition_interval: turns the message table into a table partitioned by
+-- time_received. The existing table is kept as the partition for everything before `boundary`, it must
+-- have a validated CHECK (time_received < boundary) constraint already so attaching it doesn't scan it
+-- while the table is locked. Messages for which there is no partition (yet) go to message_pdefault.
+-- Unique indexes of a partitioned table must include the partition key, so the twitch_message_id index is
+-- created on every partition instead (the previous table keeps its own): duplicates are only detected within
+-- the same partition.
+CREATE FUNCTION partition_message_table_by_time(previous_table TEXT, boundary TIMESTAMP WITH TIME ZONE)
+    RETURNS VOID AS
+$$
+BEGIN
+    LOCK TABLE message IN ACCESS EXCLUSIVE MODE;
+    EXECUTE format('ALTER TABLE message RENAME TO %I', previous_table);
+    -- the id sequence would be dropped with the old table once it expires
+    ALTER SEQUENCE message_id_seq OWNED BY NONE;
+    EXECUTE format('CREATE TABLE message (LIKE %I INCLUDING DEFAULTS) PARTITION BY RANGE (time_received)',
+                   previous_table);
+    ALTER SEQUENCE message_id_seq OWNED BY message.id;
+    CREATE INDEX ON message (channel_login, time_received, id);
+    EXECUTE format('ALTER TABLE message ATTACH PARTITION %I FOR VALUES FROM (MINVALUE) TO (%L)',
+                   previous_table, boundary);
+    CREATE TABLE message_pdefault PARTITION OF message DEFAULT;
+    CREATE UNIQUE INDEX ON message_pdefault (channel_login, twitch_message_id) WHERE twitch_message_id IS NOT NULL;
+END;
+$$ LANGUAGE plpgsql;
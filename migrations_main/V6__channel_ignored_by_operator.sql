@@ -0,0 +1,5 @@
+-- set by operators directly in the database, e.g.
+-- UPDATE channel SET ignored_at = now(), ignored_by_operator = TRUE WHERE channel_login = 'some_channel';
+-- channels ignored by an operator can't be un-ignored by the channel owner via the API.
+ALTER TABLE channel
+    ADD COLUMN ignored_by_operator BOOLEAN NOT NULL DEFAULT FALSE;
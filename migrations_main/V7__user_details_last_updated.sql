@@ -0,0 +1,8 @@
+-- user details are re-queried from Helix at most every web.refresh_user_details_after
+-- if web.prefer_cached_user_details is enabled, otherwise on every validation.
+ALTER TABLE user_authorization
+    ADD COLUMN user_details_last_updated TIMESTAMPTZ;
+UPDATE user_authorization
+SET user_details_last_updated = twitch_authorization_last_validated;
+ALTER TABLE user_authorization
+    ALTER COLUMN user_details_last_updated SET NOT NULL;
@@ -0,0 +1,15 @@
+-- tiebreaker for messages received within the same millisecond (time_received is truncated to
+-- milliseconds), so they have a stable order. With a constant default, adding the column doesn't rewrite
+-- the table (PostgreSQL 11+), which would block storing and reading messages until it is done on large
+-- databases. Existing rows therefore all get the id 0 and keep their previous order within a millisecond,
+-- only new rows are numbered, after the existing ones.
+ALTER TABLE message
+    ADD COLUMN id BIGINT NOT NULL DEFAULT 0;
+CREATE SEQUENCE message_id_seq OWNED BY message.id;
+ALTER TABLE message
+    ALTER COLUMN id SET DEFAULT nextval('message_id_seq');
+
+-- used by the get_messages, get_messages_window, purge_messages, run_message_vacuum queries. Building it
+-- blocks storing messages (but not reading them) until it is done.
+create index on message(channel_login, time_received, id);
+drop index message_channel_login_time_received_idx;
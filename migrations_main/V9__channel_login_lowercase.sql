@@ -0,0 +1,14 @@
+-- channel logins are lowercased before being stored since this version. Merge rows of the same
+-- channel that only differ in case, keeping the channel ignored if any of them was ignored.
+INSERT INTO channel (channel_login, ignored_at, last_access, ignored_by_operator)
+SELECT lower(channel_login), max(ignored_at), max(last_access), bool_or(ignored_by_operator)
+FROM channel
+WHERE channel_login <> lower(channel_login)
+GROUP BY lower(channel_login)
+ON CONFLICT ON CONSTRAINT channel_pkey DO UPDATE
+    SET ignored_at          = coalesce(channel.ignored_at, excluded.ignored_at),
+        last_access         = greatest(channel.last_access, excluded.last_access),
+        ignored_by_operator = channel.ignored_by_operator OR excluded.ignored_by_operator;
+
+DELETE FROM channel
+WHERE channel_login <> lower(channel_login);
@@ -0,0 +1,6 @@
+-- only filled if irc.deduplicate_messages is enabled, NULL otherwise
+ALTER TABLE message
+    ADD COLUMN twitch_message_id TEXT;
+
+-- used by the ON CONFLICT DO NOTHING clause of the batch insert to skip duplicates
+create unique index on message(channel_login, twitch_message_id) where twitch_message_id is not null;
@@ -0,0 +1,5 @@
+-- only filled if irc.store_sender_columns is enabled, NULL otherwise
+ALTER TABLE message
+    ADD COLUMN sender_login        TEXT,
+    ADD COLUMN sender_display_name TEXT,
+    ADD COLUMN color               TEXT;
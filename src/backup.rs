@@ -0,0 +1,134 @@
+//! Backing up all stored messages to a gzip-compressed CSV file, in the format read by
+//! `import`, so a backup can be restored with the `import` command.
+//!
+//! The messages are streamed from one database after another, only one batch is held in memory
+//! at a time.
+
+use crate::db::{DataStorage, MessageToStore, StorageError};
+use chrono::SecondsFormat;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Progress is logged whenever this many more messages were written
+const PROGRESS_EVERY: u64 = 100_000;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Failed to create `{}`: {1}", .0.display())]
+    Create(PathBuf, std::io::Error),
+    #[error("Failed to read messages: {0}")]
+    Read(#[from] StorageError),
+    #[error("Failed to write messages: {0}")]
+    Write(#[from] csv::Error),
+    #[error("Failed to finish writing the file: {0}")]
+    Finish(std::io::Error),
+}
+
+#[derive(Debug, Default)]
+pub struct BackupSummary {
+    pub messages: u64,
+    pub databases: usize,
+    /// Databases that were skipped because they are unavailable
+    pub unavailable_databases: usize,
+}
+
+/// The columns of a CSV row, see `import`
+fn to_row(message: &MessageToStore) -> [String; 3] {
+    [
+        message.channel_login.clone(),
+        message
+            .time_received
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+        message.message_source.clone(),
+    ]
+}
+
+/// Writes all messages of all available databases to `path`, overwriting it if it exists.
+pub async fn backup_gzip_csv(
+    data_storage: &DataStorage,
+    path: &Path,
+) -> Result<BackupSummary, BackupError> {
+    let file = File::create(path).map_err(|e| BackupError::Create(path.to_owned(), e))?;
+    let mut csv_writer =
+        csv::Writer::from_writer(GzEncoder::new(BufWriter::new(file), Compression::default()));
+
+    let mut summary = BackupSummary::default();
+    for partition_id in 0..data_storage.num_partitions() {
+        let name = data_storage.name_partition(partition_id);
+        if !data_storage.is_partition_available(partition_id) {
+            tracing::warn!("Skipping {}, it is unavailable", name);
+            summary.unavailable_databases += 1;
+            continue;
+        }
+
+        let messages_before = summary.messages;
+        data_storage
+            .for_each_message_batch(partition_id, |messages| {
+                for message in messages.iter() {
+                    csv_writer.write_record(to_row(message))?;
+                }
+                let messages_written = summary.messages + messages.len() as u64;
+                if messages_written / PROGRESS_EVERY > summary.messages / PROGRESS_EVERY {
+                    tracing::info!("Backed up {} messages so far", messages_written);
+                }
+                summary.messages = messages_written;
+                Ok::<_, BackupError>(())
+            })
+            .await?;
+        tracing::info!(
+            "Backed up {} messages from {}",
+            summary.messages - messages_before,
+            name
+        );
+        summary.databases += 1;
+    }
+
+    let gz_writer = csv_writer
+        .into_inner()
+        .map_err(|e| BackupError::Finish(e.into_error()))?;
+    gz_writer
+        .finish()
+        .and_then(|mut file| std::io::Write::flush(&mut file))
+        .map_err(BackupError::Finish)?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_row;
+    use crate::db::MessageToStore;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn rows_can_be_imported_again() {
+        let message = MessageToStore {
+            channel_login: "pajlada".to_owned(),
+            time_received: Utc.timestamp_millis_opt(1596061058008).unwrap(),
+            message_source: ":tmi.twitch.tv ROOMSTATE #pajlada".to_owned(),
+            twitch_message_id: None,
+            sender_login: None,
+            sender_display_name: None,
+            color: None,
+        };
+        let row = to_row(&message);
+        assert_eq!(
+            row,
+            [
+                "pajlada",
+                "2020-07-29T22:17:38.008Z",
+                ":tmi.twitch.tv ROOMSTATE #pajlada"
+            ]
+        );
+
+        let imported =
+            crate::import::parse_row(&csv::StringRecord::from(row.to_vec()), false).unwrap();
+        assert_eq!(imported.channel_login, message.channel_login);
+        assert_eq!(imported.time_received, message.time_received);
+        assert_eq!(imported.message_source, message.message_source);
+    }
+}
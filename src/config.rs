@@ -1,5 +1,8 @@
-use serde::Deserialize;
-use std::net::SocketAddr;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::time::Duration;
 use structopt::StructOpt;
@@ -20,6 +23,28 @@ pub struct Args {
         default_value = DEFAULT_CONFIG_PATH
     )]
     pub config_path: PathBuf,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tasks to run instead of the service, they exit when done
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub enum Command {
+    /// Import messages from a CSV file with the columns channel login, time received (RFC 3339)
+    /// and message (raw IRC), as written by `recent-messages2-migrate-messages` or `backup`
+    Import {
+        #[structopt(default_value = "messages.csv")]
+        file: PathBuf,
+    },
+    /// Write all stored messages to a gzip-compressed CSV file that can be restored with `import`
+    Backup {
+        #[structopt(default_value = "messages.csv.gz")]
+        file: PathBuf,
+    },
+    /// Move the messages of channels that are stored on another database than the one they map
+    /// to, e.g. after adding shards. Can run while the service is running
+    Rebalance,
 }
 
 /// Config file options
@@ -48,10 +73,52 @@ pub struct AppConfig {
     #[serde(with = "humantime_serde")]
     pub channels_expire_after: Duration,
     #[serde(with = "humantime_serde")]
+    pub channel_touch_debounce: Duration,
+    #[serde(with = "humantime_serde")]
     pub vacuum_messages_every: Duration,
+    /// Maximum number of channels of a database vacuumed at the same time
+    pub vacuum_concurrency: usize,
     #[serde(with = "humantime_serde")]
     pub messages_expire_after: Duration,
+    /// Replaces `messages_expire_after` for channels with no more than `max_buffer_size` messages
+    #[serde(with = "humantime_serde")]
+    pub quiet_channel_messages_expire_after: Option<Duration>,
     pub max_buffer_size: usize,
+    /// Always joined, regardless of whether they have been requested recently
+    pub seed_channels: Vec<String>,
+    /// Seed channels are joined this many at a time after startup. 0 joins all of them at once.
+    pub seed_join_batch_size: usize,
+    #[serde(with = "humantime_serde")]
+    pub seed_join_batch_every: Duration,
+    /// Start even if some shards can't be set up, serving only channels on the other databases.
+    pub allow_unavailable_shards: bool,
+    #[serde(with = "humantime_serde")]
+    pub retry_unavailable_shards_every: Duration,
+    /// How often the channels and messages per database are counted for the metrics.
+    #[serde(with = "humantime_serde")]
+    pub partition_metrics_every: Duration,
+    /// If disabled and there are `[[shard_db]]`s, the main database only stores channels and
+    /// authorizations, and all messages go to the shards.
+    pub main_db_stores_messages: bool,
+    /// Export options that are forced off, regardless of what clients request.
+    pub disabled_export_options: HashSet<ExportOption>,
+    /// Respond with 400 Bad Request if a client requests a disabled export option, instead of
+    /// silently ignoring it.
+    pub reject_disabled_export_options: bool,
+    /// Initialize the stored message count metrics from the planner's estimate instead of
+    /// counting all rows at startup.
+    pub estimate_initial_message_counts: bool,
+    /// Log queries for a single channel that take at least this long.
+    #[serde(with = "humantime_serde")]
+    pub slow_query_threshold: Option<Duration>,
+    /// Partition the message table by time, in partitions of this length. Expired messages are
+    /// then removed by dropping entire partitions.
+    #[serde(with = "humantime_serde")]
+    pub message_partition_interval: Option<Duration>,
+    /// After a shutdown was requested, exit with a non-zero code if not everything shut down
+    /// within this time.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
 }
 
 impl Default for AppConfig {
@@ -59,9 +126,46 @@ impl Default for AppConfig {
         AppConfig {
             vacuum_channels_every: Duration::from_secs(30 * 60), // 30 minutes
             channels_expire_after: Duration::from_secs(24 * 60 * 60), // 24 hours
+            channel_touch_debounce: Duration::from_secs(30 * 60), // 30 minutes
             vacuum_messages_every: Duration::from_secs(30 * 60), // 30 minutes
+            vacuum_concurrency: 1,
             messages_expire_after: Duration::from_secs(24 * 60 * 60), // 24 hours
+            quiet_channel_messages_expire_after: None,
             max_buffer_size: 500,
+            seed_channels: vec![],
+            seed_join_batch_size: 20,
+            seed_join_batch_every: Duration::from_secs(10),
+            allow_unavailable_shards: false,
+            retry_unavailable_shards_every: Duration::from_secs(60), // 1 minute
+            partition_metrics_every: Duration::from_secs(5 * 60),    // 5 minutes
+            main_db_stores_messages: true,
+            disabled_export_options: HashSet::new(),
+            reject_disabled_export_options: false,
+            estimate_initial_message_counts: false,
+            slow_query_threshold: None,
+            message_partition_interval: None,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Query options of the recent-messages endpoint that change which messages are exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportOption {
+    HideModerationMessages,
+    HideModeratedMessages,
+    ClearchatToNotice,
+    HideRoomModeNotices,
+}
+
+impl ExportOption {
+    pub fn query_parameter(&self) -> &'static str {
+        match self {
+            ExportOption::HideModerationMessages => "hide_moderation_messages",
+            ExportOption::HideModeratedMessages => "hide_moderated_messages",
+            ExportOption::ClearchatToNotice => "clearchat_to_notice",
+            ExportOption::HideRoomModeNotices => "hide_room_mode_notices",
         }
     }
 }
@@ -74,6 +178,45 @@ pub struct IrcConfig {
 
     #[serde(with = "humantime_serde")]
     pub forwarder_run_every: Duration,
+
+    pub forwarder_max_chunk_size: usize,
+
+    /// Adapt the time between forwarder runs to the message rate, between
+    /// `forwarder_min_run_every` and `forwarder_max_run_every`, starting at `forwarder_run_every`.
+    pub adaptive_forwarder: bool,
+    #[serde(with = "humantime_serde")]
+    pub forwarder_min_run_every: Duration,
+    #[serde(with = "humantime_serde")]
+    pub forwarder_max_run_every: Duration,
+
+    /// Export the message rate of this many of the busiest channels. 0 disables tracking.
+    pub message_rate_top_channels: usize,
+
+    /// Store the Twitch message ID with each message and skip messages whose ID is already stored
+    /// for the channel, e.g. when they are received again after a reconnect.
+    pub deduplicate_messages: bool,
+
+    /// Store the sender's login, display name and color of chat messages in separate columns.
+    pub store_sender_columns: bool,
+
+    /// IRC commands of the messages that are stored. Defaults to the types that are exported.
+    pub stored_message_types: HashSet<String>,
+
+    /// Don't store a `ROOMSTATE` if it is identical to the last stored `ROOMSTATE` of the channel.
+    pub compact_roomstate: bool,
+
+    /// Confirmed joins are trusted for this long without asking the IRC client again. 0 disables
+    /// caching.
+    #[serde(with = "humantime_serde")]
+    pub join_status_cache_ttl: Duration,
+
+    /// On shutdown, messages that were already received are still stored for up to this long.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_drain_timeout: Duration,
+
+    /// Messages of channels that were ignored within this time are not stored anymore.
+    #[serde(with = "humantime_serde")]
+    pub recently_ignored_for: Duration,
 }
 
 impl Default for IrcConfig {
@@ -81,6 +224,28 @@ impl Default for IrcConfig {
         IrcConfig {
             new_connection_every: Duration::from_millis(550), // value determined empirically
             forwarder_run_every: Duration::from_millis(100),
+            forwarder_max_chunk_size: 10000,
+            adaptive_forwarder: false,
+            forwarder_min_run_every: Duration::from_millis(10),
+            forwarder_max_run_every: Duration::from_millis(500),
+            message_rate_top_channels: 10,
+            deduplicate_messages: false,
+            store_sender_columns: false,
+            stored_message_types: [
+                "PRIVMSG",
+                "CLEARCHAT",
+                "CLEARMSG",
+                "USERNOTICE",
+                "NOTICE",
+                "ROOMSTATE",
+            ]
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+            compact_roomstate: false,
+            join_status_cache_ttl: Duration::from_secs(10),
+            shutdown_drain_timeout: Duration::from_secs(5),
+            recently_ignored_for: Duration::from_secs(10),
         }
     }
 }
@@ -100,10 +265,162 @@ pub struct WebConfig {
     pub twitch_api_credentials: TwitchApiClientCredentials,
     #[serde(with = "humantime_serde", default = "seven_days")]
     pub sessions_expire_after: Duration,
+    #[serde(default)]
+    pub sliding_sessions: bool,
     #[serde(with = "humantime_serde", default = "one_hour")]
     pub recheck_twitch_auth_after: Duration,
+    /// While the Twitch API fails, users are still accepted for this long after
+    /// `recheck_twitch_auth_after` has passed
+    #[serde(with = "humantime_serde", default = "one_hour")]
+    pub twitch_auth_outage_grace: Duration,
+    /// Only query the user details from Helix every `refresh_user_details_after`, and check
+    /// tokens with Twitch's (cheaper) token validation endpoint in between
+    #[serde(default)]
+    pub prefer_cached_user_details: bool,
+    #[serde(with = "humantime_serde", default = "one_day")]
+    pub refresh_user_details_after: Duration,
     #[serde(with = "humantime_serde", default = "ten_seconds")]
     pub request_timeout: Duration,
+    #[serde(with = "humantime_serde", default = "five_seconds")]
+    pub max_wait_for_join: Duration,
+    /// After responding, wait up to this long for a pending join to be confirmed, so the channel
+    /// is added to the database without another request
+    #[serde(with = "humantime_serde", default = "twenty_seconds")]
+    pub background_join_timeout: Duration,
+    #[serde(with = "humantime_serde", default = "two_seconds")]
+    pub background_join_recheck_every: Duration,
+    /// Recent messages responses have `"backlog_status": "filling"` until the channel has been
+    /// joined for this long, or the oldest stored message of the channel is this old
+    #[serde(with = "humantime_serde", default = "five_minutes")]
+    pub backlog_filling_for: Duration,
+    /// Maximum number of concurrent queries adding/touching requested channels in the database, and
+    /// of concurrent checks of pending joins
+    #[serde(default = "default_channel_touch_workers")]
+    pub channel_touch_workers: usize,
+    /// Requested channels waiting for their join to be confirmed, further channels are not
+    /// added/touched
+    #[serde(default = "default_channel_touch_queue_size")]
+    pub channel_touch_queue_size: usize,
+    #[serde(default)]
+    pub max_response_bytes: Option<usize>,
+    /// Maximum page size of the message window endpoint, which is disabled if not set
+    #[serde(default)]
+    pub message_window_max_limit: Option<usize>,
+    /// Bearer token for the `/api/v2/admin/*` endpoints, which are disabled if not set
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Log the client IP of every request and count requests per client IP
+    #[serde(default)]
+    pub track_client_ips: bool,
+    /// Connections from these addresses may set the client IP with `X-Forwarded-For`
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Number of clients listed by `/api/v2/admin/top-clients`
+    #[serde(default = "default_client_ip_top_n")]
+    pub client_ip_top_n: usize,
+    /// Serve the reasons why channels recently failed to be joined at `/api/v2/join-failures`, to
+    /// requests with the `admin_token`
+    #[serde(default)]
+    pub expose_join_failures: bool,
+    /// Reject channel logins with uppercase letters instead of lowercasing them
+    #[serde(default)]
+    pub reject_uppercase_channel_logins: bool,
+    /// Serialize recent messages responses while sending them instead of buffering them
+    #[serde(default)]
+    pub stream_responses: bool,
+    /// Add a `Server-Timing` header with the durations of the stages of recent messages requests
+    #[serde(default)]
+    pub server_timing_header: bool,
+    /// Defaults to `app.max_buffer_size`, see [`Config::max_request_limit`]
+    #[serde(default)]
+    pub max_request_limit: Option<usize>,
+    #[serde(with = "humantime_serde", default = "five_seconds")]
+    pub twitch_api_connect_timeout: Duration,
+    #[serde(with = "humantime_serde", default = "five_seconds")]
+    pub twitch_api_timeout: Duration,
+    /// After this many consecutive failed requests to the Twitch API, stop sending requests for
+    /// `twitch_api_cooldown`. 0 disables this.
+    #[serde(default = "default_twitch_api_failure_threshold")]
+    pub twitch_api_failure_threshold: u32,
+    #[serde(with = "humantime_serde", default = "ten_seconds")]
+    pub twitch_api_cooldown: Duration,
+    #[serde(with = "humantime_serde", default = "five_minutes")]
+    pub twitch_api_max_cooldown: Duration,
+    /// Requests beyond this many concurrent requests are rejected immediately. Unlimited if unset.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// How requests for the messages of ignored channels are answered
+    #[serde(default = "default_ignored_channel_response")]
+    pub ignored_channel_response: IgnoredChannelResponse,
+    /// Requests with a longer query string (in bytes) are rejected with 400 Bad Request
+    #[serde(default = "default_max_query_length")]
+    pub max_query_length: usize,
+    /// Requests with a larger body (in bytes) are rejected with 413 Payload Too Large
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// Added to all responses that don't already have them set. Set to an empty table to disable.
+    #[serde(default = "default_security_headers")]
+    pub security_headers: HashMap<String, String>,
+}
+
+/// Sessions can't be valid for longer than this, so their expiry always fits into a timestamp
+const MAX_SESSIONS_EXPIRE_AFTER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+impl WebConfig {
+    /// `sessions_expire_after`, which is checked to fit when the config is loaded.
+    pub fn sessions_expire_after(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.sessions_expire_after).unwrap()
+    }
+}
+
+/// A `WebConfig` for tests, with the required options set and `extra_toml` added to them.
+#[cfg(test)]
+pub fn test_web_config(extra_toml: &str) -> WebConfig {
+    toml::from_str(&format!(
+        r#"
+        client_id = "abc"
+        client_secret = "def"
+        redirect_uri = "https://recent-messages.robotty.de/authorized"
+        {}
+        "#,
+        extra_toml
+    ))
+    .unwrap()
+}
+
+fn default_security_headers() -> HashMap<String, String> {
+    HashMap::from([
+        ("X-Content-Type-Options".to_owned(), "nosniff".to_owned()),
+        ("Referrer-Policy".to_owned(), "no-referrer".to_owned()),
+    ])
+}
+
+fn default_ignored_channel_response() -> IgnoredChannelResponse {
+    IgnoredChannelResponse::Error
+}
+
+fn default_max_query_length() -> usize {
+    4096
+}
+
+fn default_max_body_size() -> usize {
+    256 * 1024
+}
+
+fn default_twitch_api_failure_threshold() -> u32 {
+    5
+}
+
+fn default_client_ip_top_n() -> usize {
+    20
+}
+
+fn default_channel_touch_workers() -> usize {
+    16
+}
+
+fn default_channel_touch_queue_size() -> usize {
+    1000
 }
 
 fn default_listen_addr() -> ListenAddr {
@@ -116,14 +433,34 @@ fn seven_days() -> Duration {
     Duration::from_secs(7 * 24 * 60 * 60)
 }
 
+fn one_day() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
 fn one_hour() -> Duration {
     Duration::from_secs(60 * 60)
 }
 
+fn five_minutes() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
 fn ten_seconds() -> Duration {
     Duration::from_secs(10)
 }
 
+fn twenty_seconds() -> Duration {
+    Duration::from_secs(20)
+}
+
+fn two_seconds() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn five_seconds() -> Duration {
+    Duration::from_secs(5)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
 pub enum ListenAddr {
@@ -159,16 +496,28 @@ pub struct DatabaseConfig {
     pub keepalives_idle: Duration,
     pub target_session_attrs: PgTargetSessionAttrs,
     pub channel_binding: PgChannelBinding,
+    // Name the server's TLS certificate is verified against, instead of the host connected to
+    pub tls_server_name: Option<String>,
+    // Maximum number of chunks of messages that are inserted into this database at the same time
+    pub max_concurrent_inserts: usize,
     #[serde(default)]
     pub pool: PoolConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
+/// Unlike in libpq, the server's certificate is always verified when TLS is used, see
+/// `connect_to_single_postgres_server`.
 pub enum PgSslMode {
     Disable,
+    /// TLS if the server supports it, certificate chain and hostname are verified
     Prefer,
+    /// Same as `VerifyFull`, kept for existing configs
     Require,
+    /// TLS is required, the certificate chain is verified but not the hostname
+    VerifyCa,
+    /// TLS is required, the certificate chain and hostname are verified
+    VerifyFull,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -191,6 +540,15 @@ fn default_pg_port() -> u16 {
     5432
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IgnoredChannelResponse {
+    /// 403 Forbidden with the `channel_ignored` error code
+    Error,
+    /// 200 OK without messages, with the `channel_ignored` status
+    Empty,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PgTargetSessionAttrs {
@@ -210,6 +568,9 @@ pub enum PgChannelBinding {
 #[serde(default)]
 pub struct PoolConfig {
     pub max_size: usize,
+    /// Main database only: user authorization queries use a separate pool of this many
+    /// connections, in addition to `max_size`
+    pub auth_max_size: Option<usize>,
     #[serde(with = "humantime_serde")]
     pub create_timeout: Duration,
     #[serde(with = "humantime_serde")]
@@ -222,6 +583,7 @@ impl Default for PoolConfig {
     fn default() -> Self {
         PoolConfig {
             max_size: num_cpus::get() * 4,
+            auth_max_size: None,
             create_timeout: Duration::from_secs(5),
             wait_timeout: Duration::from_secs(5),
             recycle_timeout: Duration::from_secs(5),
@@ -302,6 +664,8 @@ impl From<postgres::Config> for DatabaseConfig {
                 postgres::config::ChannelBinding::Require => PgChannelBinding::Require,
                 _ => panic!("unhandled variant"),
             },
+            tls_server_name: None,
+            max_concurrent_inserts: 1,
             pool: PoolConfig::default(),
         }
     }
@@ -330,7 +694,9 @@ impl From<DatabaseConfig> for postgres::Config {
         new_cfg.ssl_mode(match config.ssl_mode {
             PgSslMode::Disable => postgres::config::SslMode::Disable,
             PgSslMode::Prefer => postgres::config::SslMode::Prefer,
-            PgSslMode::Require => postgres::config::SslMode::Require,
+            PgSslMode::Require | PgSslMode::VerifyCa | PgSslMode::VerifyFull => {
+                postgres::config::SslMode::Require
+            }
         });
         for host in config.host {
             match host {
@@ -369,14 +735,302 @@ impl From<DatabaseConfig> for postgres::Config {
 pub enum LoadConfigError {
     #[error("Failed to read file: {0}")]
     ReadFile(std::io::Error),
+    #[error("File is not valid UTF-8: {0}")]
+    NotUtf8(std::string::FromUtf8Error),
+    #[error("Environment variable `{0}` is referenced in the config file, but not set")]
+    MissingEnvVar(String),
+    #[error("Environment variable `{0}` is referenced in a literal string ('...'), but its value can't be represented in one")]
+    EnvVarInLiteralString(String),
     #[error("Failed to parse contents: {0}")]
     ParseContents(toml::de::Error),
+    #[error("Invalid configuration: {0}")]
+    Invalid(&'static str),
+}
+
+impl Config {
+    /// Maximum number of messages returned by a single request for recent messages.
+    pub fn max_request_limit(&self) -> usize {
+        self.web
+            .max_request_limit
+            .unwrap_or(self.app.max_buffer_size)
+    }
+
+    fn validate(&self) -> Result<(), LoadConfigError> {
+        if self.web.sessions_expire_after > MAX_SESSIONS_EXPIRE_AFTER {
+            return Err(LoadConfigError::Invalid(
+                "web.sessions_expire_after must not be longer than 100 years",
+            ));
+        }
+        if self.web.max_wait_for_join >= self.web.request_timeout {
+            return Err(LoadConfigError::Invalid(
+                "web.max_wait_for_join must be shorter than web.request_timeout",
+            ));
+        }
+        if let Some(quiet_channel_messages_expire_after) =
+            self.app.quiet_channel_messages_expire_after
+        {
+            if quiet_channel_messages_expire_after < self.app.messages_expire_after {
+                return Err(LoadConfigError::Invalid(
+                    "app.quiet_channel_messages_expire_after must not be shorter than app.messages_expire_after",
+                ));
+            }
+        }
+        if self.irc.adaptive_forwarder
+            && !(self.irc.forwarder_min_run_every <= self.irc.forwarder_run_every
+                && self.irc.forwarder_run_every <= self.irc.forwarder_max_run_every)
+        {
+            return Err(LoadConfigError::Invalid(
+                "irc.forwarder_run_every must be between irc.forwarder_min_run_every and irc.forwarder_max_run_every",
+            ));
+        }
+        if self.web.channel_touch_workers == 0 {
+            return Err(LoadConfigError::Invalid(
+                "web.channel_touch_workers must be at least 1",
+            ));
+        }
+        let tls_server_names = std::iter::once(&self.main_db)
+            .chain(self.shard_db.iter())
+            .filter_map(|db| db.tls_server_name.as_deref());
+        for tls_server_name in tls_server_names {
+            if rustls::ServerName::try_from(tls_server_name).is_err() {
+                return Err(LoadConfigError::Invalid(
+                    "tls_server_name must be a valid hostname or IP address",
+                ));
+            }
+        }
+        if self
+            .shard_db
+            .iter()
+            .any(|shard_db| shard_db.pool.auth_max_size.is_some())
+        {
+            return Err(LoadConfigError::Invalid(
+                "pool.auth_max_size is only supported for the main_db",
+            ));
+        }
+        if let Some(message_partition_interval) = self.app.message_partition_interval {
+            if message_partition_interval < Duration::from_secs(60)
+                || message_partition_interval.subsec_nanos() != 0
+            {
+                return Err(LoadConfigError::Invalid(
+                    "app.message_partition_interval must be whole seconds, at least one minute",
+                ));
+            }
+            if self.app.vacuum_messages_every > message_partition_interval {
+                return Err(LoadConfigError::Invalid(
+                    "app.vacuum_messages_every must not be longer than app.message_partition_interval",
+                ));
+            }
+        }
+        if self.app.channel_touch_debounce >= self.app.channels_expire_after {
+            return Err(LoadConfigError::Invalid(
+                "app.channel_touch_debounce must be shorter than app.channels_expire_after",
+            ));
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref RE_ENV_VAR_REFERENCE: Regex =
+        Regex::new(r"^\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap();
+}
+
+/// Where in the raw TOML file an environment variable is referenced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TomlContext {
+    /// Outside of strings and comments, e.g. `port = ${DB_PORT}`
+    Bare,
+    Comment,
+    /// `"..."`, or `"""..."""` if multi-line
+    BasicString {
+        multi_line: bool,
+    },
+    /// `'...'`, or `'''...'''` if multi-line
+    LiteralString {
+        multi_line: bool,
+    },
+}
+
+impl TomlContext {
+    /// The length of the token at the start of `rest`, and the context after it.
+    fn advance(self, rest: &str) -> (usize, TomlContext) {
+        match self {
+            TomlContext::Bare if rest.starts_with('#') => (1, TomlContext::Comment),
+            TomlContext::Bare if rest.starts_with(r#"""""#) => {
+                (3, TomlContext::BasicString { multi_line: true })
+            }
+            TomlContext::Bare if rest.starts_with('"') => {
+                (1, TomlContext::BasicString { multi_line: false })
+            }
+            TomlContext::Bare if rest.starts_with("'''") => {
+                (3, TomlContext::LiteralString { multi_line: true })
+            }
+            TomlContext::Bare if rest.starts_with('\'') => {
+                (1, TomlContext::LiteralString { multi_line: false })
+            }
+            TomlContext::Comment if rest.starts_with('\n') => (1, TomlContext::Bare),
+            TomlContext::BasicString { .. } if rest.starts_with('\\') => {
+                // the escaped character can't end the string
+                let escaped_char_len = rest[1..].chars().next().map_or(0, char::len_utf8);
+                (1 + escaped_char_len, self)
+            }
+            TomlContext::BasicString { multi_line: true } if rest.starts_with(r#"""""#) => {
+                (3, TomlContext::Bare)
+            }
+            TomlContext::LiteralString { multi_line: true } if rest.starts_with("'''") => {
+                (3, TomlContext::Bare)
+            }
+            TomlContext::BasicString { multi_line: false } if rest.starts_with(['"', '\n']) => {
+                (1, TomlContext::Bare)
+            }
+            TomlContext::LiteralString { multi_line: false } if rest.starts_with(['\'', '\n']) => {
+                (1, TomlContext::Bare)
+            }
+            _ => (rest.chars().next().map_or(0, char::len_utf8), self),
+        }
+    }
+}
+
+/// Escapes `value` for a TOML basic string (`"..."`).
+fn escape_toml_basic_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in the raw config file with the value of the environment
+/// variable `VAR` (as looked up with `lookup_var`), or `default` if it is unset or empty like in
+/// the shell. Values are escaped in basic strings (`"..."`), so they can contain any characters.
+/// Comments are left untouched.
+fn interpolate_env_vars(
+    file_contents: &str,
+    lookup_var: impl Fn(&str) -> Option<String>,
+) -> Result<String, LoadConfigError> {
+    let mut interpolated = String::with_capacity(file_contents.len());
+    let mut context = TomlContext::Bare;
+    let mut rest = file_contents;
+    while !rest.is_empty() {
+        let captures = match context {
+            TomlContext::Comment => None,
+            _ => RE_ENV_VAR_REFERENCE.captures(rest),
+        };
+        if let Some(captures) = captures {
+            let name = &captures[1];
+            let value = match (lookup_var(name), captures.get(2)) {
+                (Some(value), Some(default)) if value.is_empty() => default.as_str().to_owned(),
+                (Some(value), _) => value,
+                (None, Some(default)) => default.as_str().to_owned(),
+                (None, None) => return Err(LoadConfigError::MissingEnvVar(name.to_owned())),
+            };
+            match context {
+                TomlContext::BasicString { .. } => {
+                    interpolated.push_str(&escape_toml_basic_string(&value))
+                }
+                TomlContext::LiteralString { multi_line }
+                    if value.contains('\'') || (!multi_line && value.contains('\n')) =>
+                {
+                    return Err(LoadConfigError::EnvVarInLiteralString(name.to_owned()));
+                }
+                _ => interpolated.push_str(&value),
+            }
+            rest = &rest[captures[0].len()..];
+            continue;
+        }
+
+        let (token_len, next_context) = context.advance(rest);
+        interpolated.push_str(&rest[..token_len]);
+        rest = &rest[token_len..];
+        context = next_context;
+    }
+    Ok(interpolated)
 }
 
 pub async fn load_config(args: &Args) -> Result<Config, LoadConfigError> {
     let file_contents = tokio::fs::read(&args.config_path)
         .await
         .map_err(LoadConfigError::ReadFile)?;
-    let config = toml::from_slice(&file_contents).map_err(LoadConfigError::ParseContents)?;
+    let file_contents = String::from_utf8(file_contents).map_err(LoadConfigError::NotUtf8)?;
+    let file_contents = interpolate_env_vars(&file_contents, |name| std::env::var(name).ok())?;
+    let config: Config = toml::from_str(&file_contents).map_err(LoadConfigError::ParseContents)?;
+    config.validate()?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{interpolate_env_vars, LoadConfigError};
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "DB_HOST" => Some("db.example.com".to_owned()),
+            "EMPTY" => Some("".to_owned()),
+            "PASSWORD" => Some("pa\"ss\\word\n'".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn interpolates_env_vars_and_defaults() {
+        let interpolated = interpolate_env_vars(
+            r#"host = "${DB_HOST}"
+port = ${DB_PORT:-5432}
+user = "${EMPTY:-postgres}"
+name = "$DB_HOST"
+# password = "${DB_PASSWORD}""#,
+            lookup,
+        )
+        .unwrap();
+        assert_eq!(
+            interpolated,
+            r#"host = "db.example.com"
+port = 5432
+user = "postgres"
+name = "$DB_HOST"
+# password = "${DB_PASSWORD}""#
+        );
+    }
+
+    #[test]
+    fn values_are_escaped_in_basic_strings() {
+        let interpolated = interpolate_env_vars(
+            r#"password = "${PASSWORD}" # "${DB_PASSWORD}"
+multi_line = """
+${PASSWORD}"""
+escaped = "\"${DB_HOST}\\""#,
+            lookup,
+        )
+        .unwrap();
+        let parsed: toml::Value = toml::from_str(&interpolated).unwrap();
+        assert_eq!(parsed["password"].as_str(), Some("pa\"ss\\word\n'"));
+        assert_eq!(parsed["multi_line"].as_str(), Some("pa\"ss\\word\n'"));
+        assert_eq!(parsed["escaped"].as_str(), Some("\"db.example.com\\"));
+    }
+
+    #[test]
+    fn values_that_dont_fit_into_literal_strings_are_an_error() {
+        let interpolated = interpolate_env_vars("host = '${DB_HOST}'", lookup).unwrap();
+        assert_eq!(interpolated, "host = 'db.example.com'");
+        let result = interpolate_env_vars("password = '${PASSWORD}'", lookup);
+        assert!(
+            matches!(result, Err(LoadConfigError::EnvVarInLiteralString(name)) if name == "PASSWORD")
+        );
+    }
+
+    #[test]
+    fn missing_env_var_without_default_is_an_error() {
+        let result = interpolate_env_vars(r#"password = "${DB_PASSWORD}""#, lookup);
+        assert!(
+            matches!(result, Err(LoadConfigError::MissingEnvVar(name)) if name == "DB_PASSWORD")
+        );
+    }
+}
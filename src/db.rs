@@ -1,19 +1,30 @@
-use crate::config::{Config, DatabaseConfig};
+use crate::config::{Config, DatabaseConfig, ExportOption, PgSslMode};
+use crate::sharding;
 use crate::web::auth::{TwitchUserAccessToken, UserAuthorization};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use deadpool_postgres::{ManagerConfig, PoolConfig, RecyclingMethod};
+use futures::future;
+use futures::stream::{self, StreamExt};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use murmur3::murmur3_32;
-use prometheus::{register_histogram_vec, register_int_counter_vec, register_int_gauge_vec};
-use prometheus::{HistogramVec, IntCounterVec, IntGaugeVec};
+use prometheus::{
+    exponential_buckets, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec,
+};
+use prometheus::{GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
 use rustls::{OwnedTrustAnchor, RootCertStore};
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
-use std::io::Cursor;
-use std::ops::DerefMut;
-use std::time::Duration;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
 use tokio::time::MissedTickBehavior;
+use tokio_postgres::tls::MakeTlsConnect;
 use tokio_postgres::types::ToSql;
 use tokio_postgres_rustls::MakeRustlsConnect;
 use tokio_util::sync::CancellationToken;
@@ -25,18 +36,48 @@ lazy_static! {
         &["db"]
     )
     .unwrap();
+    static ref QUERIES_CANCELLED: IntCounterVec = register_int_counter_vec!(
+        "recentmessages_queries_cancelled",
+        "Total number of queries of web requests that were cancelled because the request was aborted or the query failed",
+        &["db"]
+    )
+    .unwrap();
+    static ref DUPLICATE_MESSAGES_SKIPPED: IntCounterVec = register_int_counter_vec!(
+        "recentmessages_duplicate_messages_skipped",
+        "Total number of messages that were not appended to storage because a message with the same ID was already stored",
+        &["db"]
+    )
+    .unwrap();
     static ref MESSAGES_STORED: IntGaugeVec = register_int_gauge_vec!(
         "recentmessages_messages_stored",
         "Number of messages currently stored in storage",
         &["db"]
     )
     .unwrap();
+    static ref CHANNELS_PER_PARTITION: IntGaugeVec = register_int_gauge_vec!(
+        "recentmessages_channels_per_partition",
+        "Number of channels (that are not ignored) whose messages are stored on the database",
+        &["db"]
+    )
+    .unwrap();
+    static ref MESSAGES_PER_PARTITION: IntGaugeVec = register_int_gauge_vec!(
+        "recentmessages_messages_per_partition",
+        "Number of messages stored on the database, according to PostgreSQL's row estimate",
+        &["db"]
+    )
+    .unwrap();
     static ref STORE_CHUNK_RUNS: IntCounterVec = register_int_counter_vec!(
         "recentmessages_irc_forwarder_store_chunk_runs",
         "Number of runs the IRC forwarder has completed",
         &["db"]
     )
     .unwrap();
+    static ref DB_AVAILABLE: IntGaugeVec = register_int_gauge_vec!(
+        "recentmessages_db_available",
+        "1 if the database is in use, 0 if it failed at startup and is waiting to be retried",
+        &["db"]
+    )
+    .unwrap();
     static ref STORE_CHUNK_ERRORS: IntCounterVec = register_int_counter_vec!(
         "recentmessages_irc_forwarder_store_chunk_errors",
         "Number of times a chunk could not be appended to the database successfully",
@@ -49,6 +90,20 @@ lazy_static! {
         &["db"]
     )
     .unwrap();
+    static ref INGESTION_LAG: HistogramVec = register_histogram_vec!(
+        "recentmessages_ingestion_lag_seconds",
+        "Time from a message being received from IRC until it was committed to the database, including time spent queued",
+        &["db"],
+        // 0.01, 0.02, 0.04, ... ~164 seconds, +Inf
+        exponential_buckets(0.01, 2.0, 15).unwrap()
+    )
+    .unwrap();
+    static ref SLOW_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "recentmessages_slow_queries",
+        "Number of queries for a single channel that took longer than app.slow_query_threshold",
+        &["db", "operation"]
+    )
+    .unwrap();
     static ref MESSAGES_VACUUMED: IntCounterVec = register_int_counter_vec!(
         "recentmessages_messages_vacuumed",
         "Total number of messages that were removed by the automatic vacuum runner",
@@ -61,6 +116,12 @@ lazy_static! {
         &["db"]
     )
     .unwrap();
+    static ref VACUUM_SWEEP_DURATION: GaugeVec = register_gauge_vec!(
+        "recentmessages_message_vacuum_sweep_duration_seconds",
+        "Time taken by the last completed vacuum of all channels of a database, falling behind if it approaches app.vacuum_messages_every",
+        &["db"]
+    )
+    .unwrap();
     static ref DB_CONNECTIONS_IN_USE: IntGaugeVec = register_int_gauge_vec!(
         "recentmessages_db_pool_connections_in_use",
         "Number of database connections currently in use",
@@ -85,6 +146,17 @@ lazy_static! {
 pub struct DatabaseAccess {
     db_pool: deadpool_postgres::Pool,
     cached_name: &'static str,
+    /// Limits the number of message chunks being inserted at the same time
+    insert_semaphore: Arc<Semaphore>,
+    max_concurrent_inserts: u32,
+    /// Shards that could not be migrated at startup are marked unavailable (if
+    /// `app.allow_unavailable_shards` is enabled) until a background retry succeeds.
+    available: Arc<AtomicBool>,
+    /// For cancel requests, which are sent on a new connection
+    tls: TlsServerNameOverride,
+    /// Separate connections for user authorization queries (`pool.auth_max_size`, main database
+    /// only), with their name in metrics
+    auth_pool: Option<(deadpool_postgres::Pool, &'static str)>,
 }
 
 impl DatabaseAccess {
@@ -95,27 +167,58 @@ impl DatabaseAccess {
         custom_name: Option<String>,
         partition_id: usize,
         db_pool: deadpool_postgres::Pool,
+        tls: TlsServerNameOverride,
+        max_concurrent_inserts: usize,
+        auth_pool: Option<deadpool_postgres::Pool>,
     ) -> Self {
-        let shard_or_main = if partition_id == 0 { "main" } else { "shard" };
-        let cached_name = if let Some(custom_name) = &custom_name {
-            format!("db{}({}, {})", partition_id, shard_or_main, custom_name)
-        } else {
-            format!("db{}({})", partition_id, shard_or_main)
-        };
-        let cached_name = Box::leak(Box::new(cached_name));
+        let cached_name = Box::leak(Box::new(partition_name(
+            custom_name.as_deref(),
+            partition_id,
+        )));
+        let auth_pool = auth_pool.map(|auth_pool| {
+            let auth_name: &'static str =
+                Box::leak(format!("{}/auth", cached_name).into_boxed_str());
+            (auth_pool, auth_name)
+        });
         DatabaseAccess {
             db_pool,
             cached_name,
+            insert_semaphore: Arc::new(Semaphore::new(usize::max(max_concurrent_inserts, 1))),
+            max_concurrent_inserts: usize::max(max_concurrent_inserts, 1) as u32,
+            available: Arc::new(AtomicBool::new(true)),
+            tls,
+            auth_pool,
         }
     }
 }
 
+/// Name of a partition in logs, metrics and its connections' `application_name`, e.g.
+/// `db1(shard, replica-a)`.
+fn partition_name(custom_name: Option<&str>, partition_id: usize) -> String {
+    let shard_or_main = if partition_id == 0 { "main" } else { "shard" };
+    if let Some(custom_name) = custom_name {
+        format!("db{}({}, {})", partition_id, shard_or_main, custom_name)
+    } else {
+        format!("db{}({})", partition_id, shard_or_main)
+    }
+}
+
 impl Display for DatabaseAccess {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.cached_name)
     }
 }
 
+/// The form channel logins are stored and partitioned by. All `DataStorage` methods taking a
+/// channel login apply this, so `Forsen` and `forsen` always refer to the same channel.
+pub fn normalize_channel_login(channel_login: &str) -> Cow<'_, str> {
+    if channel_login.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(channel_login.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(channel_login)
+    }
+}
+
 pub fn connect_to_postgresql(config: &Config) -> DataStorage {
     let mut partition_id_counter = 0usize;
     let main_db = connect_to_single_postgres_server(&config.main_db, &mut partition_id_counter);
@@ -127,7 +230,86 @@ pub fn connect_to_postgresql(config: &Config) -> DataStorage {
         ));
     }
 
-    DataStorage::new(main_db, shard_dbs)
+    DataStorage::new(
+        main_db,
+        shard_dbs,
+        config.app.main_db_stores_messages,
+        config.app.slow_query_threshold,
+    )
+}
+
+/// Verifies the server's certificate against (and sends as SNI) a configured name instead of the
+/// host that is connected to, e.g. for servers behind a connection pooler or cloud proxy.
+#[derive(Clone)]
+struct TlsServerNameOverride {
+    inner: MakeRustlsConnect,
+    server_name: Option<String>,
+}
+
+impl<S> MakeTlsConnect<S> for TlsServerNameOverride
+where
+    MakeRustlsConnect: MakeTlsConnect<S>,
+{
+    type Stream = <MakeRustlsConnect as MakeTlsConnect<S>>::Stream;
+    type TlsConnect = <MakeRustlsConnect as MakeTlsConnect<S>>::TlsConnect;
+    type Error = <MakeRustlsConnect as MakeTlsConnect<S>>::Error;
+
+    fn make_tls_connect(&mut self, hostname: &str) -> Result<Self::TlsConnect, Self::Error> {
+        let server_name = self.server_name.as_deref().unwrap_or(hostname);
+        self.inner.make_tls_connect(server_name)
+    }
+}
+
+/// Signature algorithms accepted in certificates by `SkipHostnameVerification`, the same ones
+/// rustls accepts.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Verifies the server's certificate chain against the webpki roots, but not that the
+/// certificate was issued for the host connected to (`ssl_mode = "verify_ca"`).
+struct SkipHostnameVerification;
+
+impl ServerCertVerifier for SkipHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let invalid_certificate =
+            |e: webpki::Error| rustls::Error::InvalidCertificateData(e.to_string());
+        let certificate =
+            webpki::EndEntityCert::try_from(end_entity.0.as_ref()).map_err(invalid_certificate)?;
+        let intermediates = intermediates
+            .iter()
+            .map(|certificate| certificate.0.as_ref())
+            .collect_vec();
+        let now = webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        certificate
+            .verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki_roots::TLS_SERVER_ROOTS,
+                &intermediates,
+                now,
+            )
+            .map_err(invalid_certificate)?;
+        Ok(ServerCertVerified::assertion())
+    }
 }
 
 fn connect_to_single_postgres_server(
@@ -137,7 +319,14 @@ fn connect_to_single_postgres_server(
     let partition_id = *partition_id_counter;
     *partition_id_counter += 1;
 
-    let pg_config = tokio_postgres::Config::from(config.clone());
+    let mut pg_config = tokio_postgres::Config::from(config.clone());
+    // so connections in pg_stat_activity can be attributed to their partition
+    let application_name = format!(
+        "{}/{}",
+        pg_config.get_application_name().unwrap_or_default(),
+        partition_name(config.name.as_deref(), partition_id)
+    );
+    pg_config.application_name(&application_name);
     tracing::debug!("PostgreSQL config for db{}: {:#?}", partition_id, pg_config);
 
     let mgr_config = ManagerConfig {
@@ -158,21 +347,58 @@ fn connect_to_single_postgres_server(
     });
     root_certificates.add_server_trust_anchors(trust_anchors);
 
-    let tls_config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_certificates) // TODO support custom root certificates as well
-        .with_no_client_auth(); // TODO support client auth if needed
+    let tls_config_builder = rustls::ClientConfig::builder().with_safe_defaults();
+    // TODO support custom root certificates as well
+    let tls_config_builder = match config.ssl_mode {
+        PgSslMode::VerifyCa => {
+            tls_config_builder.with_custom_certificate_verifier(Arc::new(SkipHostnameVerification))
+        }
+        PgSslMode::Disable | PgSslMode::Prefer | PgSslMode::Require | PgSslMode::VerifyFull => {
+            tls_config_builder.with_root_certificates(root_certificates)
+        }
+    };
+    let tls_config = tls_config_builder.with_no_client_auth(); // TODO support client auth if needed
+
+    let tls = TlsServerNameOverride {
+        inner: MakeRustlsConnect::new(tls_config),
+        server_name: config.tls_server_name.clone(),
+    };
 
-    let tls = MakeRustlsConnect::new(tls_config);
+    let auth_pool = config.pool.auth_max_size.map(|auth_max_size| {
+        let mut pg_config = pg_config.clone();
+        pg_config.application_name(&format!("{}/auth", application_name));
+        let manager = deadpool_postgres::Manager::from_config(
+            pg_config,
+            tls.clone(),
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        deadpool_postgres::Pool::builder(manager)
+            .config(PoolConfig {
+                max_size: auth_max_size,
+                timeouts: deadpool_postgres::Timeouts::from(config.pool),
+            })
+            .runtime(deadpool_postgres::Runtime::Tokio1)
+            .build()
+            .unwrap()
+    });
 
-    let manager = deadpool_postgres::Manager::from_config(pg_config, tls, mgr_config);
+    let manager = deadpool_postgres::Manager::from_config(pg_config, tls.clone(), mgr_config);
     let db_pool = deadpool_postgres::Pool::builder(manager)
         .config(pool_config)
         .runtime(deadpool_postgres::Runtime::Tokio1)
         .build()
         .unwrap();
 
-    let db = DatabaseAccess::new(config.name.clone(), partition_id, db_pool);
+    let db = DatabaseAccess::new(
+        config.name.clone(),
+        partition_id,
+        db_pool,
+        tls,
+        config.max_concurrent_inserts,
+        auth_pool,
+    );
 
     DB_CONNECTIONS_MAX
         .with_label_values(&[db.cached_name])
@@ -180,6 +406,16 @@ fn connect_to_single_postgres_server(
     DB_CONNECTIONS_IN_USE
         .with_label_values(&[db.cached_name])
         .set(0);
+    if let (Some((_, auth_name)), Some(auth_max_size)) = (&db.auth_pool, config.pool.auth_max_size)
+    {
+        DB_CONNECTIONS_MAX
+            .with_label_values(&[*auth_name])
+            .set(auth_max_size as i64);
+        DB_CONNECTIONS_IN_USE
+            .with_label_values(&[*auth_name])
+            .set(0);
+    }
+    DB_AVAILABLE.with_label_values(&[db.cached_name]).set(1);
 
     db
 }
@@ -199,14 +435,122 @@ pub type StorageError = deadpool_postgres::PoolError;
 
 #[derive(Debug, Clone)]
 pub struct StoredMessage {
+    /// Orders messages with the same `time_received`, 0 for messages stored before the column was
+    /// added (see the migration)
+    pub id: i64,
+    pub time_received: DateTime<Utc>,
+    pub message_source: String,
+}
+
+/// A message received from IRC, about to be appended to storage.
+#[derive(Debug, Clone)]
+pub struct MessageToStore {
+    pub channel_login: String,
     pub time_received: DateTime<Utc>,
     pub message_source: String,
+    /// Only set if `irc.deduplicate_messages` is enabled
+    pub twitch_message_id: Option<String>,
+    // The following are only set if `irc.store_sender_columns` is enabled
+    pub sender_login: Option<String>,
+    pub sender_display_name: Option<String>,
+    pub color: Option<String>,
+}
+
+/// Number of columns set by the batch message insert query
+const MESSAGE_INSERT_COLUMNS: usize = 7;
+/// PostgreSQL allows at most 65535 parameters per query, larger chunks are split into multiple
+/// insert queries.
+const MAX_ROWS_PER_MESSAGE_INSERT: usize = u16::MAX as usize / MESSAGE_INSERT_COLUMNS;
+
+/// The planner's estimate of the number of stored messages. With time partitioning, the
+/// partitioned `message` table itself has no rows, so its partitions are summed up. `reltuples`
+/// is -1 if a table has never been vacuumed/analyzed.
+const ESTIMATE_MESSAGES_QUERY: &str = r"SELECT COALESCE(SUM(GREATEST(reltuples, 0)), 0)::BIGINT AS count
+FROM pg_class
+WHERE relkind = 'r'
+  AND (oid = 'message'::regclass
+    OR oid IN (SELECT inhrelid FROM pg_inherits WHERE inhparent = 'message'::regclass))";
+
+/// Number of `app.message_partition_interval`s that time partitions are created ahead for
+const TIME_PARTITIONS_AHEAD: i64 = 3;
+
+/// The forced export options of a `channel_settings` row, the columns can be NULL if the row was
+/// left joined.
+fn forced_export_options_from_row(row: &tokio_postgres::Row) -> HashSet<ExportOption> {
+    [
+        (
+            "hide_moderation_messages",
+            ExportOption::HideModerationMessages,
+        ),
+        (
+            "hide_moderated_messages",
+            ExportOption::HideModeratedMessages,
+        ),
+        ("clearchat_to_notice", ExportOption::ClearchatToNotice),
+        ("hide_room_mode_notices", ExportOption::HideRoomModeNotices),
+    ]
+    .into_iter()
+    .filter(|(column, _)| row.get::<_, Option<bool>>(*column).unwrap_or(false))
+    .map(|(_, export_option)| export_option)
+    .collect()
+}
+
+/// A partition of the `message` table when it is partitioned by time, named
+/// `message_p{start}_{end}` (seconds since the unix epoch) by `maintain_time_partitions`, or
+/// `message_pmin_{end}` for the table that existed before partitioning.
+#[derive(Debug, PartialEq, Eq)]
+struct TimePartition {
+    name: String,
+    end: i64,
+}
+
+impl TimePartition {
+    /// `None` for the default partition and tables that weren't created by us
+    fn from_name(name: &str) -> Option<TimePartition> {
+        let (start, end) = name.strip_prefix("message_p")?.split_once('_')?;
+        if start != "min" {
+            start.parse::<i64>().ok()?;
+        }
+        Some(TimePartition {
+            name: name.to_owned(),
+            end: end.parse().ok()?,
+        })
+    }
+}
+
+/// The first multiple of `interval` (since the unix epoch) after `time`, in seconds since the
+/// unix epoch.
+fn next_time_partition_boundary(time: DateTime<Utc>, interval: Duration) -> i64 {
+    let interval_secs = interval.as_secs() as i64;
+    (time.timestamp().div_euclid(interval_secs) + 1) * interval_secs
+}
+
+/// Time partition bounds as a literal, DDL statements can't have parameters
+fn time_partition_bound(epoch_secs: i64) -> String {
+    format!(
+        "'{}'",
+        Utc.timestamp_opt(epoch_secs, 0).unwrap().to_rfc3339()
+    )
+}
+
+/// Number of messages moved from one partition to another at once by `rebalance`
+const REBALANCE_BATCH_SIZE: i64 = 5000;
+/// Number of messages fetched at once when reading all messages of a database, e.g. for backups
+const READ_ALL_BATCH_SIZE: i32 = 5000;
+
+#[derive(Debug, Default)]
+pub struct RebalanceSummary {
+    pub channels_moved: usize,
+    pub messages_moved: u64,
 }
 
 #[derive(Clone)]
 pub struct DataStorage {
     main_db: DatabaseAccess,
     shard_dbs: Vec<DatabaseAccess>,
+    main_db_stores_messages: bool,
+    /// Channel queries taking at least this long are logged
+    slow_query_threshold: Option<Duration>,
 }
 
 struct WrappedDbConn(deadpool_postgres::Object, &'static str);
@@ -226,9 +570,90 @@ impl Drop for WrappedDbConn {
     }
 }
 
+/// A connection for a query of a web request. If this is dropped before `finish` was called, the
+/// request was aborted (e.g. the client disconnected, so axum dropped the handler) while the query
+/// was running, and the query is cancelled on the server instead of running to completion. This
+/// also happens if the query failed, the cancel request is then ignored by the server.
+struct CancelOnDrop {
+    db_conn: Option<WrappedDbConn>,
+    tls: TlsServerNameOverride,
+}
+
+impl CancelOnDrop {
+    /// The query completed, nothing to cancel.
+    fn finish(mut self) {
+        self.db_conn = None;
+    }
+}
+
+impl Deref for CancelOnDrop {
+    type Target = WrappedDbConn;
+
+    fn deref(&self) -> &WrappedDbConn {
+        self.db_conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(db_conn) = self.db_conn.take() {
+            let cancel_token = db_conn.0.cancel_token();
+            let tls = self.tls.clone();
+            QUERIES_CANCELLED.with_label_values(&[db_conn.1]).inc();
+            // keep the connection out of the pool until the cancel request was sent, so it can't
+            // cancel the next query on it instead
+            tokio::spawn(async move {
+                if let Err(e) = cancel_token.cancel_query(tls).await {
+                    tracing::warn!("({}) Failed to cancel query: {}", db_conn.1, e);
+                }
+                drop(db_conn);
+            });
+        }
+    }
+}
+
 impl DataStorage {
-    pub fn new(main_db: DatabaseAccess, shard_dbs: Vec<DatabaseAccess>) -> DataStorage {
-        DataStorage { main_db, shard_dbs }
+    pub fn new(
+        main_db: DatabaseAccess,
+        shard_dbs: Vec<DatabaseAccess>,
+        main_db_stores_messages: bool,
+        slow_query_threshold: Option<Duration>,
+    ) -> DataStorage {
+        DataStorage {
+            main_db,
+            shard_dbs,
+            main_db_stores_messages,
+            slow_query_threshold,
+        }
+    }
+
+    /// Logs and counts the query if it took longer than `app.slow_query_threshold`.
+    fn observe_query(
+        &self,
+        operation: &'static str,
+        channel_login: &str,
+        partition_id: usize,
+        started: Instant,
+        num_rows: usize,
+    ) {
+        let threshold = match self.slow_query_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let time_taken = started.elapsed();
+        if time_taken >= threshold {
+            SLOW_QUERIES
+                .with_label_values(&[self.name_partition(partition_id), operation])
+                .inc();
+            tracing::warn!(
+                "Slow query: {} for channel {} on {} took {:?} ({} rows)",
+                operation,
+                channel_login,
+                self.name_partition(partition_id),
+                time_taken,
+                num_rows
+            );
+        }
     }
 
     fn get_partition(&self, partition_id: usize) -> &DatabaseAccess {
@@ -252,49 +677,286 @@ impl DataStorage {
         ))
     }
 
+    /// See `CancelOnDrop`
+    async fn get_db_conn_cancel_on_drop(
+        &self,
+        partition_id: usize,
+    ) -> Result<CancelOnDrop, StorageError> {
+        Ok(CancelOnDrop {
+            db_conn: Some(self.get_db_conn(partition_id).await?),
+            tls: self.get_partition(partition_id).tls.clone(),
+        })
+    }
+
     async fn get_db_conn_main(&self) -> Result<WrappedDbConn, StorageError> {
         self.get_db_conn(0).await
     }
 
-    fn name_partition(&self, partition_id: usize) -> &'static str {
+    /// A connection to the main database for user authorization queries, from the separate
+    /// pool if `main_db.pool.auth_max_size` is set, so they don't have to wait for connections
+    /// behind message queries.
+    async fn get_db_conn_auth(&self) -> Result<WrappedDbConn, StorageError> {
+        let (auth_pool, auth_name) = match &self.main_db.auth_pool {
+            Some((auth_pool, auth_name)) => (auth_pool, *auth_name),
+            None => return self.get_db_conn_main().await,
+        };
+        let timer = TIME_TAKEN_TO_GET_DB_CONN
+            .with_label_values(&[auth_name])
+            .start_timer();
+        let db_conn = auth_pool.get().await;
+        timer.observe_duration();
+        Ok(WrappedDbConn::new(db_conn?, auth_name))
+    }
+
+    pub fn name_partition(&self, partition_id: usize) -> &'static str {
         self.get_partition(partition_id).cached_name
     }
 
     fn channel_to_partition_id(&self, channel_login: &str) -> usize {
-        let hash_result: u32 = murmur3_32(&mut Cursor::new(channel_login), 0).unwrap();
-        (hash_result % ((self.shard_dbs.len() + 1) as u32)) as usize
+        sharding::partition_for(
+            &normalize_channel_login(channel_login),
+            self.shard_dbs.len(),
+            self.main_db_stores_messages,
+        )
     }
 
-    pub async fn run_migrations(&self) -> Result<(), Box<dyn std::error::Error>> {
-        migrations_main::migrations::runner()
-            .run_async(self.get_db_conn_main().await?.0.as_mut().deref_mut())
-            .await?;
+    /// Whether the partition storing the given channel's messages is available.
+    pub fn is_channel_available(&self, channel_login: &str) -> bool {
+        self.is_partition_available(self.channel_to_partition_id(channel_login))
+    }
+
+    /// Number of databases, including the main database
+    pub fn num_partitions(&self) -> usize {
+        self.shard_dbs.len() + 1
+    }
+
+    pub fn is_partition_available(&self, partition_id: usize) -> bool {
+        self.get_partition(partition_id)
+            .available
+            .load(Ordering::Relaxed)
+    }
+
+    fn set_partition_available(&self, partition_id: usize, available: bool) {
+        self.get_partition(partition_id)
+            .available
+            .store(available, Ordering::Relaxed);
+        DB_AVAILABLE
+            .with_label_values(&[self.name_partition(partition_id)])
+            .set(available as i64);
+    }
+
+    /// Runs the migrations on all databases. If `allow_unavailable_shards` is set, shards that
+    /// fail are marked unavailable instead of failing the entire startup.
+    pub async fn run_migrations(
+        &self,
+        allow_unavailable_shards: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.run_migrations_partition(0).await?;
+
+        for partition_id in 1..self.shard_dbs.len() + 1 {
+            match self.run_migrations_partition(partition_id).await {
+                Ok(()) => {}
+                Err(e) if allow_unavailable_shards => {
+                    tracing::error!(
+                        "Failed to run migrations on {}, it will be unavailable until a retry succeeds: {}",
+                        self.name_partition(partition_id),
+                        e
+                    );
+                    self.set_partition_available(partition_id, false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
 
-        for i in 0..self.shard_dbs.len() {
+    async fn run_migrations_partition(
+        &self,
+        partition_id: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut db_conn = self.get_db_conn(partition_id).await?;
+        if partition_id == 0 {
+            migrations_main::migrations::runner()
+                .run_async(db_conn.0.as_mut().deref_mut())
+                .await?;
+        } else {
             migrations_shard::migrations::runner()
-                .run_async(self.get_db_conn(i + 1).await?.0.as_mut().deref_mut())
+                .run_async(db_conn.0.as_mut().deref_mut())
                 .await?;
         }
+        Ok(())
+    }
 
+    /// Counts the messages stored on all available partitions, concurrently. If `estimate` is
+    /// set, the planner's row estimate is used instead of a (slow) `COUNT(*)`.
+    pub async fn fetch_initial_metrics_values(&self, estimate: bool) -> Result<(), StorageError> {
+        futures::future::try_join_all(
+            (0..self.shard_dbs.len() + 1)
+                .filter(|partition_id| self.is_partition_available(*partition_id))
+                .map(|partition_id| {
+                    self.fetch_initial_metrics_values_partition(partition_id, estimate)
+                }),
+        )
+        .await?;
         Ok(())
     }
 
-    pub async fn fetch_initial_metrics_values(&self) -> Result<(), StorageError> {
-        for i in 0..self.shard_dbs.len() + 1 {
-            let count: i64 = self
-                .get_db_conn(i)
-                .await?
-                .0
-                .query_one("SELECT COUNT(*) AS count FROM message", &[])
-                .await?
-                .get("count");
-            MESSAGES_STORED
-                .with_label_values(&[self.name_partition(i)])
+    async fn fetch_initial_metrics_values_partition(
+        &self,
+        partition_id: usize,
+        estimate: bool,
+    ) -> Result<(), StorageError> {
+        let query = if estimate {
+            ESTIMATE_MESSAGES_QUERY
+        } else {
+            "SELECT COUNT(*) AS count FROM message"
+        };
+        let count: i64 = self
+            .get_db_conn(partition_id)
+            .await?
+            .0
+            .query_one(query, &[])
+            .await?
+            .get("count");
+        MESSAGES_STORED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .set(count);
+        Ok(())
+    }
+
+    /// Periodically retries to set up shards that were unavailable at startup, and puts them
+    /// into use once that succeeds.
+    pub async fn run_task_retry_unavailable_shards(
+        &'static self,
+        config: &'static Config,
+        shutdown_signal: CancellationToken,
+    ) {
+        let mut check_interval = tokio::time::interval(config.app.retry_unavailable_shards_every);
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let worker = async move {
+            loop {
+                check_interval.tick().await;
+                for partition_id in 1..self.shard_dbs.len() + 1 {
+                    if self.is_partition_available(partition_id) {
+                        continue;
+                    }
+
+                    let res = match self.run_migrations_partition(partition_id).await {
+                        Ok(()) => self
+                            .fetch_initial_metrics_values_partition(
+                                partition_id,
+                                config.app.estimate_initial_message_counts,
+                            )
+                            .await
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match res {
+                        Ok(()) => {
+                            tracing::info!(
+                                "{} is available again",
+                                self.name_partition(partition_id)
+                            );
+                            self.set_partition_available(partition_id, true);
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                "{} is still unavailable: {}",
+                                self.name_partition(partition_id),
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = worker => {},
+            _ = shutdown_signal.cancelled() => {}
+        }
+    }
+
+    /// Periodically updates the number of channels and messages per partition, to spot a skewed
+    /// distribution of channels across the shards.
+    pub async fn run_task_partition_metrics(
+        &'static self,
+        config: &'static Config,
+        shutdown_signal: CancellationToken,
+    ) {
+        let mut check_interval = tokio::time::interval(config.app.partition_metrics_every);
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let worker = async move {
+            loop {
+                check_interval.tick().await;
+                if let Err(e) = self.update_channels_per_partition().await {
+                    tracing::error!("Failed to count channels per partition: {}", e);
+                }
+                for partition_id in 0..self.shard_dbs.len() + 1 {
+                    if !self.is_partition_available(partition_id) {
+                        continue;
+                    }
+                    if let Err(e) = self.update_messages_per_partition(partition_id).await {
+                        tracing::error!(
+                            "Failed to count messages on {}: {}",
+                            self.name_partition(partition_id),
+                            e
+                        );
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = worker => {},
+            _ = shutdown_signal.cancelled() => {}
+        }
+    }
+
+    /// The channel list lives on the main database, so the channels are assigned to their
+    /// partitions here instead of counting the channels on every partition.
+    async fn update_channels_per_partition(&self) -> Result<(), StorageError> {
+        let rows = self
+            .get_db_conn_main()
+            .await?
+            .0
+            .query(
+                "SELECT channel_login FROM channel WHERE ignored_at IS NULL",
+                &[],
+            )
+            .await?;
+
+        let mut counts = vec![0; self.shard_dbs.len() + 1];
+        for row in rows {
+            counts[self.channel_to_partition_id(row.get(0))] += 1;
+        }
+        for (partition_id, count) in counts.into_iter().enumerate() {
+            CHANNELS_PER_PARTITION
+                .with_label_values(&[self.name_partition(partition_id)])
                 .set(count);
         }
         Ok(())
     }
 
+    /// Uses the planner's row estimate, counting all rows every time would be too slow on large
+    /// tables. With `app.message_partition_interval`, the rows are in the partitions of `message`.
+    async fn update_messages_per_partition(&self, partition_id: usize) -> Result<(), StorageError> {
+        let count: i64 = self
+            .get_db_conn(partition_id)
+            .await?
+            .0
+            .query_one(ESTIMATE_MESSAGES_QUERY, &[])
+            .await?
+            .get("count");
+        MESSAGES_PER_PARTITION
+            .with_label_values(&[self.name_partition(partition_id)])
+            .set(count);
+        Ok(())
+    }
+
     pub async fn get_channel_logins_to_join(
         &self,
         channel_expiry: Duration,
@@ -318,9 +980,14 @@ ORDER BY last_access DESC",
         Ok(channels)
     }
 
-    pub async fn touch_or_add_channel(&self, channel_login: &str) -> Result<(), StorageError> {
+    pub async fn touch_or_add_channel(
+        &self,
+        channel_login: &str,
+        debounce: Duration,
+    ) -> Result<(), StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
         let db_conn = self.get_db_conn_main().await?;
-        // this way we only update the last_access if it's been at least 30 minutes since
+        // this way we only update the last_access if it's been at least `debounce` since
         // the last time the last_access was updated for that channel. For high traffic
         // channels this massively cuts down on the amount of writes the DB has to do
         db_conn
@@ -329,7 +996,22 @@ ORDER BY last_access DESC",
                 r"INSERT INTO channel (channel_login) VALUES ($1)
 ON CONFLICT ON CONSTRAINT channel_pkey DO UPDATE
     SET last_access = now()
-    WHERE channel.last_access < now() - INTERVAL '30 minutes'",
+    WHERE channel.last_access < now() - make_interval(secs => $2)",
+                &[&channel_login, &debounce.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a channel that is not ignored from the channels to join, until it is requested
+    /// again.
+    pub async fn forget_channel(&self, channel_login: &str) -> Result<(), StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let db_conn = self.get_db_conn_main().await?;
+        db_conn
+            .0
+            .execute(
+                "DELETE FROM channel WHERE channel_login = $1 AND ignored_at IS NULL",
                 &[&channel_login],
             )
             .await?;
@@ -337,6 +1019,19 @@ ON CONFLICT ON CONSTRAINT channel_pkey DO UPDATE
     }
 
     pub async fn is_channel_ignored(&self, channel_login: &str) -> Result<bool, StorageError> {
+        // if the channel is not known, it is not ignored
+        Ok(self
+            .get_channel_ignored(channel_login)
+            .await?
+            .unwrap_or(false))
+    }
+
+    /// Returns `None` if the channel is not known, otherwise whether it is ignored.
+    pub async fn get_channel_ignored(
+        &self,
+        channel_login: &str,
+    ) -> Result<Option<bool>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
         let db_conn = self.get_db_conn_main().await?;
         let rows = db_conn
             .0
@@ -346,53 +1041,161 @@ WHERE channel_login = $1",
                 &[&channel_login],
             )
             .await?;
-        // if found, get the value from the returned row, otherwise, the channel is not known
-        // and therefore not ignored
-        Ok(rows.get(0).map(|row| row.get(0)).unwrap_or(false))
+        Ok(rows.get(0).map(|row| row.get(0)))
     }
 
+    /// Returns `false` if the channel was ignored by an operator, in which case it is left
+    /// unchanged.
     pub async fn set_channel_ignored(
         &self,
         channel_login: &str,
         ignored: bool,
-    ) -> Result<(), StorageError> {
+    ) -> Result<bool, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
         let db_conn = self.get_db_conn_main().await?;
-        db_conn
+        let rows_modified = db_conn
             .0
-            .query(
+            .execute(
                 r"INSERT INTO channel (channel_login, ignored_at)
 VALUES ($1, CASE WHEN $2 THEN now() ELSE NULL END)
 ON CONFLICT ON CONSTRAINT channel_pkey DO UPDATE
-    SET ignored_at = CASE WHEN $2 THEN now() ELSE NULL END",
+    SET ignored_at = CASE WHEN $2 THEN now() ELSE NULL END
+    WHERE NOT channel.ignored_by_operator",
                 &[&channel_login, &ignored],
             )
             .await?;
-        Ok(())
+        Ok(rows_modified > 0)
     }
 
-    pub async fn append_user_authorization(
+    /// Ignores or un-ignores all of the channels as an operator, in a single transaction.
+    /// Channels ignored by an operator can't be un-ignored by their owner, un-ignoring them here
+    /// lifts that again.
+    pub async fn set_channels_ignored_by_operator(
         &self,
-        user_authorization: &UserAuthorization,
+        channel_logins: &[String],
+        ignored: bool,
     ) -> Result<(), StorageError> {
+        let channel_logins = channel_logins
+            .iter()
+            .map(|channel_login| normalize_channel_login(channel_login).into_owned())
+            .collect_vec();
         let db_conn = self.get_db_conn_main().await?;
-
         db_conn
             .0
             .execute(
-                "INSERT INTO user_authorization(access_token, twitch_access_token,
-twitch_refresh_token, twitch_authorization_last_validated, valid_until, user_id,
-user_login, user_name, user_profile_image_url)
-VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
-                &[
-                    &user_authorization.access_token,
-                    &user_authorization.twitch_token.access_token,
-                    &user_authorization.twitch_token.refresh_token,
-                    &user_authorization.twitch_authorization_last_validated,
-                    &user_authorization.valid_until,
-                    &user_authorization.user_id,
-                    &user_authorization.user_login,
-                    &user_authorization.user_name,
+                r"INSERT INTO channel (channel_login, ignored_at, ignored_by_operator)
+SELECT channel_login, CASE WHEN $2 THEN now() ELSE NULL END, $2
+FROM unnest($1::text[]) AS channel_login
+ON CONFLICT ON CONSTRAINT channel_pkey DO UPDATE
+    SET ignored_at = CASE WHEN $2 THEN coalesce(channel.ignored_at, now()) ELSE NULL END,
+        ignored_by_operator = $2",
+                &[&channel_logins, &ignored],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the channel is ignored, and the export options its owner forces on, in one query.
+    pub async fn get_channel_ignored_and_forced_export_options(
+        &self,
+        channel_login: &str,
+    ) -> Result<(bool, HashSet<ExportOption>), StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let db_conn = self.get_db_conn_main().await?;
+        // the settings are NULL if none were set
+        let row = db_conn
+            .0
+            .query_one(
+                r"SELECT EXISTS(
+           SELECT 1 FROM channel WHERE channel_login = $1 AND ignored_at IS NOT NULL
+       ) AS ignored,
+       hide_moderation_messages, hide_moderated_messages, clearchat_to_notice,
+       hide_room_mode_notices
+FROM (SELECT $1::TEXT AS channel_login) AS requested
+LEFT JOIN channel_settings USING (channel_login)",
+                &[&channel_login],
+            )
+            .await?;
+        Ok((row.get("ignored"), forced_export_options_from_row(&row)))
+    }
+
+    /// The export options the channel owner forces on, empty if none were set.
+    pub async fn get_forced_export_options(
+        &self,
+        channel_login: &str,
+    ) -> Result<HashSet<ExportOption>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let db_conn = self.get_db_conn_main().await?;
+        let row = db_conn
+            .0
+            .query_opt(
+                r"SELECT hide_moderation_messages, hide_moderated_messages, clearchat_to_notice,
+       hide_room_mode_notices
+FROM channel_settings
+WHERE channel_login = $1",
+                &[&channel_login],
+            )
+            .await?;
+        Ok(row
+            .map(|row| forced_export_options_from_row(&row))
+            .unwrap_or_default())
+    }
+
+    pub async fn set_forced_export_options(
+        &self,
+        channel_login: &str,
+        forced_export_options: &HashSet<ExportOption>,
+    ) -> Result<(), StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let db_conn = self.get_db_conn_main().await?;
+        db_conn
+            .0
+            .execute(
+                r"INSERT INTO channel_settings
+    (channel_login, hide_moderation_messages, hide_moderated_messages, clearchat_to_notice,
+     hide_room_mode_notices)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT ON CONSTRAINT channel_settings_pkey DO UPDATE
+    SET hide_moderation_messages = excluded.hide_moderation_messages,
+        hide_moderated_messages  = excluded.hide_moderated_messages,
+        clearchat_to_notice      = excluded.clearchat_to_notice,
+        hide_room_mode_notices   = excluded.hide_room_mode_notices",
+                &[
+                    &channel_login,
+                    &forced_export_options.contains(&ExportOption::HideModerationMessages),
+                    &forced_export_options.contains(&ExportOption::HideModeratedMessages),
+                    &forced_export_options.contains(&ExportOption::ClearchatToNotice),
+                    &forced_export_options.contains(&ExportOption::HideRoomModeNotices),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn append_user_authorization(
+        &self,
+        user_authorization: &UserAuthorization,
+    ) -> Result<(), StorageError> {
+        let db_conn = self.get_db_conn_auth().await?;
+
+        db_conn
+            .0
+            .execute(
+                "INSERT INTO user_authorization(access_token, twitch_access_token,
+twitch_refresh_token, twitch_authorization_last_validated, valid_until, user_id,
+user_login, user_name, user_profile_image_url, user_details_last_updated)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                &[
+                    &user_authorization.access_token,
+                    &user_authorization.twitch_token.access_token,
+                    &user_authorization.twitch_token.refresh_token,
+                    &user_authorization.twitch_authorization_last_validated,
+                    &user_authorization.valid_until,
+                    &user_authorization.user_id,
+                    &user_authorization.user_login,
+                    &user_authorization.user_name,
                     &user_authorization.user_profile_image_url,
+                    &user_authorization.user_details_last_updated,
                 ],
             )
             .await?;
@@ -404,14 +1207,14 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
         &self,
         access_token: &str,
     ) -> Result<Option<UserAuthorization>, StorageError> {
-        let db_conn = self.get_db_conn_main().await?;
+        let db_conn = self.get_db_conn_auth().await?;
 
         let rows = db_conn
             .0
             .query(
                 "SELECT access_token, twitch_access_token, twitch_refresh_token,
 twitch_authorization_last_validated, valid_until, user_id,
-user_login, user_name, user_profile_image_url
+user_login, user_name, user_profile_image_url, user_details_last_updated
 FROM user_authorization
 WHERE access_token = $1
 AND valid_until >= now()",
@@ -433,6 +1236,7 @@ AND valid_until >= now()",
                 user_login: row.get("user_login"),
                 user_name: row.get("user_name"),
                 user_profile_image_url: row.get("user_profile_image_url"),
+                user_details_last_updated: row.get("user_details_last_updated"),
             }))
         } else {
             // token not found in DB, or it's expired
@@ -444,7 +1248,7 @@ AND valid_until >= now()",
         &self,
         user_authorization: &UserAuthorization,
     ) -> Result<(), StorageError> {
-        let db_conn = self.get_db_conn_main().await?;
+        let db_conn = self.get_db_conn_auth().await?;
 
         db_conn
             .0
@@ -457,7 +1261,8 @@ valid_until = $5,
 user_id = $6,
 user_login = $7,
 user_name = $8,
-user_profile_image_url = $9
+user_profile_image_url = $9,
+user_details_last_updated = $10
 WHERE access_token = $1",
                 &[
                     &user_authorization.access_token,
@@ -469,6 +1274,7 @@ WHERE access_token = $1",
                     &user_authorization.user_login,
                     &user_authorization.user_name,
                     &user_authorization.user_profile_image_url,
+                    &user_authorization.user_details_last_updated,
                 ],
             )
             .await?;
@@ -479,7 +1285,7 @@ WHERE access_token = $1",
     // TODO background task to purge expired authorizations
 
     pub async fn delete_user_authorization(&self, access_token: &str) -> Result<(), StorageError> {
-        let db_conn = self.get_db_conn_main().await?;
+        let db_conn = self.get_db_conn_auth().await?;
 
         db_conn
             .0
@@ -492,6 +1298,19 @@ WHERE access_token = $1",
         Ok(())
     }
 
+    /// Deletes all sessions of the given Twitch user, returns how many there were.
+    pub async fn delete_all_user_authorizations(&self, user_id: &str) -> Result<u64, StorageError> {
+        let db_conn = self.get_db_conn_auth().await?;
+
+        Ok(db_conn
+            .0
+            .execute(
+                "DELETE FROM user_authorization WHERE user_id = $1",
+                &[&user_id],
+            )
+            .await?)
+    }
+
     // left(start) of the vec: oldest messages
     pub async fn get_messages(
         &self,
@@ -499,42 +1318,344 @@ WHERE access_token = $1",
         limit: Option<usize>,
         before: Option<DateTime<Utc>>,
         after: Option<DateTime<Utc>>,
-        max_buffer_size: usize,
+        max_limit: usize,
     ) -> Result<Vec<StoredMessage>, StorageError> {
-        // limit: If specified, take the newest N messages.
+        let channel_login = &*normalize_channel_login(channel_login);
+        // limit: If specified, take the newest N messages. Never more than max_limit.
         let partition_id = self.channel_to_partition_id(channel_login);
-        let db_conn = self.get_db_conn(partition_id).await?;
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
 
         let limit = match limit {
-            Some(limit) => usize::min(limit, max_buffer_size),
-            None => max_buffer_size,
+            Some(limit) => usize::min(limit, max_limit),
+            None => max_limit,
         };
 
         // The cast() below is to allow the PostgreSQL server to unambiguously detect the
         // type of $2 and $3. See: https://stackoverflow.com/a/64223435
         let query = "\
-            SELECT time_received, message_source
+            SELECT id, time_received, message_source
             FROM message
             WHERE channel_login = $1
             AND   (cast($2 AS TIMESTAMP WITH TIME ZONE) IS NULL OR time_received < $2)
             AND   (cast($3 AS TIMESTAMP WITH TIME ZONE) IS NULL OR time_received > $3)
-            ORDER BY time_received DESC
+            ORDER BY time_received DESC, id DESC
             LIMIT $4";
 
-        Ok(db_conn
+        let started = Instant::now();
+        let rows = db_conn
             .0
             .query(query, &[&channel_login, &before, &after, &(limit as i64)])
-            .await?
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "get_messages",
+            channel_login,
+            partition_id,
+            started,
+            rows.len(),
+        );
+
+        Ok(rows
             .into_iter()
             .rev()
             .map(|row| StoredMessage {
+                id: row.get("id"),
+                time_received: row.get("time_received"),
+                message_source: row.get("message_source"),
+            })
+            .collect_vec())
+    }
+
+    /// The newest `limit` stored `ROOMSTATE`s of a channel, oldest first. The command is matched
+    /// right after the optional tags and prefix, so chat messages containing `ROOMSTATE #` don't
+    /// count towards the limit.
+    pub async fn get_roomstates(
+        &self,
+        channel_login: &str,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+
+        let started = Instant::now();
+        let rows = db_conn
+            .0
+            .query(
+                "\
+            SELECT id, time_received, message_source
+            FROM message
+            WHERE channel_login = $1
+            AND   message_source ~ '^(@\\S* )?(:\\S+ )?ROOMSTATE #'
+            ORDER BY time_received DESC, id DESC
+            LIMIT $2",
+                &[&channel_login, &(limit as i64)],
+            )
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "get_roomstates",
+            channel_login,
+            partition_id,
+            started,
+            rows.len(),
+        );
+
+        Ok(rows
+            .into_iter()
+            .rev()
+            .map(|row| StoredMessage {
+                id: row.get("id"),
+                time_received: row.get("time_received"),
+                message_source: row.get("message_source"),
+            })
+            .collect_vec())
+    }
+
+    /// The message of a channel with the given Twitch message ID, if it is stored.
+    pub async fn get_message_by_id(
+        &self,
+        channel_login: &str,
+        twitch_message_id: &str,
+    ) -> Result<Option<StoredMessage>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+
+        let started = Instant::now();
+        let row = db_conn
+            .0
+            .query_opt(
+                "\
+            SELECT id, time_received, message_source
+            FROM message
+            WHERE channel_login = $1
+            AND   twitch_message_id = $2",
+                &[&channel_login, &twitch_message_id],
+            )
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "get_message_by_id",
+            channel_login,
+            partition_id,
+            started,
+            row.is_some() as usize,
+        );
+
+        Ok(row.map(|row| StoredMessage {
+            id: row.get("id"),
+            time_received: row.get("time_received"),
+            message_source: row.get("message_source"),
+        }))
+    }
+
+    /// The `CLEARCHAT` and `CLEARMSG` messages of a channel stored after the message received at
+    /// `after` with the ID `after_id`, oldest first. Can include other messages that contain the
+    /// text `CLEARCHAT #` or `CLEARMSG #`.
+    pub async fn get_moderation_messages_after(
+        &self,
+        channel_login: &str,
+        after: DateTime<Utc>,
+        after_id: i64,
+    ) -> Result<Vec<StoredMessage>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+
+        let started = Instant::now();
+        let rows = db_conn
+            .0
+            .query(
+                "\
+            SELECT id, time_received, message_source
+            FROM message
+            WHERE channel_login = $1
+            AND   (time_received > $2 OR (time_received = $2 AND id > $3))
+            AND   (message_source LIKE '% CLEARCHAT #%' OR message_source LIKE '% CLEARMSG #%')
+            ORDER BY time_received ASC, id ASC",
+                &[&channel_login, &after, &after_id],
+            )
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "get_moderation_messages_after",
+            channel_login,
+            partition_id,
+            started,
+            rows.len(),
+        );
+
+        Ok(rows
+            .into_iter()
+            .map(|row| StoredMessage {
+                id: row.get("id"),
                 time_received: row.get("time_received"),
                 message_source: row.get("message_source"),
             })
             .collect_vec())
     }
 
-    pub async fn purge_messages(&self, channel_login: &str) -> Result<(), StorageError> {
+    /// The oldest `limit` messages of a channel between `after` and `before` (both exclusive, no
+    /// upper bound without `before`), oldest first. Unlike `get_messages`, this is not limited to the newest messages. If
+    /// `after_id` is set, messages received at `after` with a larger ID are included too, so
+    /// paging from the last message of a page doesn't skip messages of the same millisecond.
+    pub async fn get_messages_window(
+        &self,
+        channel_login: &str,
+        after: DateTime<Utc>,
+        after_id: Option<i64>,
+        before: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+
+        let started = Instant::now();
+        let rows = db_conn
+            .0
+            .query(
+                "\
+            SELECT id, time_received, message_source
+            FROM message
+            WHERE channel_login = $1
+            AND   (time_received > $2
+                   OR (time_received = $2 AND id > cast($5 AS BIGINT)))
+            AND   (cast($3 AS TIMESTAMP WITH TIME ZONE) IS NULL OR time_received < $3)
+            ORDER BY time_received ASC, id ASC
+            LIMIT $4",
+                &[&channel_login, &after, &before, &(limit as i64), &after_id],
+            )
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "get_messages_window",
+            channel_login,
+            partition_id,
+            started,
+            rows.len(),
+        );
+
+        Ok(rows
+            .into_iter()
+            .map(|row| StoredMessage {
+                id: row.get("id"),
+                time_received: row.get("time_received"),
+                message_source: row.get("message_source"),
+            })
+            .collect_vec())
+    }
+
+    /// When the oldest message currently stored for a channel was received, if there is one.
+    pub async fn get_oldest_message_time(
+        &self,
+        channel_login: &str,
+    ) -> Result<Option<DateTime<Utc>>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+        let started = Instant::now();
+        let oldest: Option<DateTime<Utc>> = db_conn
+            .0
+            .query_one(
+                "SELECT min(time_received) AS oldest FROM message WHERE channel_login = $1",
+                &[&channel_login],
+            )
+            .await?
+            .get("oldest");
+        db_conn.finish();
+        self.observe_query(
+            "get_oldest_message_time",
+            channel_login,
+            partition_id,
+            started,
+            oldest.is_some() as usize,
+        );
+        Ok(oldest)
+    }
+
+    /// Count all messages currently stored for a channel, regardless of any filters.
+    pub async fn count_messages(&self, channel_login: &str) -> Result<i64, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+        let started = Instant::now();
+        let count: i64 = db_conn
+            .0
+            .query_one(
+                "SELECT count(*) AS count FROM message WHERE channel_login = $1",
+                &[&channel_login],
+            )
+            .await?
+            .get("count");
+        db_conn.finish();
+        self.observe_query(
+            "count_messages",
+            channel_login,
+            partition_id,
+            started,
+            count as usize,
+        );
+        Ok(count)
+    }
+
+    /// Number of stored messages of a channel in each `bucket_length` long bucket, for the buckets
+    /// starting from `first_bucket` up to and including `last_bucket`. Buckets without messages
+    /// are included with a count of 0.
+    pub async fn count_messages_per_bucket(
+        &self,
+        channel_login: &str,
+        first_bucket: DateTime<Utc>,
+        last_bucket: DateTime<Utc>,
+        bucket_length: Duration,
+    ) -> Result<Vec<(DateTime<Utc>, i64)>, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
+        let partition_id = self.channel_to_partition_id(channel_login);
+        let db_conn = self.get_db_conn_cancel_on_drop(partition_id).await?;
+        let started = Instant::now();
+        let rows = db_conn
+            .0
+            .query(
+                "\
+            SELECT bucket, count(message.time_received) AS count
+            FROM generate_series(
+                cast($2 AS TIMESTAMP WITH TIME ZONE),
+                cast($3 AS TIMESTAMP WITH TIME ZONE),
+                make_interval(secs => $4)
+            ) AS bucket
+            LEFT JOIN message
+            ON    message.channel_login = $1
+            AND   message.time_received >= bucket
+            AND   message.time_received < bucket + make_interval(secs => $4)
+            GROUP BY bucket
+            ORDER BY bucket ASC",
+                &[
+                    &channel_login,
+                    &first_bucket,
+                    &last_bucket,
+                    &bucket_length.as_secs_f64(),
+                ],
+            )
+            .await?;
+        db_conn.finish();
+        self.observe_query(
+            "count_messages_per_bucket",
+            channel_login,
+            partition_id,
+            started,
+            rows.len(),
+        );
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("bucket"), row.get("count")))
+            .collect_vec())
+    }
+
+    /// Returns the number of messages deleted.
+    pub async fn purge_messages(&self, channel_login: &str) -> Result<u64, StorageError> {
+        let channel_login = &*normalize_channel_login(channel_login);
         let partition_id = self.channel_to_partition_id(channel_login);
         let num_messages_deleted = self
             .get_db_conn(partition_id)
@@ -548,18 +1669,55 @@ WHERE access_token = $1",
         MESSAGES_STORED
             .with_label_values(&[self.name_partition(partition_id)])
             .sub(num_messages_deleted as i64);
-        Ok(())
+        Ok(num_messages_deleted)
     }
 
     /// Append a message to the storage.
-    pub fn append_messages(&self, messages: Vec<(String, DateTime<Utc>, String)>) {
+    ///
+    /// The insert into each partition runs in the background. At most the configured maximum of
+    /// concurrent inserts into a partition run at once, the others wait in the order they were
+    /// appended. Waiting happens in the background too, so a slow database doesn't hold up the
+    /// inserts into the other ones.
+    ///
+    /// `filter` runs right before each insert, while its permit is held, so anything it removes
+    /// after `wait_for_pending_inserts` returned is never stored.
+    pub async fn append_messages<F>(&self, messages: Vec<MessageToStore>, filter: F)
+    where
+        F: Fn(Vec<MessageToStore>) -> Vec<MessageToStore> + Clone + Send + 'static,
+    {
         let group_map = messages
             .into_iter()
-            .into_group_map_by(|(channel_login, _, _)| self.channel_to_partition_id(channel_login));
+            .into_group_map_by(|message| self.channel_to_partition_id(&message.channel_login));
 
         for (partition_id, messages) in group_map.into_iter() {
+            if !self.is_partition_available(partition_id) {
+                // don't pile up inserts that would fail anyway
+                STORE_CHUNK_ERRORS
+                    .with_label_values(&[self.name_partition(partition_id)])
+                    .inc();
+                continue;
+            }
+
+            // queue up for the permit right away, so inserts into the same partition keep their
+            // order and `wait_for_pending_inserts` waits for this one as well
+            let mut acquire = Box::pin(
+                self.get_partition(partition_id)
+                    .insert_semaphore
+                    .clone()
+                    .acquire_owned(),
+            );
+            let permit = match futures::poll!(&mut acquire) {
+                Poll::Ready(permit) => future::Either::Left(future::ready(permit)),
+                Poll::Pending => future::Either::Right(acquire),
+            };
             let self_clone = self.clone();
+            let filter = filter.clone();
             tokio::spawn(async move {
+                let _permit = permit.await.unwrap(); // semaphore is never closed
+                let messages = filter(messages);
+                if messages.is_empty() {
+                    return;
+                }
                 STORE_CHUNK_RUNS
                     .with_label_values(&[self_clone.name_partition(partition_id)])
                     .inc();
@@ -586,10 +1744,24 @@ WHERE access_token = $1",
         }
     }
 
+    /// Waits until the chunks passed to `append_messages` so far have been inserted (or failed to
+    /// be inserted), e.g. before exiting.
+    pub async fn wait_for_pending_inserts(&self) {
+        for partition_id in 0..self.num_partitions() {
+            let partition = self.get_partition(partition_id);
+            // all permits are only free once every insert returned its permit
+            let _permits = partition
+                .insert_semaphore
+                .acquire_many(partition.max_concurrent_inserts)
+                .await
+                .unwrap(); // semaphore is never closed
+        }
+    }
+
     async fn append_messages_partition(
         &self,
         partition_id: usize,
-        messages: Vec<(String, DateTime<Utc>, String)>,
+        messages: Vec<MessageToStore>,
     ) -> Result<(), StorageError> {
         STORE_CHUNK_RUNS
             .with_label_values(&[self.name_partition(partition_id)])
@@ -598,39 +1770,279 @@ WHERE access_token = $1",
         if messages.len() <= 0 {
             return Ok(());
         }
-        let num_messages = messages.len();
-        self.get_db_conn(partition_id)
-            .await?
-            .0
-            .execute(
-                &DataStorage::batch_message_insert_query(messages.len(), 3),
-                DataStorage::batch_message_insert_values(&messages).as_slice(),
-            )
+        self.insert_messages_partition(partition_id, &messages)
             .await?;
+        let committed_at = Utc::now();
+        let ingestion_lag = INGESTION_LAG.with_label_values(&[self.name_partition(partition_id)]);
+        for message in messages.iter() {
+            let lag = (committed_at - message.time_received)
+                .to_std()
+                .unwrap_or(Duration::ZERO);
+            ingestion_lag.observe(lag.as_secs_f64());
+        }
+        Ok(())
+    }
+
+    /// Insert messages that were received in the past, e.g. from a backup or the version 1
+    /// service. Unlike `append_messages`, this waits for the inserts to complete, and returns the
+    /// number of inserted messages. Messages with a `twitch_message_id` that is already stored are
+    /// skipped.
+    pub async fn import_messages(
+        &self,
+        messages: Vec<MessageToStore>,
+    ) -> Result<u64, StorageError> {
+        let group_map = messages
+            .into_iter()
+            .into_group_map_by(|message| self.channel_to_partition_id(&message.channel_login));
+
+        let mut num_inserted = 0;
+        for (partition_id, messages) in group_map.into_iter() {
+            num_inserted += self
+                .insert_messages_partition(partition_id, &messages)
+                .await?;
+        }
+        Ok(num_inserted)
+    }
+
+    /// Returns the number of inserted messages.
+    async fn insert_messages_partition(
+        &self,
+        partition_id: usize,
+        messages: &[MessageToStore],
+    ) -> Result<u64, StorageError> {
+        let num_messages = messages.len();
+        let db_conn = self.get_db_conn(partition_id).await?;
+        // messages with an ID that is already stored are skipped (ON CONFLICT DO NOTHING),
+        // so this can be less than num_messages
+        let mut num_inserted = 0;
+        for rows in messages.chunks(MAX_ROWS_PER_MESSAGE_INSERT) {
+            num_inserted += db_conn
+                .0
+                .execute(
+                    &DataStorage::batch_message_insert_query(rows.len(), MESSAGE_INSERT_COLUMNS),
+                    DataStorage::batch_message_insert_values(rows).as_slice(),
+                )
+                .await?;
+        }
         MESSAGES_APPENDED
             .with_label_values(&[self.name_partition(partition_id)])
-            .inc_by(num_messages as u64);
+            .inc_by(num_inserted);
+        DUPLICATE_MESSAGES_SKIPPED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .inc_by(num_messages as u64 - num_inserted);
         MESSAGES_STORED
             .with_label_values(&[self.name_partition(partition_id)])
-            .add(num_messages as i64);
+            .add(num_inserted as i64);
+        Ok(num_inserted)
+    }
+
+    /// Moves the messages of all channels that are stored on another partition than the one they
+    /// map to, e.g. after `[[shard_db]]`s were added. Can run while the service is running: new
+    /// messages of a moved channel are already stored on its new partition, and only messages
+    /// that are not moved yet are missing from responses. Channels that were already moved are
+    /// skipped, so this can simply be run again if it was interrupted.
+    pub async fn rebalance(&self) -> Result<RebalanceSummary, StorageError> {
+        let mut summary = RebalanceSummary::default();
+        for partition_id in 0..self.shard_dbs.len() + 1 {
+            let channel_logins: Vec<String> = self
+                .get_db_conn(partition_id)
+                .await?
+                .0
+                .query("SELECT DISTINCT channel_login FROM message", &[])
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+
+            for channel_login in channel_logins {
+                let target_partition_id = self.channel_to_partition_id(&channel_login);
+                if target_partition_id == partition_id {
+                    continue;
+                }
+                let num_moved = self
+                    .move_channel_messages(&channel_login, partition_id, target_partition_id)
+                    .await?;
+                tracing::info!(
+                    "Moved {} messages of channel {} from {} to {}",
+                    num_moved,
+                    channel_login,
+                    self.name_partition(partition_id),
+                    self.name_partition(target_partition_id)
+                );
+                summary.channels_moved += 1;
+                summary.messages_moved += num_moved;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Calls `on_batch` with all messages stored on the partition, in batches read from a cursor,
+    /// so they never have to fit into memory at once.
+    pub async fn for_each_message_batch<E, F>(
+        &self,
+        partition_id: usize,
+        mut on_batch: F,
+    ) -> Result<(), E>
+    where
+        E: From<StorageError>,
+        F: FnMut(Vec<MessageToStore>) -> Result<(), E>,
+    {
+        let mut db_conn = self.get_db_conn(partition_id).await?;
+        // portals only exist inside of transactions
+        let transaction = db_conn.0.transaction().await.map_err(StorageError::from)?;
+        let portal = transaction
+            .bind(
+                "SELECT channel_login, time_received, message_source, twitch_message_id,
+       sender_login, sender_display_name, color
+FROM message",
+                &[],
+            )
+            .await
+            .map_err(StorageError::from)?;
+        loop {
+            let rows = transaction
+                .query_portal(&portal, READ_ALL_BATCH_SIZE)
+                .await
+                .map_err(StorageError::from)?;
+            if rows.is_empty() {
+                break;
+            }
+            on_batch(
+                rows.into_iter()
+                    .map(|row| MessageToStore {
+                        channel_login: row.get("channel_login"),
+                        time_received: row.get("time_received"),
+                        message_source: row.get("message_source"),
+                        twitch_message_id: row.get("twitch_message_id"),
+                        sender_login: row.get("sender_login"),
+                        sender_display_name: row.get("sender_display_name"),
+                        color: row.get("color"),
+                    })
+                    .collect(),
+            )?;
+        }
+        transaction.commit().await.map_err(StorageError::from)?;
         Ok(())
     }
 
-    fn batch_message_insert_values(
-        rows: &Vec<(String, DateTime<Utc>, String)>,
-    ) -> Vec<&(dyn ToSql + Sync)> {
+    /// Moves the channel's messages in batches, oldest first. Each batch is only deleted from
+    /// `from_partition_id` once it was inserted into `to_partition_id`, so no messages are lost if
+    /// this fails. If it fails between the two, the batch is moved again on the next run, and
+    /// messages that are already stored on `to_partition_id` with the same `time_received` and
+    /// `message_source` are skipped then, so they aren't duplicated.
+    async fn move_channel_messages(
+        &self,
+        channel_login: &str,
+        from_partition_id: usize,
+        to_partition_id: usize,
+    ) -> Result<u64, StorageError> {
+        let mut from_conn = self.get_db_conn(from_partition_id).await?;
+        let mut num_moved = 0;
+        loop {
+            let transaction = from_conn.0.transaction().await?;
+            // by time_received too, since id is only indexed together with it
+            let rows = transaction
+                .query(
+                    "\
+            DELETE FROM message
+            WHERE channel_login = $1
+            AND   (time_received, id) IN (
+                SELECT time_received, id
+                FROM message
+                WHERE channel_login = $1
+                ORDER BY time_received ASC, id ASC
+                LIMIT $2
+            )
+            RETURNING id, time_received, message_source, twitch_message_id,
+                      sender_login, sender_display_name, color",
+                    &[&channel_login, &REBALANCE_BATCH_SIZE],
+                )
+                .await?;
+            if rows.is_empty() {
+                break;
+            }
+            let num_deleted = rows.len();
+            // RETURNING doesn't preserve the order of the subquery
+            let rows = rows
+                .into_iter()
+                .sorted_by_key(|row| {
+                    (
+                        row.get::<_, DateTime<Utc>>("time_received"),
+                        row.get::<_, i64>("id"),
+                    )
+                })
+                .collect_vec();
+            let first_time_received: DateTime<Utc> = rows[0].get("time_received");
+            let last_time_received: DateTime<Utc> = rows[num_deleted - 1].get("time_received");
+            let already_moved = self
+                .get_db_conn(to_partition_id)
+                .await?
+                .0
+                .query(
+                    "\
+            SELECT time_received, message_source
+            FROM message
+            WHERE channel_login = $1
+            AND   time_received >= $2
+            AND   time_received <= $3",
+                    &[&channel_login, &first_time_received, &last_time_received],
+                )
+                .await?
+                .into_iter()
+                .map(|row| {
+                    (
+                        row.get::<_, DateTime<Utc>>("time_received"),
+                        row.get::<_, String>("message_source"),
+                    )
+                })
+                .collect::<HashSet<_>>();
+
+            let messages = rows
+                .into_iter()
+                .map(|row| MessageToStore {
+                    channel_login: channel_login.to_owned(),
+                    time_received: row.get("time_received"),
+                    message_source: row.get("message_source"),
+                    twitch_message_id: row.get("twitch_message_id"),
+                    sender_login: row.get("sender_login"),
+                    sender_display_name: row.get("sender_display_name"),
+                    color: row.get("color"),
+                })
+                .filter(|message| {
+                    !already_moved
+                        .contains(&(message.time_received, message.message_source.clone()))
+                })
+                .collect_vec();
+            self.insert_messages_partition(to_partition_id, &messages)
+                .await?;
+            transaction.commit().await?;
+
+            MESSAGES_STORED
+                .with_label_values(&[self.name_partition(from_partition_id)])
+                .sub(num_deleted as i64);
+            num_moved += num_deleted as u64;
+        }
+        Ok(num_moved)
+    }
+
+    fn batch_message_insert_values(rows: &[MessageToStore]) -> Vec<&(dyn ToSql + Sync)> {
         let mut out: Vec<&(dyn ToSql + Sync)> = vec![];
-        for (a, b, c) in rows {
-            out.push(a);
-            out.push(b);
-            out.push(c);
+        for row in rows {
+            out.push(&row.channel_login);
+            out.push(&row.time_received);
+            out.push(&row.message_source);
+            out.push(&row.twitch_message_id);
+            out.push(&row.sender_login);
+            out.push(&row.sender_display_name);
+            out.push(&row.color);
         }
         out
     }
 
     fn batch_message_insert_query(num_rows: usize, num_columns: usize) -> String {
         let mut buf = String::from(
-            "INSERT INTO message(channel_login, time_received, message_source) VALUES ",
+            "INSERT INTO message(channel_login, time_received, message_source, twitch_message_id, \
+sender_login, sender_display_name, color) VALUES ",
         );
         for i in 0..num_rows {
             buf.push_str("(");
@@ -645,9 +2057,179 @@ WHERE access_token = $1",
                 buf.push_str(", ");
             }
         }
+        buf.push_str(" ON CONFLICT DO NOTHING");
         buf
     }
 
+    /// Turns the `message` table of the partition into a table partitioned by `time_received` with
+    /// the `partition_message_table_by_time` function from the migrations, if it isn't already.
+    /// The existing table becomes the partition for everything before the `interval` boundary
+    /// after the next one.
+    async fn convert_to_time_partitioned(
+        &self,
+        partition_id: usize,
+        interval: Duration,
+    ) -> Result<(), StorageError> {
+        let mut db_conn = self.get_db_conn(partition_id).await?;
+        let is_partitioned: bool = db_conn
+            .0
+            .query_one(
+                "SELECT relkind = 'p' FROM pg_class WHERE oid = 'message'::regclass",
+                &[],
+            )
+            .await?
+            .get(0);
+        if is_partitioned {
+            return Ok(());
+        }
+
+        // messages keep being inserted while the constraint is validated, so it must hold for a
+        // while after now
+        let end = next_time_partition_boundary(Utc::now(), interval) + interval.as_secs() as i64;
+        let previous_table = format!("message_pmin_{}", end);
+        tracing::info!(
+            "({}) Partitioning the message table by time, the existing table becomes {}",
+            self.name_partition(partition_id),
+            previous_table
+        );
+        // with a valid constraint, the table doesn't have to be scanned when it is attached as
+        // the partition. Validating it only needs a lock that doesn't block inserts, so it is done
+        // in a separate transaction.
+        db_conn
+            .0
+            .batch_execute(&format!(
+                r"ALTER TABLE message
+    DROP CONSTRAINT IF EXISTS message_before_time_partitions,
+    ADD CONSTRAINT message_before_time_partitions CHECK (time_received < {end}) NOT VALID",
+                end = time_partition_bound(end),
+            ))
+            .await?;
+        db_conn
+            .0
+            .batch_execute("ALTER TABLE message VALIDATE CONSTRAINT message_before_time_partitions")
+            .await?;
+        db_conn
+            .0
+            .execute(
+                "SELECT partition_message_table_by_time($1, $2)",
+                &[&previous_table, &Utc.timestamp_opt(end, 0).unwrap()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Partitions the `message` table by time if needed, creates the partitions for the next
+    /// `TIME_PARTITIONS_AHEAD` intervals and drops partitions that only contain messages older
+    /// than `retention`. Messages are therefore kept up to one interval longer than `retention`.
+    async fn maintain_time_partitions(
+        &self,
+        partition_id: usize,
+        interval: Duration,
+        retention: Duration,
+    ) -> Result<(), StorageError> {
+        self.convert_to_time_partitioned(partition_id, interval)
+            .await?;
+
+        let mut db_conn = self.get_db_conn(partition_id).await?;
+        let time_partitions = db_conn
+            .0
+            .query(
+                r"SELECT child.relname
+FROM pg_inherits
+JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+WHERE pg_inherits.inhparent = 'message'::regclass",
+                &[],
+            )
+            .await?
+            .into_iter()
+            .filter_map(|row| TimePartition::from_name(row.get(0)))
+            .collect_vec();
+
+        let now = Utc::now();
+        let interval_secs = interval.as_secs() as i64;
+        // continue after the newest partition, even if the interval was changed since. If this
+        // didn't run for a while, messages received in between stay in the default partition.
+        let current_boundary = next_time_partition_boundary(now, interval) - interval_secs;
+        let mut start = time_partitions
+            .iter()
+            .map(|time_partition| time_partition.end)
+            .max()
+            .map_or(current_boundary, |newest_end| {
+                newest_end.max(current_boundary)
+            });
+        while start < now.timestamp() + TIME_PARTITIONS_AHEAD * interval_secs {
+            let end = start + interval_secs;
+            let name = format!("message_p{}_{}", start, end);
+            // messages in the default partition that belong into the new partition (e.g. if
+            // this didn't run for a while) must be moved, otherwise it can't be created. The
+            // twitch_message_id index can't be created on the partitioned table, see the
+            // migration.
+            let transaction = db_conn.0.transaction().await?;
+            transaction
+                .batch_execute(&format!(
+                    r"ALTER TABLE message DETACH PARTITION message_pdefault;
+CREATE TABLE {name} PARTITION OF message FOR VALUES FROM ({start}) TO ({end});
+CREATE UNIQUE INDEX ON {name} (channel_login, twitch_message_id) WHERE twitch_message_id IS NOT NULL;
+WITH moved AS (
+    DELETE FROM message_pdefault
+    WHERE time_received >= {start} AND time_received < {end}
+    RETURNING *
+)
+INSERT INTO message SELECT * FROM moved;
+ALTER TABLE message ATTACH PARTITION message_pdefault DEFAULT;",
+                    name = name,
+                    start = time_partition_bound(start),
+                    end = time_partition_bound(end),
+                ))
+                .await?;
+            transaction.commit().await?;
+            start = end;
+        }
+
+        let expired_before = now.timestamp() - retention.as_secs() as i64;
+        let mut messages_dropped = 0;
+        for time_partition in time_partitions
+            .iter()
+            .filter(|time_partition| time_partition.end <= expired_before)
+        {
+            let num_messages: i64 = db_conn
+                .0
+                .query_one(
+                    &format!("SELECT count(*) FROM {}", time_partition.name),
+                    &[],
+                )
+                .await?
+                .get(0);
+            db_conn
+                .0
+                .batch_execute(&format!("DROP TABLE {}", time_partition.name))
+                .await?;
+            tracing::debug!(
+                "({}) Dropped expired partition {} with {} messages",
+                self.name_partition(partition_id),
+                time_partition.name,
+                num_messages
+            );
+            messages_dropped += num_messages as u64;
+        }
+        // e.g. imported messages that are older than all partitions
+        messages_dropped += db_conn
+            .0
+            .execute(
+                "DELETE FROM message_pdefault WHERE time_received < now() - make_interval(secs => $1)",
+                &[&retention.as_secs_f64()],
+            )
+            .await?;
+
+        MESSAGES_VACUUMED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .inc_by(messages_dropped);
+        MESSAGES_STORED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .sub(messages_dropped as i64);
+        Ok(())
+    }
+
     pub async fn run_task_vacuum_old_messages(
         &'static self,
         config: &'static Config,
@@ -655,7 +2237,13 @@ WHERE access_token = $1",
     ) {
         let vacuum_messages_every = config.app.vacuum_messages_every;
         let message_expire_after = config.app.messages_expire_after;
+        let quiet_channel_messages_expire_after = config
+            .app
+            .quiet_channel_messages_expire_after
+            .unwrap_or(message_expire_after);
         let max_buffer_size = config.app.max_buffer_size;
+        let vacuum_concurrency = config.app.vacuum_concurrency;
+        let message_partition_interval = config.app.message_partition_interval;
 
         let mut check_interval = tokio::time::interval(vacuum_messages_every);
         check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -665,13 +2253,39 @@ WHERE access_token = $1",
                 check_interval.tick().await;
                 tracing::info!("Running vacuum for old messages");
                 for partition_id in 0..self.shard_dbs.len() + 1 {
+                    if !self.is_partition_available(partition_id) {
+                        continue;
+                    }
                     tokio::spawn(async move {
+                        if let Some(interval) = message_partition_interval {
+                            let res = self
+                                .maintain_time_partitions(
+                                    partition_id,
+                                    interval,
+                                    Duration::max(
+                                        message_expire_after,
+                                        quiet_channel_messages_expire_after,
+                                    ),
+                                )
+                                .await;
+                            if let Err(e) = res {
+                                tracing::error!(
+                                    "({}) Failed to maintain time partitions of the message table: {}",
+                                    self.name_partition(partition_id),
+                                    e
+                                );
+                            }
+                        }
+
                         let res = self
                             .run_message_vacuum(
                                 partition_id,
                                 vacuum_messages_every,
                                 message_expire_after,
+                                quiet_channel_messages_expire_after,
                                 max_buffer_size,
+                                vacuum_concurrency,
+                                message_partition_interval.is_some(),
                             )
                             .await;
 
@@ -692,17 +2306,29 @@ WHERE access_token = $1",
     }
 
     /// Delete messages older than `messages_expire_after` and messages that go beyond the
-    /// maximum buffer size.
+    /// maximum buffer size. Channels that do not exceed the buffer size instead keep their
+    /// messages until they are older than `quiet_channel_messages_expire_after`.
+    ///
+    /// The channels are spread over `vacuum_messages_every`, up to `concurrency` channels are
+    /// vacuumed at the same time if vacuuming a channel takes longer than its share of that time.
+    ///
+    /// If the message table is `time_partitioned`, messages of quiet channels are not deleted
+    /// here, they expire by dropping their time partition instead.
+    #[allow(clippy::too_many_arguments)]
     async fn run_message_vacuum(
         &self,
         partition_id: usize,
         vacuum_messages_every: Duration,
         messages_expire_after: Duration,
+        quiet_channel_messages_expire_after: Duration,
         max_buffer_size: usize,
+        concurrency: usize,
+        time_partitioned: bool,
     ) -> Result<(), StorageError> {
-        let db_conn = self.get_db_conn(partition_id).await?;
-
-        let channels_with_messages: Vec<String> = db_conn
+        let started = Instant::now();
+        let channels_with_messages: Vec<String> = self
+            .get_db_conn(partition_id)
+            .await?
             .0
             .query("SELECT DISTINCT channel_login FROM message", &[])
             .await?
@@ -715,15 +2341,66 @@ WHERE access_token = $1",
         }
 
         let time_between_channels = vacuum_messages_every / channels_with_messages.len() as u32;
-        let mut interval = tokio::time::interval(time_between_channels);
+        let interval = tokio::time::interval(time_between_channels);
+
+        // the next channel is only taken (and the interval only ticks) once fewer than
+        // `concurrency` channels are being vacuumed
+        let paced_channels = stream::unfold(
+            (interval, channels_with_messages.into_iter()),
+            |(mut interval, mut channels)| async move {
+                let channel = channels.next()?;
+                interval.tick().await;
+                Some((channel, (interval, channels)))
+            },
+        );
+        paced_channels
+            .for_each_concurrent(usize::max(concurrency, 1), |channel| async move {
+                let res = self
+                    .vacuum_channel(
+                        partition_id,
+                        &channel,
+                        messages_expire_after,
+                        quiet_channel_messages_expire_after,
+                        max_buffer_size,
+                        time_partitioned,
+                    )
+                    .await;
+                if let Err(e) = res {
+                    tracing::error!(
+                        "({}) Failed to vacuum channel {}: {}",
+                        self.name_partition(partition_id),
+                        channel,
+                        e
+                    );
+                }
+            })
+            .await;
 
-        for channel in channels_with_messages {
-            interval.tick().await;
-            VACUUM_RUNS
-                .with_label_values(&[self.name_partition(partition_id)])
-                .inc();
+        VACUUM_SWEEP_DURATION
+            .with_label_values(&[self.name_partition(partition_id)])
+            .set(started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    async fn vacuum_channel(
+        &self,
+        partition_id: usize,
+        channel: &str,
+        messages_expire_after: Duration,
+        quiet_channel_messages_expire_after: Duration,
+        max_buffer_size: usize,
+        time_partitioned: bool,
+    ) -> Result<(), StorageError> {
+        VACUUM_RUNS
+            .with_label_values(&[self.name_partition(partition_id)])
+            .inc();
 
-            let execute_result = db_conn
+        let db_conn = self.get_db_conn(partition_id).await?;
+        let max_offset = (max_buffer_size as i64) - 1;
+        let messages_expire_after = messages_expire_after.as_secs_f64();
+        let quiet_channel_messages_expire_after = quiet_channel_messages_expire_after.as_secs_f64();
+        let messages_deleted = if time_partitioned {
+            db_conn
                 .0
                 .execute(
                     "DELETE FROM message
@@ -740,46 +2417,129 @@ AND (
 
 	OR
 
-	time_received < now() - make_interval(secs => $3)
+	(
+		time_received < now() - make_interval(secs => $3)
+		-- channel has more than max_buffer_size messages
+		AND EXISTS (
+			SELECT 1
+			FROM message
+			WHERE channel_login = $1
+			ORDER BY time_received DESC
+			OFFSET $2 + 1
+			LIMIT 1
+		)
+	)
+)",
+                    &[&channel, &max_offset, &messages_expire_after],
+                )
+                .await?
+        } else {
+            db_conn
+                .0
+                .execute(
+                    "DELETE FROM message
+WHERE channel_login = $1
+AND (
+	time_received < (
+		SELECT time_received
+		FROM message
+		WHERE channel_login = $1
+		ORDER BY time_received DESC
+		OFFSET $2
+		LIMIT 1
+	)
+
+	OR
+
+	(
+		time_received < now() - make_interval(secs => $3)
+		AND (
+			time_received < now() - make_interval(secs => $4)
+			-- channel has more than max_buffer_size messages
+			OR EXISTS (
+				SELECT 1
+				FROM message
+				WHERE channel_login = $1
+				ORDER BY time_received DESC
+				OFFSET $2 + 1
+				LIMIT 1
+			)
+		)
+	)
 )",
                     &[
                         &channel,
-                        &((max_buffer_size as i64) - 1),
-                        &messages_expire_after.as_secs_f64(),
+                        &max_offset,
+                        &messages_expire_after,
+                        &quiet_channel_messages_expire_after,
                     ],
                 )
-                .await;
-
-            let messages_deleted = match execute_result {
-                Ok(messages_deleted) => messages_deleted,
-                Err(e) => {
-                    tracing::error!(
-                        "({}) Failed to vacuum channel {}: {}",
-                        self.name_partition(partition_id),
-                        channel,
-                        e
-                    );
-                    continue;
-                }
-            };
-
-            MESSAGES_VACUUMED
-                .with_label_values(&[self.name_partition(partition_id)])
-                .inc_by(messages_deleted);
-            MESSAGES_STORED
-                .with_label_values(&[self.name_partition(partition_id)])
-                .sub(messages_deleted as i64);
-        }
+                .await?
+        };
 
+        MESSAGES_VACUUMED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .inc_by(messages_deleted);
+        MESSAGES_STORED
+            .with_label_values(&[self.name_partition(partition_id)])
+            .sub(messages_deleted as i64);
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod integration_tests;
+
 #[cfg(test)]
 pub mod test {
+    use super::{next_time_partition_boundary, TimePartition};
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
     #[test]
     pub fn dump_migrations() {
         dbg!(super::migrations_main::migrations::runner().get_migrations());
         dbg!(super::migrations_shard::migrations::runner().get_migrations());
     }
+
+    #[test]
+    fn time_partition_names_are_parsed() {
+        assert_eq!(
+            TimePartition::from_name("message_p1596060000_1596063600"),
+            Some(TimePartition {
+                name: "message_p1596060000_1596063600".to_owned(),
+                end: 1596063600,
+            })
+        );
+        assert_eq!(
+            TimePartition::from_name("message_pmin_1596060000"),
+            Some(TimePartition {
+                name: "message_pmin_1596060000".to_owned(),
+                end: 1596060000,
+            })
+        );
+        assert_eq!(TimePartition::from_name("message_pabc_1596060000"), None);
+        assert_eq!(TimePartition::from_name("message_pdefault"), None);
+        assert_eq!(TimePartition::from_name("message_archive"), None);
+    }
+
+    #[test]
+    fn time_partition_boundaries_are_aligned_to_the_interval() {
+        let hour = Duration::from_secs(60 * 60);
+        let time = Utc.with_ymd_and_hms(2020, 7, 29, 22, 17, 38).unwrap();
+        assert_eq!(
+            next_time_partition_boundary(time, hour),
+            Utc.with_ymd_and_hms(2020, 7, 29, 23, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+        // exactly on a boundary: the next one
+        let time = Utc.with_ymd_and_hms(2020, 7, 29, 22, 0, 0).unwrap();
+        assert_eq!(
+            next_time_partition_boundary(time, hour),
+            Utc.with_ymd_and_hms(2020, 7, 29, 23, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+    }
 }
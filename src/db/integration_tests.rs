@@ -0,0 +1,964 @@
+//! End-to-end tests of [`DataStorage`] against a real PostgreSQL server.
+//!
+//! The server is started in a throwaway Docker container via `testcontainers`, so these tests
+//! need a working Docker daemon. They are therefore ignored by default, run them with
+//! `cargo test -- --ignored`.
+
+use super::{DataStorage, MessageToStore, StoredMessage};
+use crate::config::{DatabaseConfig, ExportOption, PgHost, PgSslMode};
+use chrono::{DateTime, SubsecRound, Utc};
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::time::Duration;
+use testcontainers::clients::Cli;
+use testcontainers::images::postgres::Postgres;
+use testcontainers::Container;
+
+/// A migrated, empty storage backed by a fresh PostgreSQL container. The container is stopped
+/// and removed when this is dropped.
+struct TestStorage<'d> {
+    storage: DataStorage,
+    _container: Container<'d, Postgres>,
+}
+
+fn db_config(port: u16, dbname: &str) -> DatabaseConfig {
+    DatabaseConfig {
+        user: Some("postgres".to_owned()),
+        dbname: Some(dbname.to_owned()),
+        host: vec![PgHost::Tcp {
+            hostname: "127.0.0.1".to_owned(),
+            port,
+        }],
+        ssl_mode: PgSslMode::Disable,
+        ..DatabaseConfig::default()
+    }
+}
+
+/// Start a PostgreSQL server and connect to it with `num_shards` additional shards. Main and
+/// shard databases all live on the same server.
+async fn start_storage(docker: &Cli, num_shards: usize) -> TestStorage<'_> {
+    let container = docker.run(Postgres::default());
+    let port = container.get_host_port_ipv4(5432);
+
+    let mut partition_id_counter = 0usize;
+    let main_db = super::connect_to_single_postgres_server(
+        &db_config(port, "postgres"),
+        &mut partition_id_counter,
+    );
+
+    let mut shard_dbs = vec![];
+    for i in 0..num_shards {
+        let dbname = format!("shard{}", i + 1);
+        main_db
+            .db_pool
+            .get()
+            .await
+            .unwrap()
+            .execute(format!("CREATE DATABASE {}", dbname).as_str(), &[])
+            .await
+            .unwrap();
+        shard_dbs.push(super::connect_to_single_postgres_server(
+            &db_config(port, &dbname),
+            &mut partition_id_counter,
+        ));
+    }
+
+    let storage = DataStorage::new(main_db, shard_dbs, true, None);
+    storage.run_migrations(false).await.unwrap();
+
+    TestStorage {
+        storage,
+        _container: container,
+    }
+}
+
+fn now_millis() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(3)
+}
+
+fn privmsg(channel_login: &str, text: &str) -> String {
+    format!(
+        ":randers!randers@randers.tmi.twitch.tv PRIVMSG #{} :{}",
+        channel_login, text
+    )
+}
+
+/// Build `count` messages for `channel_login`, one second apart, the newest being `newest`.
+fn messages_for(channel_login: &str, count: usize, newest: DateTime<Utc>) -> Vec<MessageToStore> {
+    (0..count)
+        .rev()
+        .map(|i| MessageToStore {
+            channel_login: channel_login.to_owned(),
+            time_received: newest - chrono::Duration::seconds(i as i64),
+            message_source: privmsg(channel_login, &format!("message {}", count - 1 - i)),
+            twitch_message_id: None,
+            sender_login: None,
+            sender_display_name: None,
+            color: None,
+        })
+        .collect_vec()
+}
+
+impl TestStorage<'_> {
+    /// Like `DataStorage::append_messages`, but waits for all inserts to complete.
+    async fn append(&self, messages: Vec<MessageToStore>) {
+        let group_map = messages.into_iter().into_group_map_by(|message| {
+            self.storage.channel_to_partition_id(&message.channel_login)
+        });
+        for (partition_id, messages) in group_map {
+            self.storage
+                .append_messages_partition(partition_id, messages)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn get_all(&self, channel_login: &str) -> Vec<StoredMessage> {
+        self.storage
+            .get_messages(channel_login, None, None, None, 10_000)
+            .await
+            .unwrap()
+    }
+
+    async fn count_in_partition(&self, partition_id: usize, channel_login: &str) -> i64 {
+        self.storage
+            .get_db_conn(partition_id)
+            .await
+            .unwrap()
+            .0
+            .query_one(
+                "SELECT count(*) FROM message WHERE channel_login = $1",
+                &[&channel_login],
+            )
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    async fn last_access(&self, channel_login: &str) -> DateTime<Utc> {
+        self.storage
+            .get_db_conn_main()
+            .await
+            .unwrap()
+            .0
+            .query_one(
+                "SELECT last_access FROM channel WHERE channel_login = $1",
+                &[&channel_login],
+            )
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    async fn set_last_access_minutes_ago(&self, channel_login: &str, minutes: i32) {
+        self.storage
+            .get_db_conn_main()
+            .await
+            .unwrap()
+            .0
+            .execute(
+                "UPDATE channel SET last_access = now() - make_interval(mins => $2)
+WHERE channel_login = $1",
+                &[&channel_login, &minutes],
+            )
+            .await
+            .unwrap();
+    }
+}
+
+fn texts(messages: &[StoredMessage]) -> Vec<&str> {
+    messages
+        .iter()
+        .map(|m| m.message_source.rsplit(':').next().unwrap())
+        .collect_vec()
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn append_and_get_messages() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    test.append(messages_for("pajlada", 5, newest)).await;
+    test.append(messages_for("randers", 3, newest)).await;
+
+    let messages = test.get_all("pajlada").await;
+    assert_eq!(
+        texts(&messages),
+        vec![
+            "message 0",
+            "message 1",
+            "message 2",
+            "message 3",
+            "message 4"
+        ]
+    );
+    assert_eq!(messages.last().unwrap().time_received, newest);
+    assert_eq!(test.get_all("randers").await.len(), 3);
+    assert!(test.get_all("forsen").await.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn get_messages_limit_before_after() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    let messages = messages_for("pajlada", 10, newest);
+    let timestamps = messages.iter().map(|m| m.time_received).collect_vec();
+    test.append(messages).await;
+
+    // limit takes the newest N
+    let result = test
+        .storage
+        .get_messages("pajlada", Some(3), None, None, 500)
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 7", "message 8", "message 9"]);
+
+    // limit is clamped to max_buffer_size
+    let result = test
+        .storage
+        .get_messages("pajlada", Some(8), None, None, 2)
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 8", "message 9"]);
+
+    // before and after are exclusive
+    let result = test
+        .storage
+        .get_messages(
+            "pajlada",
+            None,
+            Some(timestamps[5]),
+            Some(timestamps[2]),
+            500,
+        )
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 3", "message 4"]);
+
+    // all three combined
+    let result = test
+        .storage
+        .get_messages(
+            "pajlada",
+            Some(1),
+            Some(timestamps[5]),
+            Some(timestamps[2]),
+            500,
+        )
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 4"]);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn get_message_by_id() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let mut messages = messages_for("pajlada", 2, now_millis());
+    messages[0].twitch_message_id = Some("abc".to_owned());
+    test.append(messages).await;
+
+    let found = test
+        .storage
+        .get_message_by_id("pajlada", "abc")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(texts(&[found]), vec!["message 0"]);
+    assert!(test
+        .storage
+        .get_message_by_id("forsen", "abc")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(test
+        .storage
+        .get_message_by_id("pajlada", "def")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn get_roomstates_returns_the_newest() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let mut messages = messages_for("pajlada", 4, now_millis());
+    for (i, message) in messages.iter_mut().enumerate().skip(1) {
+        message.message_source = format!(
+            "@slow={} :tmi.twitch.tv ROOMSTATE #pajlada :message {}",
+            i, i
+        );
+    }
+    // a newer chat message that only mentions a ROOMSTATE
+    messages.push(MessageToStore {
+        message_source: privmsg("pajlada", ":tmi.twitch.tv ROOMSTATE #pajlada"),
+        ..messages_for("pajlada", 1, now_millis() + chrono::Duration::seconds(1)).remove(0)
+    });
+    test.append(messages).await;
+
+    let result = test.storage.get_roomstates("pajlada", 2).await.unwrap();
+    assert_eq!(texts(&result), vec!["message 2", "message 3"]);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn get_moderation_messages_after() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let mut messages = messages_for("pajlada", 4, now_millis());
+    messages[0].message_source =
+        ":tmi.twitch.tv CLEARCHAT #pajlada :message 0 before the lookup".to_owned();
+    messages[2].message_source = ":tmi.twitch.tv CLEARCHAT #pajlada :message 2".to_owned();
+    messages[3].message_source =
+        "@target-msg-id=abc :tmi.twitch.tv CLEARMSG #pajlada :message 3".to_owned();
+    test.append(messages).await;
+
+    let all = test.get_all("pajlada").await;
+    let result = test
+        .storage
+        .get_moderation_messages_after("pajlada", all[1].time_received, all[1].id)
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 2", "message 3"]);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn get_messages_window_pages_oldest_first() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    let messages = messages_for("pajlada", 10, newest);
+    let timestamps = messages.iter().map(|m| m.time_received).collect_vec();
+    test.append(messages).await;
+
+    let window = |after, limit| {
+        test.storage
+            .get_messages_window("pajlada", after, None, Some(timestamps[8]), limit)
+    };
+    let result = window(timestamps[1], 3).await.unwrap();
+    assert_eq!(texts(&result), vec!["message 2", "message 3", "message 4"]);
+    let result = window(result.last().unwrap().time_received, 3)
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 5", "message 6", "message 7"]);
+    let result = window(result.last().unwrap().time_received, 3)
+        .await
+        .unwrap();
+    assert!(result.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn messages_of_the_same_millisecond_keep_their_order() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let now = now_millis();
+    let messages = messages_for("pajlada", 5, now)
+        .into_iter()
+        .map(|message| MessageToStore {
+            time_received: now,
+            ..message
+        })
+        .collect_vec();
+    test.append(messages).await;
+
+    let all = test.get_all("pajlada").await;
+    assert_eq!(
+        texts(&all),
+        vec![
+            "message 0",
+            "message 1",
+            "message 2",
+            "message 3",
+            "message 4"
+        ]
+    );
+
+    // paging with the ID of the last message neither skips nor repeats messages
+    let before = now + chrono::Duration::seconds(1);
+    let after = now - chrono::Duration::seconds(1);
+    let result = test
+        .storage
+        .get_messages_window("pajlada", after, None, Some(before), 2)
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 0", "message 1"]);
+    let last = result.last().unwrap();
+    let result = test
+        .storage
+        .get_messages_window(
+            "pajlada",
+            last.time_received,
+            Some(last.id),
+            Some(before),
+            2,
+        )
+        .await
+        .unwrap();
+    assert_eq!(texts(&result), vec!["message 2", "message 3"]);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn count_messages_per_bucket_includes_empty_buckets() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    // 10 messages in the last 9 seconds, another channel's message is not counted
+    test.append(messages_for("pajlada", 10, newest)).await;
+    test.append(messages_for("forsen", 1, newest)).await;
+
+    let counts = test
+        .storage
+        .count_messages_per_bucket(
+            "pajlada",
+            newest - chrono::Duration::seconds(15),
+            newest,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        counts,
+        vec![
+            (newest - chrono::Duration::seconds(15), 0),
+            (newest - chrono::Duration::seconds(10), 4),
+            (newest - chrono::Duration::seconds(5), 5),
+            (newest, 1),
+        ]
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn purge_messages_only_affects_one_channel() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    test.append(messages_for("pajlada", 5, newest)).await;
+    test.append(messages_for("randers", 5, newest)).await;
+
+    test.storage.purge_messages("pajlada").await.unwrap();
+
+    assert!(test.get_all("pajlada").await.is_empty());
+    assert_eq!(test.get_all("randers").await.len(), 5);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn append_messages_eventually_stores() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 1).await;
+    test.storage
+        .append_messages(messages_for("pajlada", 5, now_millis()), |messages| {
+            messages
+        })
+        .await;
+
+    let mut stored = 0;
+    for _ in 0..50 {
+        stored = test.get_all("pajlada").await.len();
+        if stored == 5 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(stored, 5);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn messages_with_duplicate_ids_are_skipped() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    let with_ids = |ids: &[&str]| {
+        messages_for("pajlada", ids.len(), newest)
+            .into_iter()
+            .zip(ids)
+            .map(|(message, id)| MessageToStore {
+                twitch_message_id: Some((*id).to_owned()),
+                ..message
+            })
+            .collect_vec()
+    };
+
+    test.append(with_ids(&["a", "b", "c"])).await;
+    // e.g. a reconnect re-delivering messages that were already stored
+    test.append(with_ids(&["b", "c", "d"])).await;
+    // messages without an ID are never deduplicated
+    test.append(messages_for("pajlada", 2, newest)).await;
+
+    assert_eq!(test.count_in_partition(0, "pajlada").await, 6);
+    // the same ID in another channel is not a duplicate
+    test.append(
+        with_ids(&["a"])
+            .into_iter()
+            .map(|message| MessageToStore {
+                channel_login: "randers".to_owned(),
+                message_source: privmsg("randers", "a"),
+                ..message
+            })
+            .collect_vec(),
+    )
+    .await;
+    assert_eq!(test.count_in_partition(0, "randers").await, 1);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn sender_columns_are_stored() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    let mut messages = messages_for("pajlada", 2, newest);
+    messages[1].sender_login = Some("randers".to_owned());
+    messages[1].sender_display_name = Some("RANDERS".to_owned());
+    messages[1].color = Some("#FF0000".to_owned());
+    test.append(messages).await;
+
+    let stored = test
+        .storage
+        .get_db_conn(0)
+        .await
+        .unwrap()
+        .0
+        .query(
+            "SELECT sender_login, sender_display_name, color FROM message
+            WHERE channel_login = 'pajlada' ORDER BY time_received ASC",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(stored[0].get::<_, Option<String>>("sender_login"), None);
+    assert_eq!(
+        stored[1]
+            .get::<_, Option<String>>("sender_login")
+            .as_deref(),
+        Some("randers")
+    );
+    assert_eq!(
+        stored[1]
+            .get::<_, Option<String>>("sender_display_name")
+            .as_deref(),
+        Some("RANDERS")
+    );
+    assert_eq!(
+        stored[1].get::<_, Option<String>>("color").as_deref(),
+        Some("#FF0000")
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn large_chunks_are_split_into_multiple_inserts() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let count = super::MAX_ROWS_PER_MESSAGE_INSERT + 10;
+    test.append(messages_for("pajlada", count, now_millis()))
+        .await;
+
+    assert_eq!(test.count_in_partition(0, "pajlada").await, count as i64);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn messages_are_stored_on_their_partition() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 2).await;
+    let newest = now_millis();
+    let channels = ["pajlada", "randers", "forsen", "xqcow", "zneix", "supinic"];
+    for channel in channels {
+        test.append(messages_for(channel, 2, newest)).await;
+    }
+
+    for channel in channels {
+        let partition_id = test.storage.channel_to_partition_id(channel);
+        for other_partition_id in 0..3 {
+            let expected = if other_partition_id == partition_id {
+                2
+            } else {
+                0
+            };
+            assert_eq!(
+                test.count_in_partition(other_partition_id, channel).await,
+                expected,
+                "channel {} in partition {}",
+                channel,
+                other_partition_id
+            );
+        }
+        assert_eq!(test.get_all(channel).await.len(), 2);
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn rebalance_moves_messages_to_their_partition() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 2).await;
+    let newest = now_millis();
+    let channels = ["pajlada", "randers", "forsen", "xqcow", "zneix", "supinic"];
+    // as if the shards were just added: everything is still on the main database
+    for channel in channels {
+        test.storage
+            .append_messages_partition(0, messages_for(channel, 3, newest))
+            .await
+            .unwrap();
+    }
+
+    let summary = test.storage.rebalance().await.unwrap();
+    // xqcow maps to the main database
+    assert_eq!(summary.channels_moved, 5);
+    assert_eq!(summary.messages_moved, 15);
+
+    for channel in channels {
+        let partition_id = test.storage.channel_to_partition_id(channel);
+        for other_partition_id in 0..3 {
+            let expected = if other_partition_id == partition_id {
+                3
+            } else {
+                0
+            };
+            assert_eq!(
+                test.count_in_partition(other_partition_id, channel).await,
+                expected,
+                "channel {} in partition {}",
+                channel,
+                other_partition_id
+            );
+        }
+        let texts = test
+            .get_all(channel)
+            .await
+            .into_iter()
+            .map(|message| message.message_source)
+            .collect_vec();
+        assert_eq!(
+            texts,
+            messages_for(channel, 3, newest)
+                .into_iter()
+                .map(|m| m.message_source)
+                .collect_vec()
+        );
+    }
+
+    let summary = test.storage.rebalance().await.unwrap();
+    assert_eq!(summary.channels_moved, 0);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn rebalance_skips_messages_that_were_already_moved() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 2).await;
+    let newest = now_millis();
+    let partition_id = test.storage.channel_to_partition_id("pajlada");
+    assert_ne!(partition_id, 0);
+    // as if a previous run inserted the batch but failed to delete it from the main database
+    for partition_id in [0, partition_id] {
+        test.storage
+            .append_messages_partition(partition_id, messages_for("pajlada", 3, newest))
+            .await
+            .unwrap();
+    }
+
+    let summary = test.storage.rebalance().await.unwrap();
+    assert_eq!(summary.messages_moved, 3);
+    assert_eq!(test.count_in_partition(0, "pajlada").await, 0);
+    assert_eq!(test.count_in_partition(partition_id, "pajlada").await, 3);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn vacuum_keeps_max_buffer_size_newest_messages() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    test.append(messages_for("pajlada", 20, newest)).await;
+    test.append(messages_for("randers", 3, newest)).await;
+
+    test.storage
+        .run_message_vacuum(
+            0,
+            Duration::from_millis(10),
+            Duration::from_secs(24 * 60 * 60),
+            Duration::from_secs(24 * 60 * 60),
+            5,
+            1,
+            false,
+        )
+        .await
+        .unwrap();
+
+    let messages = test.get_all("pajlada").await;
+    assert_eq!(
+        texts(&messages),
+        vec![
+            "message 15",
+            "message 16",
+            "message 17",
+            "message 18",
+            "message 19"
+        ]
+    );
+    // channels below the buffer size are untouched
+    assert_eq!(test.get_all("randers").await.len(), 3);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn vacuum_expires_old_messages() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    // the four old messages are at least 15 minutes old, so with an expiry of 10 minutes they
+    // should get removed
+    let old = messages_for("pajlada", 4, newest - chrono::Duration::minutes(15));
+    let recent = messages_for("pajlada", 3, newest);
+    test.append(old).await;
+    test.append(recent).await;
+
+    test.storage
+        .run_message_vacuum(
+            0,
+            Duration::from_millis(10),
+            Duration::from_secs(10 * 60),
+            Duration::from_secs(10 * 60),
+            500,
+            1,
+            false,
+        )
+        .await
+        .unwrap();
+
+    let messages = test.get_all("pajlada").await;
+    assert_eq!(messages.len(), 3);
+    assert!(messages
+        .iter()
+        .all(|m| m.time_received > newest - chrono::Duration::minutes(1)));
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn vacuum_keeps_old_messages_of_quiet_channels_longer() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let old = now_millis() - chrono::Duration::minutes(15);
+    // pajlada is above the buffer size of 5, randers is below it
+    test.append(messages_for("pajlada", 8, old)).await;
+    test.append(messages_for("randers", 4, old)).await;
+
+    test.storage
+        .run_message_vacuum(
+            0,
+            Duration::from_millis(10),
+            Duration::from_secs(10 * 60),
+            Duration::from_secs(60 * 60),
+            5,
+            1,
+            false,
+        )
+        .await
+        .unwrap();
+
+    assert!(test.get_all("pajlada").await.is_empty());
+    assert_eq!(test.get_all("randers").await.len(), 4);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn time_partitioning_keeps_existing_messages() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let newest = now_millis();
+    test.append(messages_for(
+        "pajlada",
+        3,
+        newest - chrono::Duration::hours(2),
+    ))
+    .await;
+
+    let hour = Duration::from_secs(60 * 60);
+    let retention = Duration::from_secs(24 * 60 * 60);
+    test.storage
+        .maintain_time_partitions(0, hour, retention)
+        .await
+        .unwrap();
+    // running it again must not fail or create duplicate partitions
+    test.storage
+        .maintain_time_partitions(0, hour, retention)
+        .await
+        .unwrap();
+
+    let num_partitions: i64 = test
+        .storage
+        .get_db_conn(0)
+        .await
+        .unwrap()
+        .0
+        .query_one(
+            "SELECT count(*) FROM pg_inherits WHERE inhparent = 'message'::regclass",
+            &[],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    // the previous table, the default partition and at least the next three hours
+    assert!(num_partitions >= 5, "{} partitions", num_partitions);
+
+    test.append(messages_for("pajlada", 2, newest)).await;
+    let messages = test.get_all("pajlada").await;
+    assert_eq!(
+        texts(&messages),
+        vec![
+            "message 0",
+            "message 1",
+            "message 2",
+            "message 0",
+            "message 1"
+        ]
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn time_partitions_skip_duplicate_ids() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    test.storage
+        .maintain_time_partitions(
+            0,
+            Duration::from_secs(60 * 60),
+            Duration::from_secs(24 * 60 * 60),
+        )
+        .await
+        .unwrap();
+
+    let with_id = || {
+        messages_for("pajlada", 1, now_millis())
+            .into_iter()
+            .map(|message| MessageToStore {
+                twitch_message_id: Some("abc".to_owned()),
+                ..message
+            })
+            .collect_vec()
+    };
+    test.append(with_id()).await;
+    test.append(with_id()).await;
+
+    assert_eq!(test.count_in_partition(0, "pajlada").await, 1);
+    assert!(test
+        .storage
+        .get_message_by_id("pajlada", "abc")
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn touch_or_add_channel_is_debounced() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+
+    test.storage
+        .touch_or_add_channel("pajlada", Duration::from_secs(30 * 60))
+        .await
+        .unwrap();
+    assert!(Utc::now() - test.last_access("pajlada").await < chrono::Duration::minutes(1));
+
+    // touched recently: no update
+    test.set_last_access_minutes_ago("pajlada", 10).await;
+    let before = test.last_access("pajlada").await;
+    test.storage
+        .touch_or_add_channel("pajlada", Duration::from_secs(30 * 60))
+        .await
+        .unwrap();
+    assert_eq!(test.last_access("pajlada").await, before);
+
+    // touched more than 30 minutes ago: update
+    test.set_last_access_minutes_ago("pajlada", 40).await;
+    test.storage
+        .touch_or_add_channel("pajlada", Duration::from_secs(30 * 60))
+        .await
+        .unwrap();
+    assert!(Utc::now() - test.last_access("pajlada").await < chrono::Duration::minutes(1));
+
+    let to_join = test
+        .storage
+        .get_channel_logins_to_join(Duration::from_secs(60 * 60))
+        .await
+        .unwrap();
+    assert!(to_join.contains("pajlada"));
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn channel_logins_are_normalized() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 3).await;
+
+    test.storage
+        .touch_or_add_channel("Pajlada", Duration::from_secs(30 * 60))
+        .await
+        .unwrap();
+    assert!(test
+        .storage
+        .set_channel_ignored("PAJLADA", true)
+        .await
+        .unwrap());
+    assert!(test.storage.is_channel_ignored("pajlada").await.unwrap());
+    assert_eq!(
+        test.storage.channel_to_partition_id("PajLada"),
+        test.storage.channel_to_partition_id("pajlada")
+    );
+
+    let num_rows: i64 = test
+        .storage
+        .get_db_conn_main()
+        .await
+        .unwrap()
+        .0
+        .query_one("SELECT count(*) FROM channel", &[])
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(num_rows, 1);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn channel_ignored_and_forced_export_options_are_fetched_together() {
+    let docker = Cli::default();
+    let test = start_storage(&docker, 0).await;
+    let lookup = |channel_login: &'static str| {
+        test.storage
+            .get_channel_ignored_and_forced_export_options(channel_login)
+    };
+
+    // unknown channels are neither ignored nor have settings
+    let (ignored, forced_export_options) = lookup("pajlada").await.unwrap();
+    assert!(!ignored);
+    assert!(forced_export_options.is_empty());
+
+    let forced = HashSet::from([ExportOption::ClearchatToNotice]);
+    test.storage
+        .set_forced_export_options("pajlada", &forced)
+        .await
+        .unwrap();
+    assert_eq!(lookup("pajlada").await.unwrap(), (false, forced.clone()));
+
+    test.storage
+        .set_channel_ignored("pajlada", true)
+        .await
+        .unwrap();
+    assert_eq!(lookup("pajlada").await.unwrap(), (true, forced));
+}
@@ -0,0 +1,233 @@
+//! Importing messages from a CSV file, such as the one written by
+//! `recent-messages2-migrate-messages` from the message files of the version 1 service.
+//!
+//! Every row has three columns: the channel login, the time the message was received in RFC 3339
+//! format, and the message in raw IRC format. Files ending in `.gz`, such as the ones written by
+//! `backup`, are decompressed.
+
+use crate::config::Config;
+use crate::db::{DataStorage, MessageToStore, StorageError};
+use crate::irc_listener;
+use chrono::{DateTime, SubsecRound, Utc};
+use flate2::read::GzDecoder;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use twitch_irc::message::{IRCMessage, ServerMessage};
+
+/// Number of rows inserted at once
+const IMPORT_CHUNK_SIZE: usize = 5000;
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("Failed to open `{}`: {1}", .0.display())]
+    Open(PathBuf, csv::Error),
+    #[error("Failed to insert messages: {0}")]
+    Insert(StorageError),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+enum InvalidRow {
+    #[error("{0}")]
+    Csv(String),
+    #[error("Expected 3 columns, got {0}")]
+    ColumnCount(usize),
+    #[error("Invalid channel login: {0}")]
+    ChannelLogin(String),
+    #[error("Invalid time: {0}")]
+    Time(String),
+    #[error("Invalid message: {0}")]
+    Message(String),
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub rows: usize,
+    pub invalid_rows: usize,
+    /// Can be less than the valid rows if messages were already stored
+    pub messages_inserted: u64,
+}
+
+/// Parses a single row into a message, filling the same columns as the IRC forwarder does. The
+/// Twitch message ID is always filled, so messages that are already stored are skipped if the
+/// same file is imported again.
+pub(crate) fn parse_row(
+    record: &csv::StringRecord,
+    store_sender_columns: bool,
+) -> Result<MessageToStore, InvalidRow> {
+    if record.len() != 3 {
+        return Err(InvalidRow::ColumnCount(record.len()));
+    }
+    let channel_login = record[0].to_owned();
+    twitch_irc::validate::validate_login(&channel_login)
+        .map_err(|e| InvalidRow::ChannelLogin(e.to_string()))?;
+    // the forwarder stores millisecond precision only, see irc_listener
+    let time_received = DateTime::parse_from_rfc3339(&record[1])
+        .map_err(|e| InvalidRow::Time(e.to_string()))?
+        .with_timezone(&Utc)
+        .trunc_subsecs(3);
+    let message_source = record[2].to_owned();
+    let message = IRCMessage::parse(&message_source)
+        .map_err(|e| InvalidRow::Message(e.to_string()))
+        .and_then(|message| {
+            ServerMessage::try_from(message).map_err(|e| InvalidRow::Message(e.to_string()))
+        })?;
+
+    let twitch_message_id = message.source().tags.0.get("id").cloned().flatten();
+    let (sender_login, sender_display_name, color) = if store_sender_columns {
+        irc_listener::sender_columns(&message)
+    } else {
+        (None, None, None)
+    };
+    Ok(MessageToStore {
+        channel_login,
+        time_received,
+        message_source,
+        twitch_message_id,
+        sender_login,
+        sender_display_name,
+        color,
+    })
+}
+
+/// Imports all valid rows of the CSV file at `path`. Invalid rows are logged and skipped, the
+/// import stops at the first failed insert.
+pub async fn import_csv(
+    data_storage: &DataStorage,
+    config: &Config,
+    path: &Path,
+) -> Result<ImportSummary, ImportError> {
+    let file = File::open(path).map_err(|e| ImportError::Open(path.to_owned(), e.into()))?;
+    let file: Box<dyn Read> = if path.extension().map_or(false, |ext| ext == "gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(file);
+
+    let mut summary = ImportSummary::default();
+    let mut chunk = Vec::with_capacity(IMPORT_CHUNK_SIZE);
+    for (i, record) in reader.records().enumerate() {
+        summary.rows += 1;
+        let line = i + 1;
+        let parsed = record
+            .map_err(|e| InvalidRow::Csv(e.to_string()))
+            .and_then(|record| parse_row(&record, config.irc.store_sender_columns));
+        match parsed {
+            Ok(message) => chunk.push(message),
+            Err(e) => {
+                tracing::warn!("Skipping row {}: {}", line, e);
+                summary.invalid_rows += 1;
+            }
+        }
+
+        if chunk.len() >= IMPORT_CHUNK_SIZE {
+            summary.messages_inserted += data_storage
+                .import_messages(std::mem::take(&mut chunk))
+                .await
+                .map_err(ImportError::Insert)?;
+            tracing::info!(
+                "Imported {} rows, {} messages inserted so far",
+                summary.rows,
+                summary.messages_inserted
+            );
+        }
+    }
+    if !chunk.is_empty() {
+        summary.messages_inserted += data_storage
+            .import_messages(chunk)
+            .await
+            .map_err(ImportError::Insert)?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_row, InvalidRow};
+    use chrono::{SecondsFormat, TimeZone, Utc};
+
+    fn record(columns: &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(columns.to_vec())
+    }
+
+    #[test]
+    fn rows_are_parsed_like_received_messages() {
+        let source = "@badge-info=;badges=;color=#FF0000;display-name=Scarrov;emotes=;flags=;id=05836fc5-76e0-45de-b0be-779c223b160b;mod=0;room-id=71092938;subscriber=0;tmi-sent-ts=1596061057664;turbo=0;user-id=195960862;user-type= :scarrov!scarrov@scarrov.tmi.twitch.tv PRIVMSG #xqcow :????????";
+        let message = parse_row(
+            &record(&["xqcow", "2020-07-29T22:17:38.008123+00:00", source]),
+            true,
+        )
+        .unwrap();
+        assert_eq!(message.channel_login, "xqcow");
+        assert_eq!(
+            message.time_received,
+            Utc.timestamp_millis_opt(1596061058008).unwrap()
+        );
+        assert_eq!(message.message_source, source);
+        assert_eq!(
+            message.twitch_message_id.as_deref(),
+            Some("05836fc5-76e0-45de-b0be-779c223b160b")
+        );
+        assert_eq!(message.sender_login.as_deref(), Some("scarrov"));
+        assert_eq!(message.color.as_deref(), Some("#FF0000"));
+
+        let message = parse_row(
+            &record(&["xqcow", "2020-07-29T22:17:38.008Z", source]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(message.sender_login, None);
+    }
+
+    #[test]
+    fn times_round_trip_at_millisecond_precision() {
+        let source = ":tmi.twitch.tv ROOMSTATE #pajlada";
+        // as written by recent-messages2-migrate-messages
+        let time_received = Utc.timestamp_millis_opt(1596061058008).unwrap();
+        let column = time_received.to_rfc3339_opts(SecondsFormat::Millis, true);
+        assert_eq!(column, "2020-07-29T22:17:38.008Z");
+        let message = parse_row(&record(&["pajlada", &column, source]), false).unwrap();
+        assert_eq!(message.time_received, time_received);
+
+        // sub-millisecond precision from other sources is dropped, so `?since=`/`?before=`
+        // filters behave the same as for messages stored by the forwarder
+        let message = parse_row(
+            &record(&["pajlada", "2020-07-29T22:17:38.008999999Z", source]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(message.time_received, time_received);
+        assert_eq!(message.time_received.timestamp_subsec_nanos(), 8_000_000);
+    }
+
+    #[test]
+    fn invalid_rows_are_rejected() {
+        let source = ":tmi.twitch.tv ROOMSTATE #pajlada";
+        assert_eq!(
+            parse_row(&record(&["pajlada", "2020-07-29T22:17:38Z"]), false).unwrap_err(),
+            InvalidRow::ColumnCount(2)
+        );
+        assert!(matches!(
+            parse_row(
+                &record(&["Paj lada", "2020-07-29T22:17:38Z", source]),
+                false
+            ),
+            Err(InvalidRow::ChannelLogin(_))
+        ));
+        assert!(matches!(
+            parse_row(&record(&["pajlada", "1596061058008", source]), false),
+            Err(InvalidRow::Time(_))
+        ));
+        assert!(matches!(
+            parse_row(&record(&["pajlada", "2020-07-29T22:17:38Z", ""]), false),
+            Err(InvalidRow::Message(_))
+        ));
+    }
+}
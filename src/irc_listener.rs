@@ -1,14 +1,24 @@
-use crate::config::Config;
-use crate::db::DataStorage;
+use crate::config::{Config, IrcConfig};
+use crate::db::{DataStorage, MessageToStore};
+use crate::message_rate::{self, SharedChannelRateTracker};
 use chrono::prelude::*;
 use chrono::Utc;
+use itertools::Itertools;
 use lazy_static::lazy_static;
-use prometheus::{exponential_buckets, register_histogram, Histogram};
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_int_counter,
+    register_int_gauge, Gauge, Histogram, IntCounter, IntGauge,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use twitch_irc::login::StaticLoginCredentials;
-use twitch_irc::message::{AsRawIRC, ServerMessage};
+use twitch_irc::message::{AsRawIRC, IRCTags, ServerMessage};
 use twitch_irc::{ClientConfig, SecureTCPTransport, TwitchIRCClient};
 
 lazy_static! {
@@ -17,11 +27,78 @@ lazy_static! {
         "Time taken to add a message to the internal channel, this amount will climb if the system is overloaded"
     )
     .unwrap();
+    static ref STORED_MESSAGE_BYTES: Histogram = register_histogram!(
+        "recentmessages_stored_message_bytes",
+        "Size of the raw IRC source of the individual messages forwarded to the database",
+        // 32 bytes to 16 KiB
+        exponential_buckets(32.0, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref ROOMSTATE_SUPPRESSED: IntCounter = register_int_counter!(
+        "recentmessages_irc_forwarder_roomstate_suppressed",
+        "Number of ROOMSTATE messages that were not stored because they were identical to the previous one of the channel"
+    )
+    .unwrap();
+    static ref RECENTLY_IGNORED_DROPPED: IntCounter = register_int_counter!(
+        "recentmessages_irc_forwarder_recently_ignored_dropped",
+        "Number of messages that were not stored because their channel was ignored just before"
+    )
+    .unwrap();
+    static ref FORWARDER_RUN_EVERY: Gauge = register_gauge!(
+        "recentmessages_irc_forwarder_run_every_seconds",
+        "Current time between two runs of the IRC forwarder, if it is not full"
+    )
+    .unwrap();
+    static ref SEED_CHANNELS_PENDING: IntGauge = register_int_gauge!(
+        "recentmessages_seed_channels_pending",
+        "Number of seed channels that are still waiting to be joined after startup"
+    )
+    .unwrap();
+    static ref IRC_CONNECTED: IntGauge = register_int_gauge!(
+        "recentmessages_irc_connected",
+        "1 if messages (including keepalive PONGs) were recently received from Twitch IRC, 0 otherwise"
+    )
+    .unwrap();
 }
 
+/// The client sends a `PING` on every connection every 30 seconds, so with a working connection
+/// there is at least one message within this time.
+const IRC_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `msg-id`s of the `NOTICE`s Twitch sends instead of confirming a join.
+const JOIN_FAILURE_NOTICE_IDS: &[&str] = &[
+    "msg_channel_suspended",
+    "msg_channel_blocked",
+    "msg_banned",
+    "msg_room_not_found",
+    "tos_ban",
+];
+
+/// Time between the PART and the JOIN of a forced rejoin.
+const FORCE_REJOIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Join failures are forgotten after this time if they are not renewed.
+const JOIN_FAILURE_RETENTION: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug, Clone)]
 pub struct IrcListener {
     pub irc_client: TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
+    connected: Arc<AtomicBool>,
+    join_failures: Arc<Mutex<JoinFailures>>,
+    confirmed_channels: Arc<Mutex<ConfirmedChannels>>,
+    join_status_cache: Arc<Mutex<JoinStatusCache>>,
+    recently_ignored: Arc<Mutex<RecentlyIgnored>>,
+}
+
+/// Whether the join to a channel is confirmed, see [`IrcListener::join_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinStatus {
+    /// The join has not been confirmed (yet), or it failed
+    NotJoined,
+    /// The channel was joined before, and is currently being rejoined after a reconnect
+    Reconnecting,
+    /// The join has been confirmed by the server
+    Joined,
 }
 
 impl IrcListener {
@@ -29,31 +106,83 @@ impl IrcListener {
         data_storage: &'static DataStorage,
         config: &'static Config,
         shutdown_signal: CancellationToken,
-    ) -> (IrcListener, JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+    ) -> (
+        IrcListener,
+        JoinHandle<()>,
+        JoinHandle<()>,
+        JoinHandle<()>,
+        JoinHandle<()>,
+        JoinHandle<()>,
+    ) {
         let (incoming_messages, client) = TwitchIRCClient::new(ClientConfig {
             new_connection_every: config.irc.new_connection_every,
             ..ClientConfig::default()
         });
 
+        let rate_tracker = message_rate::new_shared_tracker();
+        let last_message_received = Arc::new(AtomicI64::new(Utc::now().timestamp_millis()));
+        let connected = Arc::new(AtomicBool::new(false));
+        let join_failures = Arc::new(Mutex::new(JoinFailures::default()));
+        let confirmed_channels = Arc::new(Mutex::new(ConfirmedChannels::default()));
+        let join_status_cache = Arc::new(Mutex::new(JoinStatusCache::new(
+            config.irc.join_status_cache_ttl,
+        )));
+        let recently_ignored = Arc::new(Mutex::new(RecentlyIgnored::new(
+            config.irc.recently_ignored_for,
+        )));
+        let last_roomstates = Arc::new(Mutex::new(LastRoomStates::default()));
+
         let (forward_worker_join_handle, chunk_worker_join_handle) = IrcListener::run_forwarder(
             incoming_messages,
             data_storage,
             config,
+            rate_tracker.clone(),
+            last_message_received.clone(),
+            join_failures.clone(),
+            confirmed_channels.clone(),
+            join_status_cache.clone(),
+            recently_ignored.clone(),
+            last_roomstates.clone(),
             shutdown_signal.clone(),
         );
 
+        let connection_monitor_join_handle = tokio::spawn(IrcListener::run_connection_monitor(
+            last_message_received,
+            connected.clone(),
+            join_status_cache.clone(),
+            shutdown_signal.clone(),
+        ));
+
+        let message_rate_join_handle = tokio::spawn(message_rate::run_rate_exporter(
+            rate_tracker,
+            config.irc.message_rate_top_channels,
+            shutdown_signal.clone(),
+        ));
+
         let channel_jp_join_handle = tokio::spawn(IrcListener::run_channel_join_parter(
             client.clone(),
             config,
             data_storage,
+            confirmed_channels.clone(),
+            join_status_cache.clone(),
+            last_roomstates,
             shutdown_signal,
         ));
 
         (
-            IrcListener { irc_client: client },
+            IrcListener {
+                irc_client: client,
+                connected,
+                join_failures,
+                confirmed_channels,
+                join_status_cache,
+                recently_ignored,
+            },
             forward_worker_join_handle,
             chunk_worker_join_handle,
             channel_jp_join_handle,
+            message_rate_join_handle,
+            connection_monitor_join_handle,
         )
     }
 
@@ -61,18 +190,18 @@ impl IrcListener {
         mut incoming_messages: mpsc::UnboundedReceiver<ServerMessage>,
         data_storage: &'static DataStorage,
         config: &'static Config,
+        rate_tracker: SharedChannelRateTracker,
+        last_message_received: Arc<AtomicI64>,
+        join_failures: Arc<Mutex<JoinFailures>>,
+        confirmed_channels: Arc<Mutex<ConfirmedChannels>>,
+        join_status_cache: Arc<Mutex<JoinStatusCache>>,
+        recently_ignored: Arc<Mutex<RecentlyIgnored>>,
+        last_roomstates: Arc<Mutex<LastRoomStates>>,
         shutdown_signal: CancellationToken,
     ) -> (JoinHandle<()>, JoinHandle<()>) {
-        let max_chunk_size = 10000;
-
-        let smallest_bucket = 1f64;
-        let largest_bucket = max_chunk_size as f64;
-        let num_buckets = 100usize;
-        // math :) this formula is the result of "solve s*x^b = l for x"
-        // where s=smallest_bucket, x=factor, b=num_buckets, l=largest_bucket
-        let factor = (largest_bucket / smallest_bucket).powf(1f64 / (num_buckets as f64));
-
-        let buckets = exponential_buckets(smallest_bucket, factor, num_buckets).unwrap();
+        // a chunk size of 0 would never forward anything
+        let max_chunk_size = usize::max(config.irc.forwarder_max_chunk_size, 1);
+        let buckets = chunk_size_buckets(max_chunk_size);
 
         let store_chunk_chunk_size = register_histogram!(
             "recentmessages_irc_forwarder_store_chunk_chunk_size",
@@ -83,117 +212,306 @@ impl IrcListener {
 
         let (tx, mut rx) = mpsc::unbounded_channel();
 
-        let forward_worker = async move {
-            let tx = tx.clone();
-            while let Some(message) = incoming_messages.recv().await {
-                if let Some(channel_login) = message.channel_login() {
-                    let message_source = message.source().as_raw_irc();
-                    let timer = INTERNAL_FORWARD_TIME_TAKEN.start_timer();
-                    // trunc_subsecs(3): Truncates now() to millisecond precision (=3 digits subsecond precision).
-                    // This prevents problems later when we filter by ?since= and ?before=,
-                    // Where the hidden sub-millisecond precision in the database would cause
-                    // surprising behaviour.
-
-                    // For example: If a message is stored in the database at millisecond-timestamp 1701718211635.613
-                    // (notice the hidden .613 precision, which won't get exported in the @rm-received-ts tag),
-                    // The user could request ?since=1701718211635, where we would expect the message to NOT be returned.
-                    // However, because the value stored in the database is actually larger in the microseconds precision,
-                    // we get unexpected/surprising behaviour.
-
-                    // Doing the truncating here is easier than doing it later during the query/filtering,
-                    // since the database index cannot be used when filtering by the truncated timestamp.
-                    let timestamp_truncated_to_milliseconds = Utc::now().trunc_subsecs(3);
-                    tx.send((
-                        channel_login.to_owned(),
-                        timestamp_truncated_to_milliseconds,
-                        message_source,
-                    ))
-                    .ok();
-                    timer.observe_duration();
+        let track_message_rate = config.irc.message_rate_top_channels > 0;
+        let deduplicate_messages = config.irc.deduplicate_messages;
+        let store_sender_columns = config.irc.store_sender_columns;
+        let stored_message_types = &config.irc.stored_message_types;
+        let compact_roomstate = config.irc.compact_roomstate;
+        let recently_ignored_1 = recently_ignored.clone();
+        // processes a single message received from IRC
+        let mut forward = move |message: ServerMessage| {
+            last_message_received.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+            if let Some(channel_login) = message.channel_login() {
+                if track_message_rate {
+                    rate_tracker.lock().unwrap().record(channel_login);
                 }
-            }
-        };
-
-        let chunk_worker = async move {
-            loop {
-                let mut chunk = Vec::<_>::with_capacity(max_chunk_size);
-                loop {
-                    match rx.try_recv() {
-                        Ok(message) => chunk.push(message),
-                        Err(_) => break,
+                match &message {
+                    ServerMessage::Notice(notice) => {
+                        if let Some(reason) = notice
+                            .message_id
+                            .as_deref()
+                            .filter(|id| JOIN_FAILURE_NOTICE_IDS.contains(id))
+                        {
+                            join_failures
+                                .lock()
+                                .unwrap()
+                                .record(channel_login, reason, Utc::now());
+                            confirmed_channels.lock().unwrap().forget(channel_login);
+                            join_status_cache.lock().unwrap().invalidate(channel_login);
+                        }
+                    }
+                    // only sent to us after a successful join
+                    ServerMessage::RoomState(_) => {
+                        join_failures.lock().unwrap().clear(channel_login);
+                        confirmed_channels.lock().unwrap().confirm(channel_login);
                     }
-                    if chunk.len() >= max_chunk_size {
-                        break;
+                    // the first ROOMSTATE after a (re)join is always stored, the stored ones
+                    // might have been purged or expired in the meantime
+                    ServerMessage::Join(_) | ServerMessage::Part(_) => {
+                        last_roomstates.lock().unwrap().forget(channel_login);
                     }
+                    _ => {}
                 }
-                if chunk.len() < max_chunk_size {
-                    tokio::time::sleep(config.irc.forwarder_run_every).await;
+                if !stored_message_types.contains(&message.source().command) {
+                    return;
                 }
-                store_chunk_chunk_size.observe(chunk.len() as f64);
-                if chunk.len() == 0 {
-                    continue;
+                // the channel is being parted, its messages would only be purged again
+                if recently_ignored_1
+                    .lock()
+                    .unwrap()
+                    .contains(channel_login, Instant::now())
+                {
+                    RECENTLY_IGNORED_DROPPED.inc();
+                    return;
+                }
+                if let ServerMessage::RoomState(roomstate) = &message {
+                    if compact_roomstate
+                        && !last_roomstates
+                            .lock()
+                            .unwrap()
+                            .is_changed(channel_login, &roomstate.source.tags)
+                    {
+                        ROOMSTATE_SUPPRESSED.inc();
+                        return;
+                    }
                 }
+                let message_source = message.source().as_raw_irc();
+                STORED_MESSAGE_BYTES.observe(message_source.len() as f64);
+                let twitch_message_id = if deduplicate_messages {
+                    message.source().tags.0.get("id").cloned().flatten()
+                } else {
+                    None
+                };
+                let (sender_login, sender_display_name, color) = if store_sender_columns {
+                    sender_columns(&message)
+                } else {
+                    (None, None, None)
+                };
+                let timer = INTERNAL_FORWARD_TIME_TAKEN.start_timer();
+                // trunc_subsecs(3): Truncates now() to millisecond precision (=3 digits subsecond precision).
+                // This prevents problems later when we filter by ?since= and ?before=,
+                // Where the hidden sub-millisecond precision in the database would cause
+                // surprising behaviour.
 
-                data_storage.append_messages(chunk);
+                // For example: If a message is stored in the database at millisecond-timestamp 1701718211635.613
+                // (notice the hidden .613 precision, which won't get exported in the @rm-received-ts tag),
+                // The user could request ?since=1701718211635, where we would expect the message to NOT be returned.
+                // However, because the value stored in the database is actually larger in the microseconds precision,
+                // we get unexpected/surprising behaviour.
+
+                // Doing the truncating here is easier than doing it later during the query/filtering,
+                // since the database index cannot be used when filtering by the truncated timestamp.
+                let timestamp_truncated_to_milliseconds = Utc::now().trunc_subsecs(3);
+                tx.send(MessageToStore {
+                    channel_login: channel_login.to_owned(),
+                    time_received: timestamp_truncated_to_milliseconds,
+                    message_source,
+                    twitch_message_id,
+                    sender_login,
+                    sender_display_name,
+                    color,
+                })
+                .ok();
+                timer.observe_duration();
             }
         };
 
         let shutdown_signal_1 = shutdown_signal.clone();
         let forward_worker_join_handle = tokio::spawn(async move {
-            tokio::select! {
-                _ = forward_worker => {
-                    if !shutdown_signal_1.is_cancelled() {
-                        panic!("forward worker should never end")
-                    }
-                },
-                _ = shutdown_signal_1.cancelled() => {}
+            loop {
+                tokio::select! {
+                    message = incoming_messages.recv() => match message {
+                        Some(message) => forward(message),
+                        None => {
+                            if !shutdown_signal_1.is_cancelled() {
+                                panic!("forward worker should never end")
+                            }
+                            break;
+                        }
+                    },
+                    _ = shutdown_signal_1.cancelled() => break,
+                }
             }
+
+            // messages that were already received are still passed on to be stored. Dropping
+            // `forward` afterwards (and with it `tx`) lets the chunk worker finish.
+            let mut num_drained: usize = 0;
+            while let Ok(message) = incoming_messages.try_recv() {
+                forward(message);
+                num_drained += 1;
+            }
+            tracing::debug!(
+                "Forwarded {} messages that were received before shutting down",
+                num_drained
+            );
         });
 
+        // messages that were queued before their channel was ignored
+        let drop_recently_ignored =
+            move |chunk| recently_ignored.lock().unwrap().drop_ignored(chunk);
+        let mut run_every = ForwarderInterval::new(&config.irc);
+        let shutdown_drain_timeout = config.irc.shutdown_drain_timeout;
         let chunk_worker_join_handle = tokio::spawn(async move {
-            tokio::select! {
-                _ = chunk_worker => {
-                    if !shutdown_signal.is_cancelled() {
-                        panic!("chunk worker should never end")
+            loop {
+                let chunk = take_chunk(&mut rx, max_chunk_size);
+                run_every.update(chunk.len(), max_chunk_size);
+                FORWARDER_RUN_EVERY.set(run_every.current.as_secs_f64());
+                if chunk.len() < max_chunk_size {
+                    // the chunk that was already taken is still stored when shutting down
+                    tokio::select! {
+                        _ = tokio::time::sleep(run_every.current) => {},
+                        _ = shutdown_signal.cancelled() => {},
                     }
-                },
-                _ = shutdown_signal.cancelled() => {}
+                }
+                store_chunk_chunk_size.observe(chunk.len() as f64);
+                if chunk.len() > 0 {
+                    data_storage
+                        .append_messages(chunk, drop_recently_ignored.clone())
+                        .await;
+                }
+
+                if shutdown_signal.is_cancelled() {
+                    break;
+                }
+            }
+
+            // store everything the forward worker passed on until it ended, and wait for the
+            // inserts to complete
+            let drain = async {
+                while let Some(message) = rx.recv().await {
+                    let mut chunk = vec![message];
+                    chunk.extend(take_chunk(&mut rx, max_chunk_size - 1));
+                    data_storage
+                        .append_messages(chunk, drop_recently_ignored.clone())
+                        .await;
+                }
+                data_storage.wait_for_pending_inserts().await;
+            };
+            if tokio::time::timeout(shutdown_drain_timeout, drain)
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    "Not all received messages could be stored within irc.shutdown_drain_timeout ({}), the rest are lost",
+                    humantime::format_duration(shutdown_drain_timeout)
+                );
             }
         });
 
         (forward_worker_join_handle, chunk_worker_join_handle)
     }
 
+    /// Periodically check whether messages are still being received from Twitch IRC, and export
+    /// the result as the `recentmessages_irc_connected` metric.
+    async fn run_connection_monitor(
+        last_message_received: Arc<AtomicI64>,
+        connected: Arc<AtomicBool>,
+        join_status_cache: Arc<Mutex<JoinStatusCache>>,
+        shutdown_signal: CancellationToken,
+    ) {
+        let mut check_interval = tokio::time::interval(Duration::from_secs(5));
+
+        let worker = async move {
+            loop {
+                check_interval.tick().await;
+
+                let millis_since_last_message =
+                    Utc::now().timestamp_millis() - last_message_received.load(Ordering::Relaxed);
+                let is_connected =
+                    millis_since_last_message < IRC_CONNECTION_TIMEOUT.as_millis() as i64;
+                if connected.swap(is_connected, Ordering::Relaxed) != is_connected {
+                    if is_connected {
+                        tracing::info!("Receiving messages from Twitch IRC");
+                    } else {
+                        tracing::warn!(
+                            "No messages received from Twitch IRC for {} seconds, considering it disconnected",
+                            millis_since_last_message / 1000
+                        );
+                        // the channels are rejoined once the connection is back
+                        join_status_cache.lock().unwrap().clear();
+                    }
+                }
+                IRC_CONNECTED.set(is_connected as i64);
+            }
+        };
+
+        tokio::select! {
+            _ = worker => {},
+            _ = shutdown_signal.cancelled() => {}
+        }
+    }
+
     /// Start background loop to vacuum/part channels that are not used.
     pub async fn run_channel_join_parter(
         irc_client: TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
         config: &'static Config,
         data_storage: &'static DataStorage,
+        confirmed_channels: Arc<Mutex<ConfirmedChannels>>,
+        join_status_cache: Arc<Mutex<JoinStatusCache>>,
+        last_roomstates: Arc<Mutex<LastRoomStates>>,
         shutdown_signal: CancellationToken,
     ) {
         let mut check_interval = tokio::time::interval(config.app.vacuum_channels_every);
 
         let worker = async move {
-            loop {
-                check_interval.tick().await;
+            // seed channels that were requested before are joined right away together with the
+            // other channels from the database, only the others have to wait for their turn
+            let requested_channels = data_storage
+                .get_channel_logins_to_join(config.app.channels_expire_after)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::error!("Failed to query the DB for a list of channels that should be joined, all seed channels are joined in batches. Cause: {}", e);
+                    HashSet::new()
+                });
+            let seed_channels =
+                IrcListener::seed_channels(data_storage, &config.app.seed_channels, config).await;
+            let new_seed_channels = seed_channels
+                .difference(&requested_channels)
+                .cloned()
+                .collect::<HashSet<_>>();
+            let pending_seed_channels = Arc::new(Mutex::new(new_seed_channels.clone()));
+            let seed_joiner = IrcListener::join_seed_channels(
+                irc_client.clone(),
+                new_seed_channels,
+                pending_seed_channels.clone(),
+                config,
+            );
 
-                let res = data_storage
-                    .get_channel_logins_to_join(config.app.channels_expire_after)
-                    .await;
-                let channels = match res {
-                    Ok(channels_to_part) => channels_to_part,
-                    Err(e) => {
-                        tracing::error!("Failed to query the DB for a list of channels that should be joined. This iteration will be skipped. Cause: {}", e);
-                        continue;
-                    }
-                };
+            let join_parter = async move {
+                loop {
+                    check_interval.tick().await;
 
-                tracing::info!(
-                    "Checked database for channels that should be joined, now at {} channels",
-                    channels.len()
-                );
-                irc_client.set_wanted_channels(channels).unwrap();
-            }
+                    let res = data_storage
+                        .get_channel_logins_to_join(config.app.channels_expire_after)
+                        .await;
+                    let channels = match res {
+                        Ok(channels_to_part) => channels_to_part,
+                        Err(e) => {
+                            tracing::error!("Failed to query the DB for a list of channels that should be joined. This iteration will be skipped. Cause: {}", e);
+                            continue;
+                        }
+                    };
+                    // new seed channels are only joined once it is their turn, even if they were
+                    // requested since startup
+                    let pending_seed_channels = pending_seed_channels.lock().unwrap().clone();
+                    let channels = channels
+                        .union(&seed_channels)
+                        .filter(|channel_login| !pending_seed_channels.contains(*channel_login))
+                        .cloned()
+                        .collect::<HashSet<_>>();
+
+                    tracing::info!(
+                        "Checked database for channels that should be joined, now at {} channels",
+                        channels.len()
+                    );
+                    confirmed_channels.lock().unwrap().retain_wanted(&channels);
+                    join_status_cache.lock().unwrap().retain_wanted(&channels);
+                    last_roomstates.lock().unwrap().retain_wanted(&channels);
+                    irc_client.set_wanted_channels(channels).unwrap();
+                }
+            };
+
+            futures::join!(seed_joiner, join_parter);
         };
 
         tokio::select! {
@@ -202,16 +520,535 @@ impl IrcListener {
         }
     }
 
+    /// Joins the new seed channels in batches of `app.seed_join_batch_size`, one batch every
+    /// `app.seed_join_batch_every`, removing them from `pending_seed_channels` as they are joined.
+    /// A long list of seed channels would otherwise exceed Twitch's join rate limit at startup.
+    async fn join_seed_channels(
+        irc_client: TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials>,
+        seed_channels: HashSet<String>,
+        pending_seed_channels: Arc<Mutex<HashSet<String>>>,
+        config: &'static Config,
+    ) {
+        // 0 joins all of them at once
+        let batch_size = match config.app.seed_join_batch_size {
+            0 => usize::max(seed_channels.len(), 1),
+            batch_size => batch_size,
+        };
+        let num_seed_channels = seed_channels.len();
+        SEED_CHANNELS_PENDING.set(num_seed_channels as i64);
+
+        let seed_channels = seed_channels.into_iter().sorted().collect_vec();
+        let mut num_joined = 0;
+        for batch in seed_channels.chunks(batch_size) {
+            {
+                let mut pending_seed_channels = pending_seed_channels.lock().unwrap();
+                for channel_login in batch {
+                    pending_seed_channels.remove(channel_login);
+                }
+                SEED_CHANNELS_PENDING.set(pending_seed_channels.len() as i64);
+            }
+            num_joined += batch.len();
+            for channel_login in batch {
+                irc_client.join(channel_login.clone()).unwrap();
+            }
+            tracing::info!(
+                "Joining seed channels: {} of {} joined",
+                num_joined,
+                num_seed_channels
+            );
+
+            if num_joined < num_seed_channels {
+                tokio::time::sleep(config.app.seed_join_batch_every).await;
+            }
+        }
+    }
+
+    /// Adds the valid, not ignored channels of `seed_channels` to the database, and returns them.
+    /// They are joined in addition to the channels from the database, regardless of when they
+    /// were last requested.
+    async fn seed_channels(
+        data_storage: &'static DataStorage,
+        seed_channels: &[String],
+        config: &'static Config,
+    ) -> HashSet<String> {
+        let mut valid_seed_channels = HashSet::new();
+        for channel_login in seed_channels {
+            if let Err(e) = twitch_irc::validate::validate_login(channel_login) {
+                tracing::warn!("Skipping invalid seed channel `{}`: {}", channel_login, e);
+                continue;
+            }
+            match data_storage.get_channel_ignored(channel_login).await {
+                Ok(Some(true)) => {
+                    tracing::info!("Skipping ignored seed channel `{}`", channel_login);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to check whether seed channel `{}` is ignored, skipping it: {}",
+                        channel_login,
+                        e
+                    );
+                    continue;
+                }
+            }
+            if let Err(e) = data_storage
+                .touch_or_add_channel(channel_login, config.app.channel_touch_debounce)
+                .await
+            {
+                tracing::error!("Failed to add seed channel `{}`: {}", channel_login, e);
+            }
+            valid_seed_channels.insert(channel_login.clone());
+        }
+
+        if !seed_channels.is_empty() {
+            tracing::info!("Joining {} seed channels", valid_seed_channels.len());
+        }
+        valid_seed_channels
+    }
+
     pub fn join_if_needed(&self, channel_login: String) {
         // the twitch_irc crate only does a JOIN if necessary
         self.irc_client.join(channel_login).unwrap();
     }
 
+    /// Parts a channel that was just ignored. Messages of the channel that are still received
+    /// until the PART reaches Twitch, or that are waiting to be stored, are dropped for
+    /// `irc.recently_ignored_for`.
+    pub fn part_ignored(&self, channel_login: String) {
+        self.recently_ignored
+            .lock()
+            .unwrap()
+            .insert(channel_login.clone(), Instant::now());
+        self.part(channel_login);
+    }
+
+    /// Joins a channel that is not ignored anymore, so its messages are stored again right away.
+    pub fn join_unignored(&self, channel_login: String) {
+        self.stop_dropping_messages(&channel_login);
+        self.join_if_needed(channel_login);
+    }
+
+    /// Stores the messages of a channel passed to `part_ignored` again, for channels that were
+    /// only parted and not ignored.
+    pub fn stop_dropping_messages(&self, channel_login: &str) {
+        self.recently_ignored.lock().unwrap().remove(channel_login);
+    }
+
     pub async fn is_join_confirmed(&self, channel_login: String) -> bool {
-        self.irc_client.get_channel_status(channel_login).await == (true, true)
+        self.get_channel_status(channel_login).await == (true, true)
+    }
+
+    /// Parts the channel, use this instead of parting with `irc_client` directly so the join
+    /// status isn't cached anymore.
+    pub fn part(&self, channel_login: String) {
+        self.join_status_cache
+            .lock()
+            .unwrap()
+            .invalidate(&channel_login);
+        self.irc_client.part(channel_login);
+    }
+
+    /// Wait up to `timeout` for the join to the given channel to be confirmed. Returns whether the
+    /// join was confirmed.
+    pub async fn wait_for_join_confirmed(&self, channel_login: String, timeout: Duration) -> bool {
+        self.wait_for_join_confirmed_every(channel_login, timeout, Duration::from_millis(100))
+            .await
+    }
+
+    /// Like [`IrcListener::wait_for_join_confirmed`], checking every `recheck_every`.
+    pub async fn wait_for_join_confirmed_every(
+        &self,
+        channel_login: String,
+        timeout: Duration,
+        recheck_every: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.is_join_confirmed(channel_login.clone()).await {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::min(deadline - now, recheck_every)).await;
+        }
+    }
+
+    /// Like [`IrcListener::get_channel_status`], but tells apart channels that are only not joined
+    /// because they are being rejoined after a reconnect, from channels that have not been joined
+    /// before (or whose join failed).
+    pub async fn join_status(&self, channel_login: String) -> (bool, JoinStatus) {
+        let (wanted, joined) = self.get_channel_status(channel_login.clone()).await;
+        let join_status =
+            self.confirmed_channels
+                .lock()
+                .unwrap()
+                .join_status(&channel_login, wanted, joined);
+        (wanted, join_status)
+    }
+
+    /// How long ago the join to the channel was first confirmed, `None` if it hasn't been since
+    /// the channel became wanted. Reconnects don't reset this, a forced rejoin does.
+    pub fn joined_for(&self, channel_login: &str) -> Option<Duration> {
+        self.confirmed_channels
+            .lock()
+            .unwrap()
+            .joined_for(channel_login)
+    }
+
+    /// Parts the channel and joins it again shortly after, then waits up to `timeout` for the join
+    /// to be confirmed. Returns whether it was.
+    pub async fn force_rejoin(&self, channel_login: String, timeout: Duration) -> bool {
+        tracing::info!("Forcing a rejoin of channel {}", channel_login);
+        self.confirmed_channels
+            .lock()
+            .unwrap()
+            .forget(&channel_login);
+        self.part(channel_login.clone());
+        // give the PART time to be sent before the JOIN, on the same connection
+        tokio::time::sleep(FORCE_REJOIN_DELAY).await;
+        self.join_if_needed(channel_login.clone());
+        self.wait_for_join_confirmed(channel_login, timeout).await
+    }
+
+    /// Whether messages are currently being received from Twitch IRC.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// The channels that recently failed to be joined, and why.
+    pub fn join_failures(&self) -> Vec<(String, JoinFailure)> {
+        let mut join_failures = self.join_failures.lock().unwrap();
+        join_failures.prune(Utc::now());
+        join_failures
+            .failures
+            .iter()
+            .map(|(channel_login, failure)| (channel_login.clone(), failure.clone()))
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .collect()
+    }
+
+    /// Returns `(wanted, joined)`: whether the channel is in the set of channels the client wants
+    /// to be joined to, and whether the join has been confirmed by the server. Confirmed joins are
+    /// cached for `irc.join_status_cache_ttl`.
+    pub async fn get_channel_status(&self, channel_login: String) -> (bool, bool) {
+        if self
+            .join_status_cache
+            .lock()
+            .unwrap()
+            .is_joined(&channel_login, Instant::now())
+        {
+            return (true, true);
+        }
+        let status = self
+            .irc_client
+            .get_channel_status(channel_login.clone())
+            .await;
+        if status == (true, true) {
+            self.join_status_cache
+                .lock()
+                .unwrap()
+                .insert(channel_login, Instant::now());
+        }
+        status
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct JoinFailure {
+    /// The `msg-id` of the `NOTICE` Twitch sent instead of confirming the join
+    pub reason: String,
+    pub time: DateTime<Utc>,
+}
+
+/// The most recent join failure of each channel that has not been joined successfully since.
+#[derive(Debug, Default)]
+struct JoinFailures {
+    failures: HashMap<String, JoinFailure>,
+}
+
+impl JoinFailures {
+    fn record(&mut self, channel_login: &str, reason: &str, now: DateTime<Utc>) {
+        self.prune(now);
+        self.failures.insert(
+            channel_login.to_owned(),
+            JoinFailure {
+                reason: reason.to_owned(),
+                time: now,
+            },
+        );
+    }
+
+    fn clear(&mut self, channel_login: &str) {
+        self.failures.remove(channel_login);
+    }
+
+    /// Forget failures older than `JOIN_FAILURE_RETENTION`, so channels that are no longer
+    /// joined don't stay in here forever.
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - chrono::Duration::from_std(JOIN_FAILURE_RETENTION).unwrap();
+        self.failures.retain(|_, failure| failure.time > cutoff);
+    }
+}
+
+/// The wanted channels whose join has been confirmed at least once since they became wanted, and
+/// when that first happened. If such a channel is not joined, it is only being rejoined after a
+/// reconnect.
+#[derive(Debug, Default)]
+struct ConfirmedChannels {
+    channels: HashMap<String, Instant>,
+}
+
+impl ConfirmedChannels {
+    fn confirm(&mut self, channel_login: &str) {
+        if !self.channels.contains_key(channel_login) {
+            self.channels
+                .insert(channel_login.to_owned(), Instant::now());
+        }
+    }
+
+    /// Time since the join was first confirmed, not reset by reconnects.
+    fn joined_for(&self, channel_login: &str) -> Option<Duration> {
+        self.channels
+            .get(channel_login)
+            .map(|first_confirmed| first_confirmed.elapsed())
+    }
+
+    fn forget(&mut self, channel_login: &str) {
+        self.channels.remove(channel_login);
+    }
+
+    /// Forget channels that are about to be parted, so they count as new if they are joined again
+    /// later.
+    fn retain_wanted(&mut self, wanted_channels: &HashSet<String>) {
+        self.channels
+            .retain(|channel_login, _| wanted_channels.contains(channel_login));
+    }
+
+    fn join_status(&self, channel_login: &str, wanted: bool, joined: bool) -> JoinStatus {
+        match (wanted, joined) {
+            (true, true) => JoinStatus::Joined,
+            (true, false) if self.channels.contains_key(channel_login) => JoinStatus::Reconnecting,
+            _ => JoinStatus::NotJoined,
+        }
+    }
+}
+
+/// Channels whose join was recently confirmed by the `twitch_irc` client, so requests for busy
+/// channels don't each have to query the client. Only confirmed joins are cached, so channels that
+/// are still being joined are seen as joined as soon as possible. Entries are removed when the
+/// channel is parted, its join fails or the connection to Twitch is lost.
+#[derive(Debug)]
+struct JoinStatusCache {
+    ttl: Duration,
+    joined: HashMap<String, Instant>,
+}
+
+impl JoinStatusCache {
+    fn new(ttl: Duration) -> JoinStatusCache {
+        JoinStatusCache {
+            ttl,
+            joined: HashMap::new(),
+        }
+    }
+
+    fn is_joined(&mut self, channel_login: &str, now: Instant) -> bool {
+        match self.joined.get(channel_login) {
+            Some(cached_at) if now.duration_since(*cached_at) < self.ttl => true,
+            Some(_) => {
+                self.joined.remove(channel_login);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, channel_login: String, now: Instant) {
+        if !self.ttl.is_zero() {
+            self.joined.insert(channel_login, now);
+        }
+    }
+
+    fn invalidate(&mut self, channel_login: &str) {
+        self.joined.remove(channel_login);
+    }
+
+    fn clear(&mut self) {
+        self.joined.clear();
+    }
+
+    /// Forget channels that are about to be parted, and expired entries of channels that weren't
+    /// requested since.
+    fn retain_wanted(&mut self, wanted_channels: &HashSet<String>) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.joined.retain(|channel_login, cached_at| {
+            wanted_channels.contains(channel_login) && now.duration_since(*cached_at) < ttl
+        });
+    }
+}
+
+/// Channels that were ignored recently. Messages of these channels are not stored anymore, even
+/// if they were received before the PART reached Twitch.
+#[derive(Debug)]
+struct RecentlyIgnored {
+    keep_for: Duration,
+    ignored_at: HashMap<String, Instant>,
+}
+
+impl RecentlyIgnored {
+    fn new(keep_for: Duration) -> RecentlyIgnored {
+        RecentlyIgnored {
+            keep_for,
+            ignored_at: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, channel_login: String, now: Instant) {
+        let keep_for = self.keep_for;
+        self.ignored_at
+            .retain(|_, ignored_at| now.duration_since(*ignored_at) < keep_for);
+        self.ignored_at.insert(channel_login, now);
+    }
+
+    fn remove(&mut self, channel_login: &str) {
+        self.ignored_at.remove(channel_login);
+    }
+
+    fn contains(&mut self, channel_login: &str, now: Instant) -> bool {
+        match self.ignored_at.get(channel_login) {
+            Some(ignored_at) if now.duration_since(*ignored_at) < self.keep_for => true,
+            Some(_) => {
+                self.ignored_at.remove(channel_login);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the messages of recently ignored channels from the chunk.
+    fn drop_ignored(&mut self, mut chunk: Vec<MessageToStore>) -> Vec<MessageToStore> {
+        if self.ignored_at.is_empty() {
+            return chunk;
+        }
+        let now = Instant::now();
+        let len_before = chunk.len();
+        chunk.retain(|message| !self.contains(&message.channel_login, now));
+        RECENTLY_IGNORED_DROPPED.inc_by((len_before - chunk.len()) as u64);
+        chunk
+    }
+}
+
+/// The tags of the last stored `ROOMSTATE` of each channel, to skip storing `ROOMSTATE`s that
+/// don't change anything.
+#[derive(Debug, Default)]
+struct LastRoomStates {
+    tags: HashMap<String, IRCTags>,
+}
+
+impl LastRoomStates {
+    /// Whether a `ROOMSTATE` with the given tags differs from the last one of the channel, and
+    /// should therefore be stored. Remembers the tags if so. The first `ROOMSTATE` of every channel
+    /// is always stored.
+    fn is_changed(&mut self, channel_login: &str, tags: &IRCTags) -> bool {
+        if self.tags.get(channel_login) == Some(tags) {
+            return false;
+        }
+        self.tags.insert(channel_login.to_owned(), tags.clone());
+        true
+    }
+
+    fn forget(&mut self, channel_login: &str) {
+        self.tags.remove(channel_login);
+    }
+
+    /// Forget channels that are about to be parted.
+    fn retain_wanted(&mut self, wanted_channels: &HashSet<String>) {
+        self.tags
+            .retain(|channel_login, _| wanted_channels.contains(channel_login));
+    }
+}
+
+/// Sender login, display name and color of chat messages (`PRIVMSG` and `USERNOTICE`), to be
+/// stored alongside the message.
+pub fn sender_columns(message: &ServerMessage) -> (Option<String>, Option<String>, Option<String>) {
+    let sender = match message {
+        ServerMessage::Privmsg(msg) => &msg.sender,
+        ServerMessage::UserNotice(msg) => &msg.sender,
+        _ => return (None, None, None),
+    };
+    // the raw tag, Twitch sends an empty value if the user has never set a color
+    let color = message
+        .source()
+        .tags
+        .0
+        .get("color")
+        .cloned()
+        .flatten()
+        .filter(|color| !color.is_empty());
+    (Some(sender.login.clone()), Some(sender.name.clone()), color)
+}
+
+/// Time between forwarder runs, which is either fixed or adapts to how full the chunks are.
+#[derive(Debug)]
+struct ForwarderInterval {
+    current: Duration,
+    /// `None` if the interval is fixed
+    bounds: Option<(Duration, Duration)>,
+}
+
+impl ForwarderInterval {
+    fn new(config: &IrcConfig) -> ForwarderInterval {
+        ForwarderInterval {
+            current: config.forwarder_run_every,
+            bounds: config.adaptive_forwarder.then_some((
+                config.forwarder_min_run_every,
+                config.forwarder_max_run_every,
+            )),
+        }
+    }
+
+    /// Run more often while chunks fill up quickly, and less often while there is nothing to do.
+    fn update(&mut self, chunk_size: usize, max_chunk_size: usize) {
+        let (min, max) = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        if chunk_size * 2 >= max_chunk_size {
+            self.current = Duration::max(self.current / 2, min);
+        } else if chunk_size == 0 {
+            self.current = Duration::min(self.current * 2, max);
+        }
+    }
+}
+
+/// Take up to `max_chunk_size` messages that are immediately available from `rx`.
+fn take_chunk<T>(rx: &mut mpsc::UnboundedReceiver<T>, max_chunk_size: usize) -> Vec<T> {
+    let mut chunk = Vec::with_capacity(max_chunk_size);
+    while chunk.len() < max_chunk_size {
+        match rx.try_recv() {
+            Ok(message) => chunk.push(message),
+            Err(_) => break,
+        }
+    }
+    chunk
+}
+
+/// Histogram buckets for the chunk size metric, from 1 up to `max_chunk_size`.
+fn chunk_size_buckets(max_chunk_size: usize) -> Vec<f64> {
+    let smallest_bucket = 1f64;
+    // exponential_buckets() requires a factor greater than 1
+    let largest_bucket = f64::max(max_chunk_size as f64, 2f64);
+    let num_buckets = 100usize;
+    // math :) this formula is the result of "solve s*x^b = l for x"
+    // where s=smallest_bucket, x=factor, b=num_buckets, l=largest_bucket
+    let factor = (largest_bucket / smallest_bucket).powf(1f64 / (num_buckets as f64));
+
+    exponential_buckets(smallest_bucket, factor, num_buckets).unwrap()
+}
+
 trait ServerMessageExt {
     /// Get the channel login if this message was sent to a channel.
     fn channel_login(&self) -> Option<&str>;
@@ -233,3 +1070,228 @@ impl ServerMessageExt for ServerMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        chunk_size_buckets, take_chunk, ConfirmedChannels, ForwarderInterval, JoinFailures,
+        JoinStatus, JoinStatusCache, LastRoomStates, RecentlyIgnored,
+    };
+    use crate::config::IrcConfig;
+    use crate::db::MessageToStore;
+    use chrono::Utc;
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use twitch_irc::message::IRCTags;
+
+    fn tags(pairs: &[(&str, &str)]) -> IRCTags {
+        let mut tags = IRCTags::new();
+        for (key, value) in pairs {
+            tags.0.insert((*key).to_owned(), Some((*value).to_owned()));
+        }
+        tags
+    }
+
+    #[test]
+    fn only_changed_roomstates_are_stored() {
+        let mut last_roomstates = LastRoomStates::default();
+        let initial = tags(&[("room-id", "11148817"), ("slow", "0"), ("subs-only", "0")]);
+        let slow = tags(&[("room-id", "11148817"), ("slow", "10")]);
+
+        assert!(last_roomstates.is_changed("pajlada", &initial));
+        assert!(!last_roomstates.is_changed("pajlada", &initial));
+        assert!(last_roomstates.is_changed("pajlada", &slow));
+        assert!(!last_roomstates.is_changed("pajlada", &slow));
+        assert!(last_roomstates.is_changed("pajlada", &initial));
+        // tracked separately per channel
+        assert!(last_roomstates.is_changed("forsen", &initial));
+
+        // the first ROOMSTATE after a rejoin is stored again
+        last_roomstates.forget("pajlada");
+        assert!(last_roomstates.is_changed("pajlada", &initial));
+        last_roomstates.retain_wanted(&HashSet::from(["pajlada".to_owned()]));
+        assert!(!last_roomstates.is_changed("pajlada", &initial));
+        assert!(last_roomstates.is_changed("forsen", &initial));
+    }
+
+    #[test]
+    fn join_failures_are_cleared_and_expire() {
+        let start = chrono::Utc::now();
+        let mut join_failures = JoinFailures::default();
+        join_failures.record("pajlada", "msg_channel_suspended", start);
+        join_failures.record("forsen", "msg_channel_suspended", start);
+        assert_eq!(
+            join_failures.failures["pajlada"].reason,
+            "msg_channel_suspended"
+        );
+
+        join_failures.clear("pajlada");
+        assert!(!join_failures.failures.contains_key("pajlada"));
+
+        join_failures.prune(start + chrono::Duration::minutes(59));
+        assert!(join_failures.failures.contains_key("forsen"));
+        join_failures.prune(start + chrono::Duration::minutes(61));
+        assert!(join_failures.failures.is_empty());
+    }
+
+    #[test]
+    fn previously_confirmed_channels_are_reconnecting() {
+        let mut confirmed_channels = ConfirmedChannels::default();
+        assert_eq!(
+            confirmed_channels.join_status("pajlada", true, false),
+            JoinStatus::NotJoined
+        );
+        assert_eq!(confirmed_channels.joined_for("pajlada"), None);
+        confirmed_channels.confirm("pajlada");
+        assert_eq!(
+            confirmed_channels.join_status("pajlada", true, true),
+            JoinStatus::Joined
+        );
+        let joined_for = confirmed_channels.joined_for("pajlada").unwrap();
+        // confirming again (e.g. after a reconnect) doesn't reset the time
+        confirmed_channels.confirm("pajlada");
+        assert!(confirmed_channels.joined_for("pajlada").unwrap() >= joined_for);
+        assert_eq!(
+            confirmed_channels.join_status("pajlada", true, false),
+            JoinStatus::Reconnecting
+        );
+
+        // no longer wanted, a later join starts from scratch
+        confirmed_channels.retain_wanted(&HashSet::from(["forsen".to_owned()]));
+        assert_eq!(
+            confirmed_channels.join_status("pajlada", true, false),
+            JoinStatus::NotJoined
+        );
+
+        confirmed_channels.confirm("forsen");
+        confirmed_channels.forget("forsen");
+        assert_eq!(
+            confirmed_channels.join_status("forsen", true, false),
+            JoinStatus::NotJoined
+        );
+    }
+
+    #[test]
+    fn messages_of_recently_ignored_channels_are_dropped() {
+        let start = tokio::time::Instant::now();
+        let mut recently_ignored = RecentlyIgnored::new(Duration::from_secs(10));
+        assert!(!recently_ignored.contains("pajlada", start));
+        recently_ignored.insert("pajlada".to_owned(), start);
+        assert!(recently_ignored.contains("pajlada", start + Duration::from_secs(9)));
+        assert!(!recently_ignored.contains("pajlada", start + Duration::from_secs(10)));
+
+        // un-ignoring a channel stores its messages again right away
+        recently_ignored.insert("forsen".to_owned(), start);
+        recently_ignored.remove("forsen");
+        assert!(!recently_ignored.contains("forsen", start));
+
+        recently_ignored.insert("pajlada".to_owned(), tokio::time::Instant::now());
+        let message = |channel_login: &str| MessageToStore {
+            channel_login: channel_login.to_owned(),
+            time_received: Utc::now(),
+            message_source: format!(":tmi.twitch.tv ROOMSTATE #{}", channel_login),
+            twitch_message_id: None,
+            sender_login: None,
+            sender_display_name: None,
+            color: None,
+        };
+        let chunk = recently_ignored.drop_ignored(vec![
+            message("pajlada"),
+            message("forsen"),
+            message("pajlada"),
+        ]);
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].channel_login, "forsen");
+    }
+
+    #[test]
+    fn confirmed_joins_are_cached_until_the_ttl_passes() {
+        let start = tokio::time::Instant::now();
+        let mut cache = JoinStatusCache::new(Duration::from_secs(10));
+        assert!(!cache.is_joined("pajlada", start));
+        cache.insert("pajlada".to_owned(), start);
+        assert!(cache.is_joined("pajlada", start + Duration::from_secs(9)));
+        assert!(!cache.is_joined("pajlada", start + Duration::from_secs(10)));
+
+        cache.insert("forsen".to_owned(), start);
+        cache.invalidate("forsen");
+        assert!(!cache.is_joined("forsen", start));
+
+        // a TTL of 0 disables the cache
+        let mut cache = JoinStatusCache::new(Duration::ZERO);
+        cache.insert("pajlada".to_owned(), start);
+        assert!(!cache.is_joined("pajlada", start));
+    }
+
+    #[test]
+    fn stored_message_types_default_to_exported_types() {
+        let config: IrcConfig = toml::from_str("").unwrap();
+        assert!(config.stored_message_types.contains("PRIVMSG"));
+        assert!(config.stored_message_types.contains("ROOMSTATE"));
+        assert!(!config.stored_message_types.contains("JOIN"));
+        assert!(!config.stored_message_types.contains("USERSTATE"));
+
+        let config: IrcConfig = toml::from_str(r#"stored_message_types = ["PRIVMSG"]"#).unwrap();
+        assert_eq!(config.stored_message_types.len(), 1);
+    }
+
+    #[test]
+    fn chunks_respect_configured_max_chunk_size() {
+        let config: IrcConfig = toml::from_str("forwarder_max_chunk_size = 10").unwrap();
+        assert_eq!(config.forwarder_max_chunk_size, 10);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for i in 0..25 {
+            tx.send(i).unwrap();
+        }
+
+        let chunk_sizes = (0..4)
+            .map(|_| take_chunk(&mut rx, config.forwarder_max_chunk_size).len())
+            .collect::<Vec<_>>();
+        assert_eq!(chunk_sizes, vec![10, 10, 5, 0]);
+    }
+
+    #[test]
+    fn adaptive_forwarder_interval_stays_within_bounds() {
+        let config: IrcConfig = toml::from_str(
+            r#"
+            adaptive_forwarder = true
+            forwarder_run_every = "100ms"
+            forwarder_min_run_every = "30ms"
+            forwarder_max_run_every = "300ms"
+            "#,
+        )
+        .unwrap();
+        let mut interval = ForwarderInterval::new(&config);
+
+        interval.update(50, 100);
+        assert_eq!(interval.current, Duration::from_millis(50));
+        interval.update(100, 100);
+        assert_eq!(interval.current, Duration::from_millis(30));
+        // partially filled chunks keep the interval
+        interval.update(10, 100);
+        assert_eq!(interval.current, Duration::from_millis(30));
+        for _ in 0..5 {
+            interval.update(0, 100);
+        }
+        assert_eq!(interval.current, Duration::from_millis(300));
+
+        let config: IrcConfig = toml::from_str(r#"forwarder_run_every = "100ms""#).unwrap();
+        let mut interval = ForwarderInterval::new(&config);
+        interval.update(100, 100);
+        interval.update(0, 100);
+        assert_eq!(interval.current, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn chunk_size_buckets_end_at_max_chunk_size() {
+        let buckets = chunk_size_buckets(256);
+        assert_eq!(buckets.len(), 100);
+        assert_eq!(buckets[0], 1.0);
+        assert!((buckets[99] * (256f64).powf(0.01) - 256.0).abs() < 0.001);
+
+        // must not panic for tiny values
+        chunk_size_buckets(1);
+    }
+}
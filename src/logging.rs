@@ -0,0 +1,41 @@
+//! Log output to stdout, with a filter that can be changed while the service is running.
+
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, ParseError};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// Changes the filter of the subscriber installed by [`init`].
+#[derive(Debug, Clone)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+/// Installs the global subscriber, filtered by `RUST_LOG` (`info` if not set).
+pub fn init() -> LogFilterHandle {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .init();
+    LogFilterHandle(handle)
+}
+
+impl LogFilterHandle {
+    pub fn current(&self) -> String {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .expect("subscriber is installed globally")
+    }
+
+    /// Replaces the filter with `directives` (same syntax as `RUST_LOG`, e.g.
+    /// `info,recent_messages2::web::auth=debug`), returning the previous filter.
+    pub fn set(&self, directives: &str) -> Result<String, ParseError> {
+        let filter = EnvFilter::builder().parse(directives)?;
+        let mut previous = String::new();
+        self.0
+            .modify(|current| previous = std::mem::replace(current, filter).to_string())
+            .expect("subscriber is installed globally");
+        Ok(previous)
+    }
+}
@@ -2,15 +2,20 @@
 #![deny(clippy::all)]
 #![deny(clippy::cargo)]
 
+mod backup;
 mod config;
 mod db;
+mod import;
 mod irc_listener;
+mod logging;
 mod message_export;
+mod message_rate;
 mod monitoring;
+mod sharding;
 mod shutdown;
 mod web;
 
-use crate::config::{Args, Config};
+use crate::config::{Args, Command, Config};
 use crate::db::DataStorage;
 use futures::future::FusedFuture;
 use futures::prelude::*;
@@ -20,7 +25,7 @@ use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let log_filter: &'static logging::LogFilterHandle = Box::leak(Box::new(logging::init()));
 
     // args and config parsing
     let args = Args::from_args();
@@ -51,7 +56,9 @@ async fn main() {
 
     // db init
     let data_storage = Box::leak(Box::new(db::connect_to_postgresql(&config)));
-    let migrations_result = data_storage.run_migrations().await;
+    let migrations_result = data_storage
+        .run_migrations(config.app.allow_unavailable_shards)
+        .await;
     match migrations_result {
         Ok(()) => {
             tracing::info!("Successfully ran database migrations");
@@ -61,7 +68,22 @@ async fn main() {
             std::process::exit(1);
         }
     }
-    if let Err(e) = data_storage.fetch_initial_metrics_values().await {
+    if let Some(Command::Import { file }) = &args.command {
+        run_import(data_storage, config, file).await;
+        return;
+    }
+    if let Some(Command::Backup { file }) = &args.command {
+        run_backup(data_storage, file).await;
+        return;
+    }
+    if let Some(Command::Rebalance) = &args.command {
+        run_rebalance(data_storage).await;
+        return;
+    }
+    if let Err(e) = data_storage
+        .fetch_initial_metrics_values(config.app.estimate_initial_message_counts)
+        .await
+    {
         tracing::error!("Failed to query some initial message count from the DB to initialize exported metrics: {}", e);
         std::process::exit(1);
     }
@@ -71,20 +93,34 @@ async fn main() {
         forward_worker_join_handle,
         chunk_worker_join_handle,
         channel_jp_join_handle,
+        message_rate_join_handle,
+        connection_monitor_join_handle,
     ) = irc_listener::IrcListener::start(data_storage, config, shutdown_signal.clone());
     let irc_listener = Box::leak(Box::new(irc_listener));
 
     let old_msg_vacuum_join_handle =
         tokio::spawn(data_storage.run_task_vacuum_old_messages(config, shutdown_signal.clone()));
+    let retry_shards_join_handle = tokio::spawn(
+        data_storage.run_task_retry_unavailable_shards(config, shutdown_signal.clone()),
+    );
+    let partition_metrics_join_handle =
+        tokio::spawn(data_storage.run_task_partition_metrics(config, shutdown_signal.clone()));
 
-    let webserver =
-        match web::run(data_storage, irc_listener, config, shutdown_signal.clone()).await {
-            Ok(webserver) => webserver,
-            Err(bind_error) => {
-                tracing::error!("{}", bind_error);
-                std::process::exit(1);
-            }
-        };
+    let webserver = match web::run(
+        data_storage,
+        irc_listener,
+        config,
+        log_filter,
+        shutdown_signal.clone(),
+    )
+    .await
+    {
+        Ok(webserver) => webserver,
+        Err(bind_error) => {
+            tracing::error!("{}", bind_error);
+            std::process::exit(1);
+        }
+    };
     let webserver_join_handle = tokio::spawn(webserver);
 
     // await termination.
@@ -92,21 +128,37 @@ async fn main() {
     futures::pin_mut!(os_shutdown_signal);
 
     let with_name = move |fut: JoinHandle<()>, name| fut.map(move |x| (x, name));
-    let mut simple_workers = [
-        with_name(process_monitoring_join_handle, "Process Monitoring task").fuse(),
-        with_name(
+    let simple_worker_handles = [
+        (process_monitoring_join_handle, "Process Monitoring task"),
+        (
             forward_worker_join_handle,
             "IRC message forwarder (preprocessor)",
-        )
-        .fuse(),
-        with_name(
+        ),
+        (
             chunk_worker_join_handle,
             "IRC message-to-database-forwarder",
-        )
-        .fuse(),
-        with_name(channel_jp_join_handle, "IRC channel join/part task").fuse(),
-        with_name(old_msg_vacuum_join_handle, "Old message vacuum task").fuse(),
+        ),
+        (channel_jp_join_handle, "IRC channel join/part task"),
+        (message_rate_join_handle, "Channel message rate exporter"),
+        (connection_monitor_join_handle, "IRC connection monitor"),
+        (old_msg_vacuum_join_handle, "Old message vacuum task"),
+        (retry_shards_join_handle, "Unavailable shard retry task"),
+        (partition_metrics_join_handle, "Partition metrics task"),
     ];
+    let simple_worker_names = simple_worker_handles.each_ref().map(|(_, name)| *name);
+    let mut simple_workers = simple_worker_handles.map(|(fut, name)| with_name(fut, name).fuse());
+
+    // starts counting down once a shutdown is requested, for whatever reason
+    let shutdown_timeout = config.app.shutdown_timeout;
+    let shutdown_deadline = {
+        let shutdown_signal = shutdown_signal.clone();
+        async move {
+            shutdown_signal.cancelled().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        }
+    }
+    .fuse();
+    futures::pin_mut!(shutdown_deadline);
 
     let mut webserver_join_handle = webserver_join_handle.fuse();
     let mut exit_code: i32 = 0;
@@ -178,12 +230,89 @@ async fn main() {
                     }
                 }
             }
+            _ = &mut shutdown_deadline => {
+                let mut still_running = simple_worker_names
+                    .iter()
+                    .zip(simple_workers.iter())
+                    .filter(|(_, fut)| !fut.is_terminated())
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>();
+                if !webserver_join_handle.is_terminated() {
+                    still_running.push("Webserver");
+                }
+                tracing::error!(
+                    "Shutdown did not complete within app.shutdown_timeout ({}), exiting anyway. Still running: {}",
+                    humantime::format_duration(shutdown_timeout),
+                    still_running.join(", ")
+                );
+                exit_code = 1;
+                break;
+            }
         }
     }
 
     std::process::exit(exit_code);
 }
 
+async fn run_import(data_storage: &DataStorage, config: &Config, file: &std::path::Path) {
+    tracing::info!("Importing messages from `{}`", file.display());
+    match import::import_csv(data_storage, config, file).await {
+        Ok(summary) => {
+            tracing::info!(
+                "Import done: {} rows, {} invalid rows skipped, {} messages inserted (the rest were already stored)",
+                summary.rows,
+                summary.invalid_rows,
+                summary.messages_inserted
+            );
+            if summary.invalid_rows > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            tracing::error!("Import failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_backup(data_storage: &DataStorage, file: &std::path::Path) {
+    tracing::info!("Backing up messages to `{}`", file.display());
+    match backup::backup_gzip_csv(data_storage, file).await {
+        Ok(summary) => {
+            tracing::info!(
+                "Backup done: {} messages from {} databases, {} unavailable databases skipped",
+                summary.messages,
+                summary.databases,
+                summary.unavailable_databases
+            );
+            if summary.unavailable_databases > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            tracing::error!("Backup failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_rebalance(data_storage: &DataStorage) {
+    tracing::info!("Moving messages of channels stored on the wrong database");
+    match data_storage.rebalance().await {
+        Ok(summary) => {
+            tracing::info!(
+                "Rebalance done: {} messages of {} channels moved",
+                summary.messages_moved,
+                summary.channels_moved
+            );
+        }
+        Err(e) => {
+            tracing::error!("Rebalance failed (run it again to resume): {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 #[cfg(unix)]
 fn increase_nofile_rlimit() {
     use rlimit::Resource;
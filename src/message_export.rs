@@ -1,9 +1,12 @@
 use crate::db::StoredMessage;
-use crate::web::get_recent_messages::GetRecentMessagesQueryOptions;
-use chrono::{DateTime, Utc};
+use crate::web::get_recent_messages::{GetRecentMessagesQueryOptions, TimestampFormat};
+use chrono::{DateTime, SecondsFormat, Utc};
 use humantime::format_duration;
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter_vec, Histogram, IntCounterVec,
+};
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use twitch_irc::message::{
@@ -26,8 +29,15 @@ struct ContainerFrame {
 }
 
 impl ContainerFrame {
-    fn export(self, options: &GetRecentMessagesQueryOptions) -> Option<String> {
+    fn export(
+        self,
+        options: &GetRecentMessagesQueryOptions,
+        batch_ref: Option<&str>,
+    ) -> Option<String> {
         if options.hide_moderated_messages && self.deleted_by_moderation {
+            EXPORT_FILTERED_MESSAGES
+                .with_label_values(&["hide_moderated_messages"])
+                .inc();
             return None;
         }
 
@@ -37,6 +47,22 @@ impl ContainerFrame {
                 ServerMessage::ClearChat(_) | ServerMessage::ClearMsg(_)
             )
         {
+            EXPORT_FILTERED_MESSAGES
+                .with_label_values(&["hide_moderation_messages"])
+                .inc();
+            return None;
+        }
+
+        if options.hide_room_mode_notices
+            && matches!(
+                &self.original_message,
+                ServerMessage::Notice(NoticeMessage { message_id: Some(message_id), .. })
+                    if ROOM_MODE_NOTICE_IDS.contains(&message_id.as_str())
+            )
+        {
+            EXPORT_FILTERED_MESSAGES
+                .with_label_values(&["hide_room_mode_notices"])
+                .inc();
             return None;
         }
 
@@ -95,6 +121,24 @@ impl ContainerFrame {
             "rm-received-ts".to_owned(),
             Some(self.time_received.timestamp_millis().to_string()),
         );
+        // Add rm-received-time=<RFC 3339 timestamp> if requested
+        if options.timestamp_format == TimestampFormat::Rfc3339 {
+            message_to_export.tags.0.insert(
+                "rm-received-time".to_owned(),
+                Some(
+                    self.time_received
+                        .to_rfc3339_opts(SecondsFormat::Millis, true),
+                ),
+            );
+        }
+
+        // Add batch=<ref> if the messages are exported as a batch
+        if let Some(batch_ref) = batch_ref {
+            message_to_export
+                .tags
+                .0
+                .insert("batch".to_owned(), Some(batch_ref.to_owned()));
+        }
 
         // Add rm-deleted=1 if needed
         if self.deleted_by_moderation {
@@ -104,7 +148,9 @@ impl ContainerFrame {
                 .insert("rm-deleted".to_owned(), Some("1".to_owned()));
         }
 
-        Some(message_to_export.as_raw_irc())
+        let exported = message_to_export.as_raw_irc();
+        EXPORTED_MESSAGE_BYTES.observe(exported.len() as f64);
+        Some(exported)
     }
 }
 
@@ -125,6 +171,36 @@ lazy_static! {
     .iter()
     .cloned()
     .collect();
+    /// Announcements of room mode changes, omitted with `hide_room_mode_notices`
+    static ref ROOM_MODE_NOTICE_IDS: HashSet<&'static str> = [
+        "emote_only_on",
+        "emote_only_off",
+        "followers_on",
+        "followers_on_zero",
+        "followers_off",
+        "slow_on",
+        "slow_off",
+        "subs_on",
+        "subs_off",
+        "r9k_on",
+        "r9k_off"
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    static ref EXPORTED_MESSAGE_BYTES: Histogram = register_histogram!(
+        "recentmessages_exported_message_bytes",
+        "Size of the individual exported IRC messages, including the tags added on export",
+        // 32 bytes to 16 KiB
+        exponential_buckets(32.0, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref EXPORT_FILTERED_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "recentmessages_export_filtered_messages",
+        "Number of stored messages that were not exported, by the reason they were filtered out",
+        &["reason"]
+    )
+    .unwrap();
 }
 
 impl MessageContainer {
@@ -143,6 +219,9 @@ impl MessageContainer {
                 | ServerMessage::Notice(_)
                 | ServerMessage::RoomState(_)
         ) {
+            EXPORT_FILTERED_MESSAGES
+                .with_label_values(&["unexported_message_type"])
+                .inc();
             return;
         }
 
@@ -182,6 +261,9 @@ impl MessageContainer {
             }) => {
                 // Don't export ignored NOTICE types
                 if IGNORED_NOTICE_IDS.contains(&message_id.as_str()) {
+                    EXPORT_FILTERED_MESSAGES
+                        .with_label_values(&["ignored_notice"])
+                        .inc();
                     return;
                 }
             }
@@ -198,19 +280,30 @@ impl MessageContainer {
         self.frames.push(frame);
     }
 
-    pub fn export(self) -> Vec<String> {
+    pub fn export(self, batch_ref: Option<&str>) -> Vec<String> {
         let MessageContainer { frames, options } = self;
         frames
             .into_iter()
-            .filter_map(|frame| frame.export(&options))
+            .filter_map(|frame| frame.export(&options, batch_ref))
             .collect_vec()
     }
+
+    /// Like [`MessageContainer::export`], but each message is only exported when the returned
+    /// iterator gets to it.
+    fn export_lazily(self, batch_ref: Option<String>) -> impl Iterator<Item = String> + Send {
+        let MessageContainer { frames, options } = self;
+        frames
+            .into_iter()
+            .filter_map(move |frame| frame.export(&options, batch_ref.as_deref()))
+    }
 }
 
-/// Processes the stored message and applies the options specified by `options`.
+/// Processes the stored message and applies the options specified by `options`. If `batch_ref`
+/// is set, all messages are tagged as belonging to that batch (see [`wrap_in_batch`]).
 pub fn export_stored_messages(
     stored_messages: Vec<StoredMessage>,
     options: GetRecentMessagesQueryOptions,
+    batch_ref: Option<&str>,
 ) -> Vec<String> {
     let mut container = MessageContainer {
         options,
@@ -221,5 +314,417 @@ pub fn export_stored_messages(
         container.append_stored_msg(&stored_message);
     }
 
-    container.export()
+    container.export(batch_ref)
+}
+
+/// Like [`export_stored_messages`], but the messages are only exported as the returned iterator
+/// is advanced, e.g. while they are streamed to the client. They are still all parsed up front,
+/// since `CLEARCHAT` and `CLEARMSG` messages mark earlier messages as deleted.
+pub fn export_stored_messages_lazily(
+    stored_messages: Vec<StoredMessage>,
+    options: GetRecentMessagesQueryOptions,
+    batch_ref: Option<String>,
+) -> impl Iterator<Item = String> + Send {
+    let mut container = MessageContainer {
+        options,
+        frames: vec![],
+    };
+
+    for stored_message in stored_messages {
+        container.append_stored_msg(&stored_message);
+    }
+
+    container.export_lazily(batch_ref)
+}
+
+/// IRCv3 batch type of the exported messages, see [`wrap_in_batch`].
+const BATCH_TYPE: &str = "rm.recent-messages";
+
+/// A random reference for a batch of exported messages.
+pub fn generate_batch_ref() -> String {
+    format!("rm{:016x}", rand::random::<u64>())
+}
+
+/// Surrounds messages exported with `batch_ref` with the `BATCH` messages starting and ending
+/// the batch, so clients can tell where the recent messages start and end.
+pub fn wrap_in_batch(messages: &mut Vec<String>, batch_ref: &str) {
+    messages.insert(0, batch_start(batch_ref));
+    messages.push(batch_end(batch_ref));
+}
+
+/// The `BATCH` message starting the batch, see [`wrap_in_batch`].
+pub fn batch_start(batch_ref: &str) -> String {
+    format!("BATCH +{} {}", batch_ref, BATCH_TYPE)
+}
+
+/// The `BATCH` message ending the batch, see [`wrap_in_batch`].
+pub fn batch_end(batch_ref: &str) -> String {
+    format!("BATCH -{}", batch_ref)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_stored_messages, export_stored_messages_lazily, ROOM_MODE_NOTICE_IDS};
+    use crate::db::StoredMessage;
+    use crate::web::get_recent_messages::{GetRecentMessagesQueryOptions, TimestampFormat};
+    use chrono::{TimeZone, Utc};
+    use twitch_irc::message::{IRCMessage, IRCPrefix};
+
+    /// Received at 1596061058000 + the index of the message in milliseconds
+    const FIRST_RECEIVED_TS: i64 = 1596061058000;
+
+    const SCARROV_PRIVMSG: &str = "@badge-info=;badges=;color=#FF0000;display-name=Scarrov;emotes=;flags=;id=05836fc5-76e0-45de-b0be-779c223b160b;mod=0;room-id=71092938;subscriber=0;tmi-sent-ts=1596061057664;turbo=0;user-id=195960862;user-type= :scarrov!scarrov@scarrov.tmi.twitch.tv PRIVMSG #xqcow :????????";
+    const SCARROV_PRIVMSG_2: &str = "@badge-info=;badges=;color=#FF0000;display-name=Scarrov;emotes=;flags=;id=9e7ed5a7-3a59-4a5d-a71a-b6d8f5f3f9b7;mod=0;room-id=71092938;subscriber=0;tmi-sent-ts=1596061057900;turbo=0;user-id=195960862;user-type= :scarrov!scarrov@scarrov.tmi.twitch.tv PRIVMSG #xqcow :LUL";
+    const PAJLADA_PRIVMSG: &str = "@badge-info=;badges=;color=#0000FF;display-name=pajlada;emotes=;flags=;id=2c3a1a4b-2a3e-4bd6-bd2e-4b1c5a3b2d11;mod=0;room-id=71092938;subscriber=0;tmi-sent-ts=1596061057700;turbo=0;user-id=11148817;user-type= :pajlada!pajlada@pajlada.tmi.twitch.tv PRIVMSG #xqcow :pajaH";
+    const SCARROV_TIMEOUT: &str = "@ban-duration=630;room-id=71092938;target-user-id=195960862;tmi-sent-ts=1596061058100 :tmi.twitch.tv CLEARCHAT #xqcow :scarrov";
+    const SCARROV_BAN: &str = "@room-id=71092938;target-user-id=195960862;tmi-sent-ts=1596061058100 :tmi.twitch.tv CLEARCHAT #xqcow :scarrov";
+    const CHAT_CLEAR: &str =
+        "@room-id=71092938;tmi-sent-ts=1596061058100 :tmi.twitch.tv CLEARCHAT #xqcow";
+    const SCARROV_CLEARMSG: &str = "@login=scarrov;room-id=;target-msg-id=05836fc5-76e0-45de-b0be-779c223b160b;tmi-sent-ts=1596061058100 :tmi.twitch.tv CLEARMSG #xqcow :????????";
+
+    fn stored_message(id: i64, message_source: String) -> StoredMessage {
+        StoredMessage {
+            id,
+            time_received: Utc.timestamp_millis_opt(1596061058008).unwrap(),
+            message_source,
+        }
+    }
+
+    #[test]
+    fn lazy_export_is_identical() {
+        let stored_messages = [SCARROV_PRIVMSG, PAJLADA_PRIVMSG, SCARROV_TIMEOUT]
+            .iter()
+            .enumerate()
+            .map(|(i, message_source)| stored_message(i as i64, (*message_source).to_owned()))
+            .collect::<Vec<_>>();
+        for hide_moderated_messages in [false, true] {
+            let options = GetRecentMessagesQueryOptions {
+                hide_moderated_messages,
+                ..GetRecentMessagesQueryOptions::default()
+            };
+            assert_eq!(
+                export_stored_messages_lazily(
+                    stored_messages.clone(),
+                    options,
+                    Some("rm1".to_owned())
+                )
+                .collect::<Vec<_>>(),
+                export_stored_messages(stored_messages.clone(), options, Some("rm1"))
+            );
+        }
+    }
+
+    #[test]
+    fn room_mode_notices_can_be_hidden() {
+        let options = GetRecentMessagesQueryOptions {
+            hide_room_mode_notices: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        for notice_id in ROOM_MODE_NOTICE_IDS.iter() {
+            let notice = format!(
+                "@msg-id={} :tmi.twitch.tv NOTICE #pajlada :The room mode changed.",
+                notice_id
+            );
+            let exported = export_stored_messages(
+                vec![stored_message(1, notice.clone())],
+                GetRecentMessagesQueryOptions::default(),
+                None,
+            );
+            assert_eq!(exported.len(), 1, "{} is exported by default", notice_id);
+            let exported = export_stored_messages(vec![stored_message(1, notice)], options, None);
+            assert!(exported.is_empty(), "{} is hidden", notice_id);
+        }
+
+        // other notices and the ROOMSTATE with the current modes are still exported
+        let exported = export_stored_messages(
+            vec![
+                stored_message(
+                    1,
+                    "@msg-id=msg_timedout :tmi.twitch.tv NOTICE #pajlada :You are timed out."
+                        .to_owned(),
+                ),
+                stored_message(
+                    2,
+                    "@emote-only=1;room-id=11148817 :tmi.twitch.tv ROOMSTATE #pajlada".to_owned(),
+                ),
+            ],
+            options,
+            None,
+        );
+        assert_eq!(exported.len(), 2);
+    }
+
+    fn export(
+        message_sources: &[&str],
+        options: GetRecentMessagesQueryOptions,
+        batch_ref: Option<&str>,
+    ) -> Vec<IRCMessage> {
+        let stored_messages = message_sources
+            .iter()
+            .enumerate()
+            .map(|(i, message_source)| StoredMessage {
+                id: i as i64,
+                time_received: Utc
+                    .timestamp_millis_opt(FIRST_RECEIVED_TS + i as i64)
+                    .unwrap(),
+                message_source: (*message_source).to_owned(),
+            })
+            .collect();
+        export_stored_messages(stored_messages, options, batch_ref)
+            .iter()
+            .map(|line| IRCMessage::parse(line).unwrap())
+            .collect()
+    }
+
+    fn tag<'a>(message: &'a IRCMessage, name: &str) -> Option<&'a str> {
+        message.tags.0.get(name).and_then(|value| value.as_deref())
+    }
+
+    fn is_deleted(message: &IRCMessage) -> bool {
+        tag(message, "rm-deleted") == Some("1")
+    }
+
+    /// The message with the tags added on export removed again
+    fn without_export_tags(message: &IRCMessage) -> IRCMessage {
+        let mut message = message.clone();
+        for added_tag in ["historical", "rm-received-ts", "rm-deleted"] {
+            message.tags.0.remove(added_tag);
+        }
+        message
+    }
+
+    #[test]
+    fn exported_messages_are_tagged_as_historical() {
+        let exported = export(
+            &[SCARROV_PRIVMSG, PAJLADA_PRIVMSG],
+            GetRecentMessagesQueryOptions::default(),
+            None,
+        );
+        assert_eq!(exported.len(), 2);
+        for (i, (message, original)) in exported
+            .iter()
+            .zip([SCARROV_PRIVMSG, PAJLADA_PRIVMSG])
+            .enumerate()
+        {
+            assert_eq!(tag(message, "historical"), Some("1"));
+            let received_ts = (FIRST_RECEIVED_TS + i as i64).to_string();
+            assert_eq!(tag(message, "rm-received-ts"), Some(received_ts.as_str()));
+            assert_eq!(tag(message, "rm-received-time"), None);
+            assert_eq!(tag(message, "batch"), None);
+            assert!(!is_deleted(message));
+            // everything else is unchanged
+            assert_eq!(
+                without_export_tags(message),
+                IRCMessage::parse(original).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn timestamp_format_and_batch_add_tags() {
+        let options = GetRecentMessagesQueryOptions {
+            timestamp_format: TimestampFormat::Rfc3339,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        let exported = export(&[SCARROV_PRIVMSG], options, Some("rm0123456789abcdef"));
+        assert_eq!(
+            tag(&exported[0], "rm-received-time"),
+            Some("2020-07-29T22:17:38.000Z")
+        );
+        assert_eq!(tag(&exported[0], "batch"), Some("rm0123456789abcdef"));
+    }
+
+    #[test]
+    fn timeouts_and_bans_mark_earlier_messages_of_the_user_deleted() {
+        for clearchat in [SCARROV_TIMEOUT, SCARROV_BAN] {
+            let exported = export(
+                &[
+                    SCARROV_PRIVMSG,
+                    PAJLADA_PRIVMSG,
+                    clearchat,
+                    SCARROV_PRIVMSG_2,
+                ],
+                GetRecentMessagesQueryOptions::default(),
+                None,
+            );
+            assert_eq!(exported.len(), 4);
+            assert!(is_deleted(&exported[0]));
+            // other users' messages and later messages are not affected
+            assert!(!is_deleted(&exported[1]));
+            assert!(!is_deleted(&exported[3]));
+            // the CLEARCHAT itself is exported as is
+            assert_eq!(exported[2].command, "CLEARCHAT");
+            assert!(!is_deleted(&exported[2]));
+        }
+    }
+
+    #[test]
+    fn clearmsg_marks_the_message_with_its_id_deleted() {
+        let exported = export(
+            &[
+                SCARROV_PRIVMSG,
+                SCARROV_PRIVMSG_2,
+                PAJLADA_PRIVMSG,
+                SCARROV_CLEARMSG,
+            ],
+            GetRecentMessagesQueryOptions::default(),
+            None,
+        );
+        assert_eq!(exported.len(), 4);
+        assert!(is_deleted(&exported[0]));
+        assert!(!is_deleted(&exported[1]));
+        assert!(!is_deleted(&exported[2]));
+        assert_eq!(exported[3].command, "CLEARMSG");
+    }
+
+    #[test]
+    fn chat_clear_marks_all_earlier_messages_deleted() {
+        let exported = export(
+            &[
+                SCARROV_PRIVMSG,
+                PAJLADA_PRIVMSG,
+                CHAT_CLEAR,
+                SCARROV_PRIVMSG_2,
+            ],
+            GetRecentMessagesQueryOptions::default(),
+            None,
+        );
+        assert_eq!(exported.len(), 4);
+        assert!(is_deleted(&exported[0]));
+        assert!(is_deleted(&exported[1]));
+        assert!(!is_deleted(&exported[2]));
+        assert!(!is_deleted(&exported[3]));
+    }
+
+    #[test]
+    fn hide_options_omit_messages() {
+        let messages = [
+            SCARROV_PRIVMSG,
+            PAJLADA_PRIVMSG,
+            SCARROV_TIMEOUT,
+            SCARROV_PRIVMSG_2,
+            SCARROV_CLEARMSG,
+        ];
+        let commands_and_ids = |options| {
+            export(&messages, options, None)
+                .iter()
+                .map(|message| {
+                    (
+                        message.command.clone(),
+                        tag(message, "id").map(str::to_owned),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let privmsg = |id: &str| ("PRIVMSG".to_owned(), Some(id.to_owned()));
+        let scarrov_id = "05836fc5-76e0-45de-b0be-779c223b160b";
+        let scarrov_id_2 = "9e7ed5a7-3a59-4a5d-a71a-b6d8f5f3f9b7";
+        let pajlada_id = "2c3a1a4b-2a3e-4bd6-bd2e-4b1c5a3b2d11";
+
+        let hide_moderated = GetRecentMessagesQueryOptions {
+            hide_moderated_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert_eq!(
+            commands_and_ids(hide_moderated),
+            vec![
+                privmsg(pajlada_id),
+                ("CLEARCHAT".to_owned(), None),
+                privmsg(scarrov_id_2),
+                ("CLEARMSG".to_owned(), None),
+            ]
+        );
+
+        let hide_moderation = GetRecentMessagesQueryOptions {
+            hide_moderation_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert_eq!(
+            commands_and_ids(hide_moderation),
+            vec![
+                privmsg(scarrov_id),
+                privmsg(pajlada_id),
+                privmsg(scarrov_id_2),
+            ]
+        );
+
+        let hide_both = GetRecentMessagesQueryOptions {
+            hide_moderated_messages: true,
+            hide_moderation_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert_eq!(
+            commands_and_ids(hide_both),
+            vec![privmsg(pajlada_id), privmsg(scarrov_id_2)]
+        );
+    }
+
+    #[test]
+    fn clearchat_is_converted_to_notice() {
+        let options = GetRecentMessagesQueryOptions {
+            clearchat_to_notice: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        let cases = [
+            (
+                SCARROV_TIMEOUT,
+                "rm-timeout",
+                "scarrov has been timed out for 10m 30s.",
+            ),
+            (
+                SCARROV_BAN,
+                "rm-permaban",
+                "scarrov has been permanently banned.",
+            ),
+            (
+                CHAT_CLEAR,
+                "rm-clearchat",
+                "Chat has been cleared by a moderator.",
+            ),
+        ];
+        for (clearchat, msg_id, text) in cases {
+            let exported = export(&[SCARROV_PRIVMSG, clearchat], options, None);
+            assert_eq!(exported.len(), 2);
+            // the deleted flag is still applied
+            assert!(is_deleted(&exported[0]));
+
+            let notice = &exported[1];
+            assert_eq!(notice.command, "NOTICE");
+            assert_eq!(
+                notice.prefix,
+                Some(IRCPrefix::HostOnly {
+                    host: "tmi.twitch.tv".to_owned()
+                })
+            );
+            assert_eq!(notice.params, vec!["#xqcow".to_owned(), text.to_owned()]);
+            assert_eq!(tag(notice, "msg-id"), Some(msg_id));
+            assert_eq!(tag(notice, "historical"), Some("1"));
+            let received_ts = (FIRST_RECEIVED_TS + 1).to_string();
+            assert_eq!(tag(notice, "rm-received-ts"), Some(received_ts.as_str()));
+            // only the tags set on conversion and export
+            assert_eq!(notice.tags.0.len(), 3);
+        }
+
+        // the option only affects CLEARCHAT
+        let exported = export(&[SCARROV_PRIVMSG, SCARROV_CLEARMSG], options, None);
+        assert_eq!(exported[1].command, "CLEARMSG");
+    }
+
+    #[test]
+    fn ignored_notices_and_unexported_commands_are_dropped() {
+        let exported = export(
+            &[
+                "@msg-id=host_on :tmi.twitch.tv NOTICE #xqcow :Now hosting pajlada.",
+                "@msg-id=no_permission :tmi.twitch.tv NOTICE #xqcow :You don't have permission to perform that action.",
+                ":scarrov!scarrov@scarrov.tmi.twitch.tv JOIN #xqcow",
+                "@msg-id=emote_only_on :tmi.twitch.tv NOTICE #xqcow :This room is now in emote-only mode.",
+                SCARROV_PRIVMSG,
+            ],
+            GetRecentMessagesQueryOptions::default(),
+            None,
+        );
+        assert_eq!(exported.len(), 2);
+        assert_eq!(tag(&exported[0], "msg-id"), Some("emote_only_on"));
+        assert_eq!(exported[1].command, "PRIVMSG");
+    }
 }
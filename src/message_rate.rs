@@ -0,0 +1,170 @@
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+lazy_static! {
+    static ref CHANNEL_MESSAGE_RATE: GaugeVec = register_gauge_vec!(
+        "recentmessages_channel_message_rate",
+        "Messages per second received over the last minute, only exported for the busiest channels",
+        &["channel"]
+    )
+    .unwrap();
+}
+
+/// Length of a single counting slot.
+const SLOT_LENGTH: Duration = Duration::from_secs(5);
+/// Number of slots that make up the window rates are calculated over (12 * 5s = 1 minute).
+const NUM_SLOTS: usize = 12;
+
+/// Counts messages per channel in fixed-length time slots, to calculate per-channel message rates
+/// over a sliding window.
+#[derive(Debug)]
+pub struct ChannelRateTracker {
+    /// One map of counts per slot, the oldest slot is at the front, the current slot at the back.
+    slots: VecDeque<HashMap<String, u64>>,
+}
+
+impl ChannelRateTracker {
+    fn new() -> ChannelRateTracker {
+        let mut slots = VecDeque::with_capacity(NUM_SLOTS + 1);
+        slots.push_back(HashMap::new());
+        ChannelRateTracker { slots }
+    }
+
+    pub fn record(&mut self, channel_login: &str) {
+        let current_slot = self.slots.back_mut().unwrap();
+        if let Some(count) = current_slot.get_mut(channel_login) {
+            *count += 1;
+        } else {
+            current_slot.insert(channel_login.to_owned(), 1);
+        }
+    }
+
+    /// Start a new slot, dropping the oldest one if the window is full.
+    fn advance(&mut self) {
+        self.slots.push_back(HashMap::new());
+        while self.slots.len() > NUM_SLOTS {
+            self.slots.pop_front();
+        }
+    }
+
+    /// The `n` channels with the highest message rate (in messages per second) over the window,
+    /// busiest channel first.
+    pub fn top_channels(&self, n: usize) -> Vec<(&str, f64)> {
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+        for slot in self.slots.iter() {
+            for (channel_login, count) in slot.iter() {
+                *totals.entry(channel_login.as_str()).or_insert(0) += count;
+            }
+        }
+
+        let window_secs = (SLOT_LENGTH * NUM_SLOTS as u32).as_secs_f64();
+        totals
+            .into_iter()
+            .sorted_by(|(a_login, a_count), (b_login, b_count)| {
+                b_count.cmp(a_count).then(a_login.cmp(b_login))
+            })
+            .take(n)
+            .map(|(channel_login, count)| (channel_login, count as f64 / window_secs))
+            .collect_vec()
+    }
+}
+
+pub type SharedChannelRateTracker = Arc<Mutex<ChannelRateTracker>>;
+
+pub fn new_shared_tracker() -> SharedChannelRateTracker {
+    Arc::new(Mutex::new(ChannelRateTracker::new()))
+}
+
+/// Periodically advances the window of `tracker` and exports the rates of the `top_n` busiest
+/// channels. All other channels are removed from the exported metric to keep its cardinality
+/// bounded.
+pub async fn run_rate_exporter(
+    tracker: SharedChannelRateTracker,
+    top_n: usize,
+    shutdown_signal: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(SLOT_LENGTH);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown_signal.cancelled() => {
+                break;
+            }
+        }
+
+        let mut tracker_guard = tracker.lock().unwrap();
+        CHANNEL_MESSAGE_RATE.reset();
+        for (channel_login, rate) in tracker_guard.top_channels(top_n) {
+            CHANNEL_MESSAGE_RATE
+                .with_label_values(&[channel_login])
+                .set(rate);
+        }
+        tracker_guard.advance();
+    }
+}
+
+/// Periodically advances the window of `tracker`, for trackers whose rates are not exported as
+/// metrics.
+pub async fn run_window_advancer(
+    tracker: SharedChannelRateTracker,
+    shutdown_signal: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(SLOT_LENGTH);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown_signal.cancelled() => {
+                break;
+            }
+        }
+        tracker.lock().unwrap().advance();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelRateTracker, NUM_SLOTS};
+
+    #[test]
+    fn top_channels_over_sliding_window() {
+        let mut tracker = ChannelRateTracker::new();
+        for _ in 0..120 {
+            tracker.record("forsen");
+        }
+        tracker.advance();
+        for _ in 0..60 {
+            tracker.record("pajlada");
+        }
+        tracker.record("randers");
+
+        assert_eq!(
+            tracker.top_channels(2),
+            vec![("forsen", 2.0), ("pajlada", 1.0)]
+        );
+
+        // the slot with the forsen messages drops out of the window
+        for _ in 0..NUM_SLOTS - 1 {
+            tracker.advance();
+        }
+        assert_eq!(
+            tracker.top_channels(5),
+            vec![("pajlada", 1.0), ("randers", 1.0 / 60.0)]
+        );
+        tracker.advance();
+        assert!(tracker.top_channels(5).is_empty());
+    }
+}
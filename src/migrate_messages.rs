@@ -1,7 +1,22 @@
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use itertools::Itertools;
 use serde::{Deserialize, Deserializer};
 use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use thiserror::Error;
+
+/// Converts the message files of the version 1 recent-messages service into a CSV file
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+struct Args {
+    /// Directory containing the `.dat` message files
+    #[structopt(short = "i", long = "input", default_value = "messages")]
+    input_dir: PathBuf,
+    /// CSV file to write the messages to
+    #[structopt(short = "o", long = "output", default_value = "messages.csv")]
+    output_file: PathBuf,
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StoredMessage {
@@ -15,59 +30,139 @@ where
     D: Deserializer<'de>,
 {
     let millis = Deserialize::deserialize(deserializer)?;
-    Ok(Utc.timestamp_millis_opt(millis).unwrap())
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| serde::de::Error::custom(format!("timestamp out of range: {}", millis)))
+}
+
+#[derive(Error, Debug)]
+enum MigrateFileError {
+    #[error("File name is not valid UTF-8")]
+    InvalidFileName,
+    #[error("Failed to open file: {0}")]
+    Open(std::io::Error),
+    #[error("Failed to decode file contents: {0}")]
+    Decode(rmp_serde::decode::Error),
+    #[error("Failed to write to output file: {0}")]
+    Write(csv::Error),
+}
+
+/// Write all messages from the given `.dat` file to the CSV writer, returns the number of messages
+fn migrate_file<W: std::io::Write>(
+    file_path: &Path,
+    csv_writer: &mut csv::Writer<W>,
+) -> Result<usize, MigrateFileError> {
+    let channel_login = file_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or(MigrateFileError::InvalidFileName)?
+        .to_owned();
+
+    let file = std::fs::File::open(file_path).map_err(MigrateFileError::Open)?;
+    let channel_messages: Vec<StoredMessage> =
+        rmp_serde::decode::from_read(file).map_err(MigrateFileError::Decode)?;
+
+    for message in channel_messages.iter() {
+        csv_writer
+            .write_record(&[
+                &channel_login,
+                // millisecond precision, like the times stored by the IRC forwarder
+                &message
+                    .time_received
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                &message.message_source,
+            ])
+            .map_err(MigrateFileError::Write)?;
+    }
+
+    Ok(channel_messages.len())
 }
 
 fn main() {
-    let dir_contents = std::fs::read_dir("messages")
-        .expect("messages directory missing")
-        .collect_vec();
+    let args = Args::from_args();
+
+    let dir_contents = match std::fs::read_dir(&args.input_dir) {
+        Ok(dir_contents) => dir_contents.collect_vec(),
+        Err(e) => {
+            eprintln!(
+                "Failed to read messages directory `{}`: {}",
+                args.input_dir.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
     let output_file = OpenOptions::new()
         .write(true)
         .append(false)
         .create(true)
         .truncate(true)
-        .open("messages.csv")
-        .unwrap();
+        .open(&args.output_file);
+    let output_file = match output_file {
+        Ok(output_file) => output_file,
+        Err(e) => {
+            eprintln!(
+                "Failed to open output file `{}`: {}",
+                args.output_file.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
     let mut csv_writer = csv::Writer::from_writer(output_file);
 
     let mut idx: usize = 0;
+    let mut files_succeeded: usize = 0;
+    let mut files_failed: usize = 0;
+    let mut messages_migrated: usize = 0;
     let total = dir_contents.len();
     print!("Processing... 0/{}", total);
 
     for dir_entry in dir_contents {
-        let file_path = dir_entry.unwrap().path();
+        idx += 1;
+
+        let file_path = match dir_entry {
+            Ok(dir_entry) => dir_entry.path(),
+            Err(e) => {
+                eprintln!("\nFailed to read directory entry: {}", e);
+                files_failed += 1;
+                continue;
+            }
+        };
         if file_path
             .extension()
             .map(|ext| ext != "dat")
             .unwrap_or(true)
         {
             // either has an extension that is not `dat` or has no extension
-            tracing::debug!(
-                "Ignoring file {} from messages directory, extension is not `dat`",
-                file_path.display()
-            );
             continue;
         }
 
-        let channel_login = file_path.file_stem().unwrap().to_str().unwrap().to_owned();
-
-        let file = std::fs::File::open(file_path).unwrap();
-        let channel_messages: Vec<StoredMessage> = rmp_serde::decode::from_read(file).unwrap();
-
-        for message in channel_messages {
-            csv_writer
-                .write_record(&[
-                    &channel_login,
-                    &message.time_received.to_rfc3339(),
-                    &message.message_source,
-                ])
-                .unwrap();
+        match migrate_file(&file_path, &mut csv_writer) {
+            Ok(num_messages) => {
+                files_succeeded += 1;
+                messages_migrated += num_messages;
+            }
+            Err(e) => {
+                eprintln!("\nSkipping `{}`: {}", file_path.display(), e);
+                files_failed += 1;
+            }
         }
 
-        idx += 1;
         print!("\rProcessing... {}/{}", idx, total);
     }
 
+    if let Err(e) = csv_writer.flush() {
+        eprintln!("\nFailed to flush output file: {}", e);
+        std::process::exit(1);
+    }
+
     println!(" Done");
+    println!(
+        "Migrated {} messages from {} files, {} files failed",
+        messages_migrated, files_succeeded, files_failed
+    );
+    if files_failed > 0 {
+        std::process::exit(1);
+    }
 }
@@ -1,5 +1,5 @@
 use chrono::Utc;
-use prometheus::{register_gauge, register_int_gauge};
+use prometheus::{register_gauge, register_int_gauge, register_int_gauge_vec};
 use simple_process_stats::ProcessStats;
 use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
@@ -26,7 +26,20 @@ pub async fn run_process_monitoring(shutdown_signal: CancellationToken) {
         "Resident memory usage size as reported by the kernel, in bytes"
     )
     .unwrap();
+    let build_info = register_int_gauge_vec!(
+        "recentmessages_build_info",
+        "Always 1, labelled with the version, git commit and compiler the running build was made from",
+        &["version", "git_commit", "rustc"]
+    )
+    .unwrap();
     start_time_seconds.set(Utc::now().timestamp() as f64);
+    build_info
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            env!("RECENT_MESSAGES_GIT_COMMIT"),
+            env!("RECENT_MESSAGES_RUSTC_VERSION"),
+        ])
+        .set(1);
 
     let mut interval = tokio::time::interval(Duration::from_secs(10));
     loop {
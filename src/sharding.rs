@@ -0,0 +1,73 @@
+//! Mapping of channels to the database partition their messages are stored on.
+//!
+//! Partition 0 is the main database, partitions `1..=num_shard_dbs` are the `[[shard_db]]`s in
+//! the order they are configured. Unless `app.main_db_stores_messages` is disabled, the main
+//! database stores messages like any shard. External tools that need to find a channel's messages (e.g. to
+//! rebalance shards) must use the exact same mapping.
+
+use murmur3::murmur3_32;
+use std::io::Cursor;
+
+/// The partition the messages of `channel_login` are stored on, given the number of configured
+/// `[[shard_db]]`s (not counting the main database). Without shards, the main database always
+/// stores all messages.
+pub fn partition_for(
+    channel_login: &str,
+    num_shard_dbs: usize,
+    main_db_stores_messages: bool,
+) -> usize {
+    let hash_result: u32 = murmur3_32(&mut Cursor::new(channel_login), 0).unwrap();
+    if main_db_stores_messages || num_shard_dbs == 0 {
+        (hash_result % ((num_shard_dbs + 1) as u32)) as usize
+    } else {
+        1 + (hash_result % (num_shard_dbs as u32)) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::partition_for;
+
+    #[test]
+    fn everything_is_on_main_db_without_shards() {
+        for channel_login in ["pajlada", "randers", "forsen", "xqcow"] {
+            assert_eq!(partition_for(channel_login, 0, true), 0);
+            assert_eq!(partition_for(channel_login, 0, false), 0);
+        }
+    }
+
+    #[test]
+    fn main_db_can_be_reserved_for_metadata() {
+        for channel_login in ["pajlada", "randers", "forsen", "xqcow", "zneix", "supinic"] {
+            assert_eq!(partition_for(channel_login, 1, false), 1);
+            for num_shard_dbs in 2..5 {
+                let partition = partition_for(channel_login, num_shard_dbs, false);
+                assert!((1..=num_shard_dbs).contains(&partition));
+            }
+        }
+    }
+
+    #[test]
+    fn partitions_are_stable() {
+        // changing these outputs means stored messages can no longer be found after an upgrade
+        let expected = [
+            ("pajlada", [0, 1, 1, 3, 3]),
+            ("randers", [0, 1, 1, 3, 0]),
+            ("forsen", [0, 1, 2, 3, 0]),
+            ("xqcow", [0, 0, 0, 2, 4]),
+            ("zneix", [0, 1, 1, 3, 4]),
+            ("supinic", [0, 0, 2, 2, 1]),
+        ];
+        for (channel_login, partitions) in expected {
+            for (num_shard_dbs, partition) in partitions.into_iter().enumerate() {
+                assert_eq!(
+                    partition_for(channel_login, num_shard_dbs, true),
+                    partition,
+                    "{} with {} shards",
+                    channel_login,
+                    num_shard_dbs
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::web::auth::UserAuthorization;
+use crate::web::error::ApiError;
+use crate::web::ignored::part_and_purge;
+use crate::web::WebAppData;
+use axum::{Extension, Json};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct DeleteAccountResponse {
+    messages_purged: u64,
+    sessions_revoked: u64,
+}
+
+// POST /api/v2/account/delete
+/// Removes everything stored about the authorized user's channel: the channel is ignored so no
+/// new messages are stored, its messages are purged, and all sessions of the user are revoked
+/// (including the one used for this request).
+pub async fn delete_account(
+    Extension(authorization): Extension<UserAuthorization>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<DeleteAccountResponse>, ApiError> {
+    if !app_data
+        .data_storage
+        .is_channel_available(&authorization.user_login)
+    {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    // ignoring a channel that is already ignored by an operator is fine, it stays ignored
+    app_data
+        .data_storage
+        .set_channel_ignored(&authorization.user_login, true)
+        .await
+        .map_err(ApiError::SetChannelIgnored)?;
+    let messages_purged = part_and_purge(app_data, authorization.user_login).await?;
+
+    let sessions_revoked = app_data
+        .data_storage
+        .delete_all_user_authorizations(&authorization.user_id)
+        .await
+        .map_err(ApiError::RevokeAllSessions)?;
+
+    Ok(Json(DeleteAccountResponse {
+        messages_purged,
+        sessions_revoked,
+    }))
+}
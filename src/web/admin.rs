@@ -0,0 +1,290 @@
+use crate::config::WebConfig;
+use crate::web::error::ApiError;
+use crate::web::WebAppData;
+use axum::extract::rejection::JsonRejection;
+use axum::{Extension, Json};
+use futures::stream::{self, StreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Larger requests are rejected, split them up instead
+const MAX_BULK_IGNORE_CHANNELS: usize = 1000;
+/// Number of channels whose messages are purged at the same time by the bulk ignore
+const BULK_PURGE_CONCURRENCY: usize = 8;
+
+#[derive(Deserialize)]
+pub struct RejoinBodyOptions {
+    channel_login: String,
+}
+
+#[derive(Serialize)]
+pub struct RejoinResponse {
+    /// Whether the join was confirmed within `web.max_wait_for_join`
+    joined: bool,
+}
+
+// POST /api/v2/admin/rejoin
+/// Parts and re-joins a channel, for channels that are believed to be joined but don't receive
+/// any messages.
+pub async fn force_rejoin(
+    Extension(app_data): Extension<WebAppData>,
+    options: Result<Json<RejoinBodyOptions>, JsonRejection>,
+) -> Result<Json<RejoinResponse>, ApiError> {
+    let Json(RejoinBodyOptions { channel_login }) =
+        options.map_err(|_| ApiError::InvalidPayload)?;
+
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
+    let is_ignored = app_data
+        .data_storage
+        .is_channel_ignored(&channel_login)
+        .await
+        .map_err(ApiError::GetChannelIgnored)?;
+    if is_ignored {
+        return Err(ApiError::ChannelIgnored(channel_login));
+    }
+
+    let joined = app_data
+        .irc_listener
+        .force_rejoin(channel_login, app_data.config.web.max_wait_for_join)
+        .await;
+    Ok(Json(RejoinResponse { joined }))
+}
+
+#[derive(Deserialize)]
+pub struct BulkIgnoreBodyOptions {
+    channels: Vec<String>,
+    ignored: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct BulkIgnoreChannelResult {
+    /// As given in the request
+    channel: String,
+    /// `None` if the channel was (un-)ignored
+    error: Option<String>,
+    /// Number of messages deleted, if the channel was ignored and its messages were purged
+    messages_purged: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct BulkIgnoreResponse {
+    /// In the order of the request
+    results: Vec<BulkIgnoreChannelResult>,
+}
+
+/// Normalizes and validates each of the channels. Returns the valid channels without duplicates,
+/// and a result for each channel, with an error for the invalid ones.
+fn validate_bulk_channels(
+    channels: Vec<String>,
+    config: &WebConfig,
+) -> (Vec<String>, Vec<(BulkIgnoreChannelResult, Option<String>)>) {
+    let results = channels
+        .into_iter()
+        .map(
+            |channel| match super::normalize_channel_login(channel.clone(), config) {
+                Ok(channel_login) => (
+                    BulkIgnoreChannelResult {
+                        channel,
+                        error: None,
+                        messages_purged: None,
+                    },
+                    Some(channel_login),
+                ),
+                Err(e) => (
+                    BulkIgnoreChannelResult {
+                        channel,
+                        error: Some(e.to_string()),
+                        messages_purged: None,
+                    },
+                    None,
+                ),
+            },
+        )
+        .collect_vec();
+    let channel_logins = results
+        .iter()
+        .filter_map(|(_, channel_login)| channel_login.clone())
+        .unique()
+        .collect_vec();
+    (channel_logins, results)
+}
+
+/// Parts the channels and purges their messages, `BULK_PURGE_CONCURRENCY` channels at a time.
+/// Returns the number of messages purged for each channel, or the error.
+async fn part_and_purge_bulk(
+    app_data: WebAppData,
+    channel_logins: Vec<String>,
+) -> HashMap<String, Result<u64, ApiError>> {
+    stream::iter(channel_logins)
+        .map(|channel_login| async move {
+            let result = super::ignored::part_and_purge(app_data, channel_login.clone()).await;
+            if let Err(e) = &result {
+                tracing::error!("Failed to purge messages of {}: {}", channel_login, e);
+            }
+            (channel_login, result)
+        })
+        .buffer_unordered(BULK_PURGE_CONCURRENCY)
+        .collect()
+        .await
+}
+
+// POST /api/v2/admin/ignored/bulk
+/// Ignores (and parts and purges) or un-ignores many channels at once, e.g. during an abuse
+/// incident. The flag is set for all valid channels in one transaction, invalid channels are
+/// reported in the results without failing the entire request. The purges run in a separate
+/// task, so they are finished even if the request times out.
+pub async fn set_ignored_bulk(
+    Extension(app_data): Extension<WebAppData>,
+    options: Result<Json<BulkIgnoreBodyOptions>, JsonRejection>,
+) -> Result<Json<BulkIgnoreResponse>, ApiError> {
+    let Json(BulkIgnoreBodyOptions { channels, ignored }) =
+        options.map_err(|_| ApiError::InvalidPayload)?;
+    if channels.len() > MAX_BULK_IGNORE_CHANNELS {
+        return Err(ApiError::InvalidPayload);
+    }
+
+    let (channel_logins, results) = validate_bulk_channels(channels, &app_data.config.web);
+    app_data
+        .data_storage
+        .set_channels_ignored_by_operator(&channel_logins, ignored)
+        .await
+        .map_err(ApiError::SetChannelIgnored)?;
+    tracing::info!(
+        "Operator {} {} channels",
+        if ignored { "ignored" } else { "un-ignored" },
+        channel_logins.len()
+    );
+
+    let purge_results = if ignored {
+        tokio::spawn(part_and_purge_bulk(app_data, channel_logins))
+            .await
+            .unwrap() // only fails if the task panicked
+    } else {
+        for channel_login in channel_logins {
+            // already validated
+            app_data.irc_listener.join_unignored(channel_login);
+        }
+        HashMap::new()
+    };
+
+    Ok(Json(BulkIgnoreResponse {
+        results: results
+            .into_iter()
+            .map(|(mut result, channel_login)| {
+                match channel_login.and_then(|c| purge_results.get(&c)) {
+                    Some(Ok(messages_purged)) => result.messages_purged = Some(*messages_purged),
+                    Some(Err(_)) => {
+                        result.error =
+                            Some("Channel was ignored, but purging its messages failed".to_owned());
+                    }
+                    None => {}
+                }
+                result
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct LogLevelResponse {
+    filter: String,
+}
+
+// GET /api/v2/admin/log-level
+pub async fn get_log_level(Extension(app_data): Extension<WebAppData>) -> Json<LogLevelResponse> {
+    Json(LogLevelResponse {
+        filter: app_data.log_filter.current(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SetLogLevelBodyOptions {
+    /// Same syntax as `RUST_LOG`
+    filter: String,
+}
+
+#[derive(Serialize)]
+pub struct SetLogLevelResponse {
+    filter: String,
+    previous_filter: String,
+}
+
+// POST /api/v2/admin/log-level
+/// Changes which log messages are output, e.g. to temporarily get `debug` messages of a single
+/// module while investigating an incident. Reset on restart.
+pub async fn set_log_level(
+    Extension(app_data): Extension<WebAppData>,
+    options: Result<Json<SetLogLevelBodyOptions>, JsonRejection>,
+) -> Result<Json<SetLogLevelResponse>, ApiError> {
+    let Json(SetLogLevelBodyOptions { filter }) = options.map_err(|_| ApiError::InvalidPayload)?;
+
+    let previous_filter = app_data
+        .log_filter
+        .set(&filter)
+        .map_err(ApiError::InvalidLogFilter)?;
+    tracing::info!(
+        "Log filter changed from `{}` to `{}`",
+        previous_filter,
+        app_data.log_filter.current()
+    );
+    Ok(Json(SetLogLevelResponse {
+        filter: app_data.log_filter.current(),
+        previous_filter,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct TopClientsResponse {
+    /// Requests per second over the last minute, by client IP
+    clients: HashMap<String, f64>,
+}
+
+// GET /api/v2/admin/top-clients
+/// The clients with the most requests over the last minute. Not found unless
+/// `web.track_client_ips` is enabled.
+pub async fn get_top_clients(
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<TopClientsResponse>, ApiError> {
+    let tracker = app_data.client_ip_tracker.ok_or(ApiError::NotFound)?;
+    Ok(Json(TopClientsResponse {
+        clients: tracker.top_clients().into_iter().collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_bulk_channels, BulkIgnoreChannelResult};
+    use crate::config::test_web_config;
+
+    #[test]
+    fn bulk_channels_are_validated_individually() {
+        let config = test_web_config("");
+        let (channel_logins, results) = validate_bulk_channels(
+            vec![
+                "Forsen".to_owned(),
+                "for sen".to_owned(),
+                "forsen".to_owned(),
+                "pajlada".to_owned(),
+            ],
+            &config,
+        );
+        assert_eq!(channel_logins, vec!["forsen", "pajlada"]);
+        let results = results
+            .into_iter()
+            .map(|(result, _)| result)
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results[0],
+            BulkIgnoreChannelResult {
+                channel: "Forsen".to_owned(),
+                error: None,
+                messages_purged: None
+            }
+        );
+        assert_eq!(results[1].channel, "for sen");
+        assert!(results[1].error.is_some());
+        assert!(results[2].error.is_none() && results[3].error.is_none());
+    }
+}
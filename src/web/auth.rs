@@ -1,14 +1,46 @@
 use crate::config::TwitchApiClientCredentials;
+use crate::web::circuit_breaker::CircuitBreaker;
 use crate::web::ApiError;
 use chrono::{DateTime, Utc};
 use futures::prelude::*;
 use http::StatusCode;
 use lazy_static::lazy_static;
+use rand::distributions::Standard;
+use rand::Rng;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::fmt::Write;
 use std::future::Future;
 use std::pin::Pin;
 use std::time::Duration;
 
+/// Length of the access tokens handed out to users, in bytes. They are encoded as lowercase hex,
+/// so the encoded token is twice as long.
+const ACCESS_TOKEN_BYTES: usize = 512 / 8;
+
+lazy_static! {
+    /// Matches an `Authorization` header carrying an access token generated by
+    /// [`generate_access_token`], capturing the token.
+    pub static ref RE_AUTHORIZATION_HEADER: Regex =
+        Regex::new(&format!("^Bearer ([0-9a-f]{{{}}})$", ACCESS_TOKEN_BYTES * 2)).unwrap();
+}
+
+/// A new random access token (512 bits as a hex string).
+pub fn generate_access_token() -> String {
+    // thread_rng() is cryptographically safe
+    rand::thread_rng()
+        .sample_iter(Standard)
+        .take(ACCESS_TOKEN_BYTES)
+        .fold(
+            String::with_capacity(ACCESS_TOKEN_BYTES * 2),
+            |mut s, x: u8| {
+                // format as hex, padded with a leading 0 if needed (e.g. 0x0 -> "00", 0xFF -> "ff")
+                write!(&mut s, "{:02x}", x).unwrap();
+                s
+            },
+        )
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct TwitchUserAccessToken {
     pub access_token: String,
@@ -34,6 +66,8 @@ pub struct UserAuthorization {
     pub user_login: String,
     pub user_name: String,
     pub user_profile_image_url: String,
+    /// last time the user details above were queried from Helix
+    pub user_details_last_updated: DateTime<Utc>,
 }
 
 #[derive(Serialize)]
@@ -79,8 +113,11 @@ pub struct HelixUser {
     pub profile_image_url: String,
 }
 
-lazy_static! {
-    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+/// Response of `https://id.twitch.tv/oauth2/validate`
+#[derive(Deserialize)]
+pub struct TwitchValidateTokenResponse {
+    pub login: String,
+    // we're not interested in the rest of the fields, so they are omitted
 }
 
 #[derive(Deserialize)]
@@ -92,10 +129,11 @@ impl UserAuthorization {
     /// Try to refresh the access token
     async fn refresh_token(
         &mut self,
+        http_client: &reqwest::Client,
         credentials: &TwitchApiClientCredentials,
     ) -> Result<(), ApiError> {
         tracing::info!("Refreshing access token for user {}", self.user_login);
-        let new_access_token = HTTP_CLIENT
+        let new_access_token = http_client
             .post("https://id.twitch.tv/oauth2/token")
             .query(&[
                 ("grant_type", "refresh_token"),
@@ -131,19 +169,98 @@ impl UserAuthorization {
     /// and this method calls itself again, only this time with `try_refresh_if_invalid=false`.
     ///
     /// (`try_refresh_if_invalid` should be `true` when called from outside)
+    ///
+    /// If `refresh_user_details_after` is set and the user details were queried more recently
+    /// than that, the token is only checked with Twitch's (cheaper) validation endpoint instead of
+    /// querying the user details from Helix.
     fn validate_still_valid_inner<'a>(
         &'a mut self,
+        http_client: &'a reqwest::Client,
         credentials: &'a TwitchApiClientCredentials,
+        circuit_breaker: &'a CircuitBreaker,
         recheck_twitch_auth_after: Duration,
+        refresh_user_details_after: Option<Duration>,
         try_refresh_if_invalid: bool,
     ) -> Pin<Box<dyn Future<Output = Result<(), ApiError>> + Send + 'a>> {
         // the boxed future is necessary because of the recursive call
         async move {
-            tracing::debug!("Executing auth validation for user {}: Querying Helix API for user", self.user_login);
-            // query helix for the user. success => token still valid, error => token expired/revoked
-            // the async {}.await acts like a try{} block (but try blocks are not in stable rust yet)
-            let user_api_response_result = async {
-                Ok(HTTP_CLIENT
+            let user_details_fresh = refresh_user_details_after.map_or(false, |refresh_after| {
+                (Utc::now() - self.user_details_last_updated)
+                    .to_std()
+                    .map_or(true, |age| age < refresh_after)
+            });
+            let validation_result = if user_details_fresh {
+                tracing::debug!("Executing auth validation for user {}: Validating token", self.user_login);
+                circuit_breaker.call(async {
+                    http_client
+                        .get("https://id.twitch.tv/oauth2/validate")
+                        .header(
+                            "Authorization",
+                            format!("OAuth {}", self.twitch_token.access_token),
+                        )
+                        .send()
+                        .await
+                        .map_err(ApiError::ValidateToken)?
+                        .error_for_status()
+                        .map_err(|e| {
+                            if e.status().unwrap() == StatusCode::UNAUTHORIZED {
+                                // token has expired or user has revoked authorization
+                                ApiError::Unauthorized
+                            } else {
+                                ApiError::ValidateToken(e)
+                            }
+                        })?
+                        .json::<TwitchValidateTokenResponse>()
+                        .await
+                        .map_err(ApiError::ValidateToken)
+                })
+                    .await
+                    .map(|response| {
+                        // the login is included for free, the other details are kept as they are
+                        self.user_login = response.login;
+                    })
+            } else {
+                self.query_user_details(http_client, credentials, circuit_breaker).await
+            };
+
+            match validation_result {
+                Ok(()) => {
+                    tracing::debug!("Executing auth validation for user {}: Success, connection still active", self.user_login);
+                    self.twitch_authorization_last_validated = Utc::now();
+                    Ok(())
+                }
+                Err(ApiError::Unauthorized) if try_refresh_if_invalid => {
+                    tracing::debug!("Executing auth validation for user {}: Failure! Unauthorized. Trying refresh", self.user_login);
+                    circuit_breaker.call(self.refresh_token(http_client, credentials)).boxed().await?;
+                    // recurse: try the above again, now that the token is successfully refreshed.
+                    self.validate_still_valid_inner(http_client, credentials, circuit_breaker, recheck_twitch_auth_after, refresh_user_details_after, false)
+                        .await
+                }
+                Err(e) => {
+                    tracing::debug!("Executing auth validation for user {}: Other error: {}", self.user_login, e);
+                    Err(e)
+                }
+            }
+        }
+            .boxed()
+    }
+
+    /// Query helix for the user and update the user details.
+    /// Success => token still valid, error => token expired/revoked
+    async fn query_user_details(
+        &mut self,
+        http_client: &reqwest::Client,
+        credentials: &TwitchApiClientCredentials,
+        circuit_breaker: &CircuitBreaker,
+    ) -> Result<(), ApiError> {
+        tracing::debug!(
+            "Executing auth validation for user {}: Querying Helix API for user",
+            self.user_login
+        );
+        // the async {}.await acts like a try{} block (but try blocks are not in stable rust yet)
+        let response = circuit_breaker
+            .call(async {
+                Ok(http_client
                     .get("https://api.twitch.tv/helix/users")
                     .header("Client-ID", &credentials.client_id)
                     .header(
@@ -167,38 +284,27 @@ impl UserAuthorization {
                     .map_err(ApiError::QueryUserDetails)?
                     .data
                     .0)
-            }
-                .await;
+            })
+            .await?;
 
-            match user_api_response_result {
-                Ok(response) => {
-                    tracing::debug!("Executing auth validation for user {}: Success, connection still active", self.user_login);
-                    self.twitch_authorization_last_validated = Utc::now();
-                    self.user_id = response.id;
-                    self.user_login = response.login;
-                    self.user_name = response.display_name;
-                    Ok(())
-                }
-                Err(ApiError::Unauthorized) if try_refresh_if_invalid => {
-                    tracing::debug!("Executing auth validation for user {}: Failure! Unauthorized. Trying refresh", self.user_login);
-                    self.refresh_token(credentials).boxed().await?;
-                    // recurse: try the above again, now that the token is successfully refreshed.
-                    self.validate_still_valid_inner(credentials, recheck_twitch_auth_after, false)
-                        .await
-                }
-                Err(e) => {
-                    tracing::debug!("Executing auth validation for user {}: Other error: {}", self.user_login, e);
-                    Err(e)
-                }
-            }
-        }
-            .boxed()
+        self.user_id = response.id;
+        self.user_login = response.login;
+        self.user_name = response.display_name;
+        self.user_details_last_updated = Utc::now();
+        Ok(())
     }
 
+    /// If the Twitch API is having an outage, the authorization is still accepted if it was
+    /// validated less than `recheck_twitch_auth_after` + `outage_grace` ago. A token that Twitch
+    /// rejects is never accepted.
     pub(crate) async fn validate_still_valid(
         &mut self,
+        http_client: &reqwest::Client,
         credentials: &TwitchApiClientCredentials,
+        circuit_breaker: &CircuitBreaker,
         recheck_twitch_auth_after: Duration,
+        refresh_user_details_after: Option<Duration>,
+        outage_grace: Duration,
     ) -> Result<(), ApiError> {
         if (Utc::now() - self.twitch_authorization_last_validated)
             .to_std()
@@ -213,7 +319,96 @@ impl UserAuthorization {
             return Ok(());
         }
 
-        self.validate_still_valid_inner(credentials, recheck_twitch_auth_after, true)
-            .await
+        let result = self
+            .validate_still_valid_inner(
+                http_client,
+                credentials,
+                circuit_breaker,
+                recheck_twitch_auth_after,
+                refresh_user_details_after,
+                true,
+            )
+            .await;
+        match result {
+            Err(e)
+                if is_transient_twitch_error(&e)
+                    && within_outage_grace(
+                        self.twitch_authorization_last_validated,
+                        Utc::now(),
+                        recheck_twitch_auth_after,
+                        outage_grace,
+                    ) =>
+            {
+                tracing::warn!(
+                    "Auth validation for user {} failed because of a Twitch API error, accepting the last successful validation: {}",
+                    self.user_login,
+                    e
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether the error means Twitch couldn't tell whether the token is valid, as opposed to the
+/// token being rejected.
+fn is_transient_twitch_error(e: &ApiError) -> bool {
+    e.is_twitch_api_outage() || matches!(e, ApiError::TwitchApiUnavailable)
+}
+
+fn within_outage_grace(
+    last_validated: DateTime<Utc>,
+    now: DateTime<Utc>,
+    recheck_twitch_auth_after: Duration,
+    outage_grace: Duration,
+) -> bool {
+    (now - last_validated)
+        .to_std()
+        .map_or(true, |age| age <= recheck_twitch_auth_after + outage_grace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        generate_access_token, is_transient_twitch_error, within_outage_grace,
+        RE_AUTHORIZATION_HEADER,
+    };
+    use crate::web::error::ApiError;
+    use chrono::Utc;
+    use std::time::Duration;
+
+    #[test]
+    fn generated_access_tokens_are_accepted() {
+        let access_token = generate_access_token();
+        assert_eq!(access_token.len(), 128);
+        let captures = RE_AUTHORIZATION_HEADER
+            .captures(&format!("Bearer {}", access_token))
+            .unwrap();
+        assert_eq!(&captures[1], access_token);
+
+        assert!(!RE_AUTHORIZATION_HEADER.is_match(&format!("Bearer {}0", access_token)));
+        assert!(!RE_AUTHORIZATION_HEADER.is_match(&format!("Bearer {}", &access_token[1..])));
+        assert!(!RE_AUTHORIZATION_HEADER.is_match(&access_token));
+    }
+
+    #[test]
+    fn recent_validations_are_trusted_during_twitch_outages() {
+        let hour = Duration::from_secs(60 * 60);
+        let now = Utc::now();
+        let validated = |minutes_ago| now - chrono::Duration::minutes(minutes_ago);
+        assert!(within_outage_grace(validated(90), now, hour, hour));
+        assert!(!within_outage_grace(validated(121), now, hour, hour));
+        // no grace
+        assert!(!within_outage_grace(
+            validated(61),
+            now,
+            hour,
+            Duration::ZERO
+        ));
+
+        assert!(is_transient_twitch_error(&ApiError::TwitchApiUnavailable));
+        // revoked tokens are never accepted
+        assert!(!is_transient_twitch_error(&ApiError::Unauthorized));
     }
 }
@@ -1,5 +1,7 @@
+use crate::config::TwitchApiClientCredentials;
 use crate::web::auth::{
-    HelixGetUserResponse, TwitchUserAccessToken, UserAuthorization, UserAuthorizationResponse,
+    generate_access_token, HelixGetUserResponse, TwitchUserAccessToken, UserAuthorization,
+    UserAuthorizationResponse,
 };
 use crate::web::error::ApiError;
 use crate::web::WebAppData;
@@ -8,10 +10,60 @@ use axum::extract::Query;
 use axum::{Extension, Json};
 use chrono::Utc;
 use http::StatusCode;
-use rand::distributions::Standard;
-use rand::Rng;
-use serde::Deserialize;
-use std::fmt::Write;
+use serde::{Deserialize, Serialize};
+
+/// Scopes requested from users when they log in. Only their identity is needed, which requires no
+/// scopes.
+const AUTHORIZE_SCOPES: &[&str] = &[];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetAuthorizeUrlQueryOptions {
+    /// Passed back to the redirect URI unchanged, for CSRF protection
+    state: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetAuthorizeUrlResponse {
+    authorize_url: String,
+    client_id: String,
+    redirect_uri: String,
+    scopes: &'static [&'static str],
+}
+
+fn authorize_url(credentials: &TwitchApiClientCredentials, state: Option<&str>) -> String {
+    let scope = AUTHORIZE_SCOPES.join(" ");
+    let mut params = vec![
+        ("client_id", credentials.client_id.as_str()),
+        ("redirect_uri", credentials.redirect_uri.as_str()),
+        ("response_type", "code"),
+        ("scope", scope.as_str()),
+    ];
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    reqwest::Url::parse_with_params("https://id.twitch.tv/oauth2/authorize", &params)
+        .unwrap() // base URL is valid
+        .into()
+}
+
+// GET /api/v2/auth/authorize-url?state=abcdef123456
+/// The Twitch URL users are sent to for logging in, built from the configured credentials so the
+/// frontend doesn't need to know them.
+pub async fn get_authorize_url(
+    Extension(app_data): Extension<WebAppData>,
+    query_options: Result<Query<GetAuthorizeUrlQueryOptions>, QueryRejection>,
+) -> Result<Json<GetAuthorizeUrlResponse>, ApiError> {
+    let Query(GetAuthorizeUrlQueryOptions { state }) =
+        query_options.map_err(|_| ApiError::InvalidQuery)?;
+
+    let credentials = &app_data.config.web.twitch_api_credentials;
+    Ok(Json(GetAuthorizeUrlResponse {
+        authorize_url: authorize_url(credentials, state.as_deref()),
+        client_id: credentials.client_id.clone(),
+        redirect_uri: credentials.redirect_uri.clone(),
+        scopes: AUTHORIZE_SCOPES,
+    }))
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CreateAuthTokenQueryOptions {
@@ -26,102 +78,104 @@ pub async fn create_token(
     let Query(CreateAuthTokenQueryOptions { code }) =
         query_options.map_err(|_| ApiError::InvalidQuery)?;
 
-    let user_access_token = crate::web::HTTP_CLIENT
-        .post("https://id.twitch.tv/oauth2/token")
-        .query(&[
-            (
-                "client_id",
-                app_data
-                    .config
-                    .web
-                    .twitch_api_credentials
-                    .client_id
-                    .as_str(),
-            ),
-            (
-                "client_secret",
-                app_data
-                    .config
-                    .web
-                    .twitch_api_credentials
-                    .client_secret
-                    .as_str(),
-            ),
-            (
-                "redirect_uri",
-                app_data
-                    .config
-                    .web
-                    .twitch_api_credentials
-                    .redirect_uri
-                    .as_str(),
-            ),
-            ("code", code.as_str()),
-            ("grant_type", "authorization_code"),
-        ])
-        .send()
-        .await
-        .map_err(ApiError::ExchangeCodeForAccessToken)?
-        .error_for_status()
-        .map_err(|e| {
-            if e.status().unwrap() == StatusCode::BAD_REQUEST {
-                ApiError::InvalidAuthorizationCode
-            } else {
-                ApiError::ExchangeCodeForAccessToken(e)
-            }
-        })?
-        .json::<TwitchUserAccessToken>()
-        .await
-        .map_err(ApiError::ExchangeCodeForAccessToken)?;
-
-    let user_api_response = crate::web::HTTP_CLIENT
-        .get("https://api.twitch.tv/helix/users")
-        .header(
-            "Client-ID",
+    let circuit_breaker = app_data.twitch_api_circuit_breaker;
+    let user_access_token = circuit_breaker
+        .call(async {
             app_data
-                .config
-                .web
-                .twitch_api_credentials
-                .client_id
-                .as_str(),
-        )
-        .header(
-            "Authorization",
-            format!("Bearer {}", user_access_token.access_token),
-        )
-        .send()
-        .await
-        .map_err(ApiError::QueryUserDetails)?
-        .error_for_status()
-        .map_err(ApiError::QueryUserDetails)?
-        .json::<HelixGetUserResponse>()
-        .await
-        .map_err(ApiError::QueryUserDetails)?
-        .data
-        .0;
-
-    // 512 bit random hex string
-    // thread_rng() is cryptographically safe
-    let access_token = rand::thread_rng().sample_iter(Standard).take(512 / 8).fold(
-        String::with_capacity(512 / 4),
-        |mut s, x: u8| {
-            // format as hex, padded with a leading 0 if needed (e.g. 0x0 -> "00", 0xFF -> "ff")
-            write!(&mut s, "{:02x}", x).unwrap();
-            s
-        },
-    );
+                .http_client
+                .post("https://id.twitch.tv/oauth2/token")
+                .query(&[
+                    (
+                        "client_id",
+                        app_data
+                            .config
+                            .web
+                            .twitch_api_credentials
+                            .client_id
+                            .as_str(),
+                    ),
+                    (
+                        "client_secret",
+                        app_data
+                            .config
+                            .web
+                            .twitch_api_credentials
+                            .client_secret
+                            .as_str(),
+                    ),
+                    (
+                        "redirect_uri",
+                        app_data
+                            .config
+                            .web
+                            .twitch_api_credentials
+                            .redirect_uri
+                            .as_str(),
+                    ),
+                    ("code", code.as_str()),
+                    ("grant_type", "authorization_code"),
+                ])
+                .send()
+                .await
+                .map_err(ApiError::ExchangeCodeForAccessToken)?
+                .error_for_status()
+                .map_err(|e| {
+                    if e.status().unwrap() == StatusCode::BAD_REQUEST {
+                        ApiError::InvalidAuthorizationCode
+                    } else {
+                        ApiError::ExchangeCodeForAccessToken(e)
+                    }
+                })?
+                .json::<TwitchUserAccessToken>()
+                .await
+                .map_err(ApiError::ExchangeCodeForAccessToken)
+        })
+        .await?;
+
+    let user_api_response = circuit_breaker
+        .call(async {
+            Ok(app_data
+                .http_client
+                .get("https://api.twitch.tv/helix/users")
+                .header(
+                    "Client-ID",
+                    app_data
+                        .config
+                        .web
+                        .twitch_api_credentials
+                        .client_id
+                        .as_str(),
+                )
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", user_access_token.access_token),
+                )
+                .send()
+                .await
+                .map_err(ApiError::QueryUserDetails)?
+                .error_for_status()
+                .map_err(ApiError::QueryUserDetails)?
+                .json::<HelixGetUserResponse>()
+                .await
+                .map_err(ApiError::QueryUserDetails)?
+                .data
+                .0)
+        })
+        .await?;
+
+    let access_token = generate_access_token();
 
     let now = Utc::now();
     let user_authorization = UserAuthorization {
         access_token,
         twitch_token: user_access_token,
         twitch_authorization_last_validated: now,
-        valid_until: now
-            + chrono::Duration::from_std(app_data.config.web.sessions_expire_after).unwrap(),
+        valid_until: now + app_data.config.web.sessions_expire_after(),
         user_id: user_api_response.id,
         user_login: user_api_response.login,
         user_name: user_api_response.display_name,
         user_profile_image_url: user_api_response.profile_image_url,
+        user_details_last_updated: now,
     };
 
     app_data
@@ -148,8 +202,7 @@ pub async fn extend_token(
     Extension(app_data): Extension<WebAppData>,
     Extension(mut authorization): Extension<UserAuthorization>,
 ) -> Result<Json<UserAuthorizationResponse>, ApiError> {
-    let new_expiry =
-        Utc::now() + chrono::Duration::from_std(app_data.config.web.sessions_expire_after).unwrap();
+    let new_expiry = Utc::now() + app_data.config.web.sessions_expire_after();
     authorization.valid_until = new_expiry;
 
     app_data
@@ -164,6 +217,18 @@ pub async fn extend_token(
     )))
 }
 
+// GET /api/v2/auth/validate
+pub async fn validate_token(
+    Extension(app_data): Extension<WebAppData>,
+    Extension(authorization): Extension<UserAuthorization>,
+) -> Json<UserAuthorizationResponse> {
+    // the auth middleware has already validated the token (and updated the user details if needed)
+    Json(UserAuthorizationResponse::from_auth(
+        &authorization,
+        app_data.config.web.recheck_twitch_auth_after,
+    ))
+}
+
 // POST /api/v2/auth/revoke
 pub async fn revoke_token(
     Extension(app_data): Extension<WebAppData>,
@@ -176,3 +241,23 @@ pub async fn revoke_token(
         .map_err(ApiError::AuthorizationRevokeFailed)?;
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::authorize_url;
+    use crate::config::TwitchApiClientCredentials;
+
+    #[test]
+    fn authorize_url_uses_the_configured_credentials() {
+        let credentials = TwitchApiClientCredentials {
+            client_id: "abc".to_owned(),
+            client_secret: "def".to_owned(),
+            redirect_uri: "https://recent-messages.robotty.de/authorized".to_owned(),
+        };
+        assert_eq!(
+            authorize_url(&credentials, Some("a1b2c3")),
+            "https://id.twitch.tv/oauth2/authorize?client_id=abc&redirect_uri=https%3A%2F%2Frecent-messages.robotty.de%2Fauthorized&response_type=code&scope=&state=a1b2c3"
+        );
+        assert!(!authorize_url(&credentials, None).contains("state="));
+    }
+}
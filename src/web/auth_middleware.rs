@@ -1,14 +1,10 @@
+use crate::web::auth::RE_AUTHORIZATION_HEADER;
 use crate::web::error::ApiError;
 use crate::web::WebAppData;
 use axum::middleware::Next;
 use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
 use http::Request;
-use lazy_static::lazy_static;
-use regex::Regex;
-
-lazy_static! {
-    static ref RE_AUTHORIZATION_HEADER: Regex = Regex::new("^Bearer ([0-9a-f]{128})$").unwrap();
-}
 
 pub async fn with_authorization<B>(
     mut req: Request<B>,
@@ -44,11 +40,27 @@ pub async fn with_authorization<B>(
     let pre_validation_auth = authorization.clone();
     authorization
         .validate_still_valid(
+            app_data.http_client,
             &app_data.config.web.twitch_api_credentials,
+            app_data.twitch_api_circuit_breaker,
             app_data.config.web.recheck_twitch_auth_after,
+            app_data
+                .config
+                .web
+                .prefer_cached_user_details
+                .then_some(app_data.config.web.refresh_user_details_after),
+            app_data.config.web.twitch_auth_outage_grace,
         )
         .await?;
 
+    if app_data.config.web.sliding_sessions {
+        authorization.valid_until = slide_session(
+            authorization.valid_until,
+            Utc::now(),
+            app_data.config.web.sessions_expire_after(),
+        );
+    }
+
     if pre_validation_auth != authorization {
         app_data
             .data_storage
@@ -61,3 +73,84 @@ pub async fn with_authorization<B>(
 
     Ok(next.run(req).await)
 }
+
+/// The new expiry of a session that is used at `now` with `web.sliding_sessions`. It is only
+/// extended once less than half the session lifetime is left, otherwise we would write to the
+/// database on every request.
+fn slide_session(
+    valid_until: DateTime<Utc>,
+    now: DateTime<Utc>,
+    sessions_expire_after: chrono::Duration,
+) -> DateTime<Utc> {
+    if valid_until - now < sessions_expire_after / 2 {
+        now + sessions_expire_after
+    } else {
+        valid_until
+    }
+}
+
+/// Only lets requests through that carry `web.admin_token` as a bearer token. If no admin token
+/// is configured, the admin endpoints don't exist.
+pub async fn with_admin_token<B>(
+    req: Request<B>,
+    next: Next<B>,
+    app_data: WebAppData,
+) -> impl IntoResponse {
+    let admin_token = match &app_data.config.web.admin_token {
+        Some(admin_token) => admin_token,
+        None => return Err(ApiError::NotFound),
+    };
+
+    let auth_header = match req.headers().get(http::header::AUTHORIZATION) {
+        Some(auth_header) => auth_header.as_bytes(),
+        None => return Err(ApiError::MissingHeader(http::header::AUTHORIZATION)),
+    };
+    let expected = format!("Bearer {}", admin_token);
+    if !constant_time_eq(auth_header, expected.as_bytes()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how much of the token
+/// matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::slide_session;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn sessions_are_extended_once_half_of_the_lifetime_is_left() {
+        let now = Utc.with_ymd_and_hms(2020, 7, 29, 22, 17, 38).unwrap();
+        let sessions_expire_after = Duration::days(7);
+
+        // more than half left: unchanged, so the database isn't written on every request
+        let valid_until = now + Duration::days(4);
+        assert_eq!(
+            slide_session(valid_until, now, sessions_expire_after),
+            valid_until
+        );
+        let valid_until = now + Duration::hours(84);
+        assert_eq!(
+            slide_session(valid_until, now, sessions_expire_after),
+            valid_until
+        );
+
+        // less than half left: valid for the full lifetime again
+        let valid_until = now + Duration::days(3);
+        assert_eq!(
+            slide_session(valid_until, now, sessions_expire_after),
+            now + sessions_expire_after
+        );
+        let valid_until = now + Duration::seconds(1);
+        assert_eq!(
+            slide_session(valid_until, now, sessions_expire_after),
+            now + sessions_expire_after
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use crate::config::ExportOption;
+use crate::web::auth::UserAuthorization;
+use crate::web::{ApiError, WebAppData};
+use axum::extract::rejection::JsonRejection;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelSettings {
+    /// Applied to every request for the channel's messages, regardless of what the client requests
+    forced_export_options: HashSet<ExportOption>,
+}
+
+// GET /api/v2/channel-settings
+pub async fn get_channel_settings(
+    Extension(authorization): Extension<UserAuthorization>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<ChannelSettings>, ApiError> {
+    let forced_export_options = app_data
+        .data_storage
+        .get_forced_export_options(&authorization.user_login)
+        .await
+        .map_err(ApiError::GetChannelSettings)?;
+
+    Ok(Json(ChannelSettings {
+        forced_export_options,
+    }))
+}
+
+// POST /api/v2/channel-settings
+/// Replaces the settings of the authorized user's channel, responding with the new settings.
+pub async fn set_channel_settings(
+    Extension(authorization): Extension<UserAuthorization>,
+    Extension(app_data): Extension<WebAppData>,
+    settings: Result<Json<ChannelSettings>, JsonRejection>,
+) -> Result<Json<ChannelSettings>, ApiError> {
+    let Json(settings) = settings.map_err(|_| ApiError::InvalidPayload)?;
+
+    app_data
+        .data_storage
+        .set_forced_export_options(&authorization.user_login, &settings.forced_export_options)
+        .await
+        .map_err(ApiError::SetChannelSettings)?;
+
+    Ok(Json(settings))
+}
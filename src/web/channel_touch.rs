@@ -0,0 +1,264 @@
+use crate::config::Config;
+use crate::db::DataStorage;
+use crate::irc_listener::IrcListener;
+use futures::stream::{self, StreamExt};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+lazy_static! {
+    static ref CHANNEL_TOUCHES_DROPPED: IntCounter = register_int_counter!(
+        "recentmessages_channel_touches_dropped",
+        "Number of requested channels that were not added/touched in the database because too many joins were pending"
+    )
+    .unwrap();
+}
+
+/// Requested channels that are waiting for their join to be confirmed, and when channels were
+/// last added/touched in the database.
+#[derive(Debug, Default)]
+struct ChannelTouches {
+    /// When each channel was first requested while it wasn't joined yet
+    pending_joins: HashMap<String, Instant>,
+    last_touched: HashMap<String, Instant>,
+}
+
+impl ChannelTouches {
+    /// Whether the channel should be touched, in which case it is recorded as touched at `now`.
+    fn start_touch(&mut self, channel_login: &str, now: Instant, debounce: Duration) -> bool {
+        self.pending_joins.remove(channel_login);
+        match self.last_touched.get(channel_login) {
+            Some(touched_at) if now.duration_since(*touched_at) < debounce => false,
+            _ => {
+                self.last_touched.insert(channel_login.to_owned(), now);
+                true
+            }
+        }
+    }
+
+    /// Touches the channel again on its next request.
+    fn touch_failed(&mut self, channel_login: &str) {
+        self.last_touched.remove(channel_login);
+    }
+
+    /// Returns whether the channel is pending now, or false if too many joins are pending.
+    fn add_pending(&mut self, channel_login: &str, now: Instant, max_pending: usize) -> bool {
+        if self.pending_joins.contains_key(channel_login) {
+            return true;
+        }
+        if self.pending_joins.len() >= max_pending {
+            return false;
+        }
+        self.pending_joins.insert(channel_login.to_owned(), now);
+        true
+    }
+
+    /// Stops waiting for the joins that were requested at least `timeout` ago and checked at
+    /// `checked_at`, and forgets touches that are debounced no longer.
+    fn prune(&mut self, now: Instant, checked_at: Instant, timeout: Duration, debounce: Duration) {
+        self.pending_joins.retain(|_, requested_at| {
+            *requested_at > checked_at || now.duration_since(*requested_at) < timeout
+        });
+        self.last_touched
+            .retain(|_, touched_at| now.duration_since(*touched_at) < debounce);
+    }
+}
+
+/// Adds/touches requested channels in the database. Channels that are already joined are touched
+/// right away (at most once per `app.channel_touch_debounce`), the others are checked together
+/// every `web.background_join_recheck_every` until their join is confirmed, so a flood of
+/// requests for channels that can't be joined doesn't hold up the busy ones.
+#[derive(Debug)]
+pub struct ChannelTouchQueue {
+    touches: Arc<Mutex<ChannelTouches>>,
+    /// Limits the concurrent database queries to `web.channel_touch_workers`
+    permits: Arc<Semaphore>,
+    data_storage: &'static DataStorage,
+    config: &'static Config,
+}
+
+impl ChannelTouchQueue {
+    pub fn start(
+        data_storage: &'static DataStorage,
+        irc_listener: &'static IrcListener,
+        config: &'static Config,
+        shutdown_signal: CancellationToken,
+    ) -> ChannelTouchQueue {
+        let queue = ChannelTouchQueue {
+            touches: Arc::new(Mutex::new(ChannelTouches::default())),
+            permits: Arc::new(Semaphore::new(config.web.channel_touch_workers)),
+            data_storage,
+            config,
+        };
+        tokio::spawn(run_pending_joins_checker(
+            queue.clone_handle(),
+            irc_listener,
+            shutdown_signal,
+        ));
+        queue
+    }
+
+    fn clone_handle(&self) -> ChannelTouchQueue {
+        ChannelTouchQueue {
+            touches: self.touches.clone(),
+            permits: self.permits.clone(),
+            data_storage: self.data_storage,
+            config: self.config,
+        }
+    }
+
+    /// Adds/touches the channel if its join is confirmed, otherwise once it is. Dropped if too
+    /// many joins are pending already, the channel then gets added on the client's next request.
+    pub fn push(&self, channel_login: String, is_confirmed_joined: bool) {
+        if is_confirmed_joined {
+            self.touch(channel_login);
+            return;
+        }
+
+        let is_pending = self.touches.lock().unwrap().add_pending(
+            &channel_login,
+            Instant::now(),
+            self.config.web.channel_touch_queue_size,
+        );
+        if !is_pending {
+            tracing::debug!(
+                "Too many pending joins, not adding/touching channel {}",
+                channel_login
+            );
+            CHANNEL_TOUCHES_DROPPED.inc();
+        }
+    }
+
+    fn touch(&self, channel_login: String) {
+        // the database only updates the last access once per debounce as well, touching twice as
+        // often here keeps the channel from expiring in between
+        let debounce = self.config.app.channel_touch_debounce / 2;
+        if !self
+            .touches
+            .lock()
+            .unwrap()
+            .start_touch(&channel_login, Instant::now(), debounce)
+        {
+            return;
+        }
+
+        let queue = self.clone_handle();
+        tokio::spawn(async move {
+            let _permit = queue.permits.acquire().await.unwrap(); // semaphore is never closed
+            tracing::trace!("Adding/touching channel: {}", channel_login);
+            let res = queue
+                .data_storage
+                .touch_or_add_channel(&channel_login, queue.config.app.channel_touch_debounce)
+                .await;
+            if let Err(e) = res {
+                tracing::error!("Failed to touch_or_add_channel: {}", e);
+                queue.touches.lock().unwrap().touch_failed(&channel_login);
+            }
+        });
+    }
+}
+
+/// Checks all pending joins every `web.background_join_recheck_every`, and touches the channels
+/// whose join was confirmed. Joins are waited for up to `web.background_join_timeout`.
+async fn run_pending_joins_checker(
+    queue: ChannelTouchQueue,
+    irc_listener: &'static IrcListener,
+    shutdown_signal: CancellationToken,
+) {
+    let web_config = &queue.config.web;
+    let worker = async {
+        loop {
+            let checked_at = Instant::now();
+            let pending_joins = queue
+                .touches
+                .lock()
+                .unwrap()
+                .pending_joins
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            let confirmed = stream::iter(pending_joins)
+                .map(|channel_login| async move {
+                    let is_confirmed = irc_listener.is_join_confirmed(channel_login.clone()).await;
+                    (channel_login, is_confirmed)
+                })
+                .buffer_unordered(web_config.channel_touch_workers)
+                .filter_map(|(channel_login, is_confirmed)| async move {
+                    is_confirmed.then_some(channel_login)
+                })
+                .collect::<Vec<_>>()
+                .await;
+            for channel_login in confirmed {
+                queue.touch(channel_login);
+            }
+
+            queue.touches.lock().unwrap().prune(
+                Instant::now(),
+                checked_at,
+                web_config.background_join_timeout,
+                queue.config.app.channel_touch_debounce / 2,
+            );
+            tokio::time::sleep(web_config.background_join_recheck_every).await;
+        }
+    };
+
+    tokio::select! {
+        _ = worker => {},
+        _ = shutdown_signal.cancelled() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelTouches;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn pending_joins_are_bounded_and_expire() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(20);
+        let debounce = Duration::from_secs(60);
+        let mut touches = ChannelTouches::default();
+        assert!(touches.add_pending("pajlada", start, 2));
+        assert!(touches.add_pending("pajlada", start, 2));
+        assert!(touches.add_pending("forsen", start, 2));
+        assert!(!touches.add_pending("xqcow", start, 2));
+
+        // a confirmed join is not pending anymore
+        assert!(touches.start_touch("forsen", start, debounce));
+        assert!(touches.add_pending("xqcow", start + Duration::from_secs(10), 2));
+
+        touches.prune(start + timeout, start + timeout, timeout, debounce);
+        assert_eq!(touches.pending_joins.len(), 1);
+        assert!(touches.pending_joins.contains_key("xqcow"));
+
+        // joins are checked at least once
+        let requested_at = start + timeout * 2;
+        assert!(touches.add_pending("pajlada", requested_at, 2));
+        touches.prune(requested_at, start + timeout, Duration::ZERO, debounce);
+        assert!(touches.pending_joins.contains_key("pajlada"));
+        touches.prune(requested_at, requested_at, Duration::ZERO, debounce);
+        assert!(!touches.pending_joins.contains_key("pajlada"));
+    }
+
+    #[test]
+    fn touches_are_debounced() {
+        let start = Instant::now();
+        let debounce = Duration::from_secs(60);
+        let mut touches = ChannelTouches::default();
+        assert!(touches.start_touch("pajlada", start, debounce));
+        assert!(!touches.start_touch("pajlada", start + Duration::from_secs(59), debounce));
+        assert!(touches.start_touch("pajlada", start + debounce, debounce));
+
+        // failed touches are retried on the next request
+        touches.touch_failed("pajlada");
+        assert!(touches.start_touch("pajlada", start + debounce, debounce));
+
+        touches.prune(start + debounce * 2, start, Duration::ZERO, debounce);
+        assert!(touches.last_touched.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::config::WebConfig;
+use crate::web::error::ApiError;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref TWITCH_API_CIRCUIT_OPEN: IntGauge = register_int_gauge!(
+        "recentmessages_twitch_api_circuit_open",
+        "1 if requests to the Twitch API are currently being short-circuited because of repeated failures"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Requests go through. Counts the consecutive failures.
+    Closed { consecutive_failures: u32 },
+    /// Requests fail immediately until `until`, then a single probe request is let through.
+    Open { until: Instant, cooldown: Duration },
+    /// A probe request is running, other requests fail immediately. If the probe hangs for longer
+    /// than `cooldown` (e.g. because the request was cancelled), another probe is allowed.
+    HalfOpen {
+        probe_started: Instant,
+        cooldown: Duration,
+    },
+}
+
+/// Stops sending requests to the Twitch API for a while after it failed repeatedly, so requests
+/// fail fast during Twitch outages instead of each waiting for a timeout. The cooldown doubles
+/// every time the probe request after it fails, up to a maximum.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    failure_threshold: u32,
+    initial_cooldown: Duration,
+    max_cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &WebConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            state: Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            }),
+            failure_threshold: config.twitch_api_failure_threshold,
+            initial_cooldown: config.twitch_api_cooldown,
+            max_cooldown: config.twitch_api_max_cooldown,
+        }
+    }
+
+    /// Runs `request` unless the circuit is open, in which case
+    /// `ApiError::TwitchApiUnavailable` is returned immediately.
+    pub async fn call<T>(
+        &self,
+        request: impl Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        if !self.before_request(Instant::now()) {
+            return Err(ApiError::TwitchApiUnavailable);
+        }
+        let result = request.await;
+        let failed = matches!(&result, Err(e) if e.is_twitch_api_outage());
+        self.after_request(failed, Instant::now());
+        result
+    }
+
+    /// Whether a request may be made now.
+    fn before_request(&self, now: Instant) -> bool {
+        if self.failure_threshold == 0 {
+            return true;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until, cooldown } if now >= until => {
+                *state = State::HalfOpen {
+                    probe_started: now,
+                    cooldown,
+                };
+                true
+            }
+            State::HalfOpen {
+                probe_started,
+                cooldown,
+            } if now >= probe_started + cooldown => {
+                *state = State::HalfOpen {
+                    probe_started: now,
+                    cooldown,
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn after_request(&self, failed: bool, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let new_state = match (*state, failed) {
+            (_, false) => State::Closed {
+                consecutive_failures: 0,
+            },
+            (
+                State::Closed {
+                    consecutive_failures,
+                },
+                true,
+            ) => {
+                let consecutive_failures = consecutive_failures + 1;
+                if consecutive_failures >= self.failure_threshold {
+                    tracing::warn!(
+                        "{} consecutive Twitch API requests failed, not sending any requests for {:?}",
+                        consecutive_failures,
+                        self.initial_cooldown
+                    );
+                    State::Open {
+                        until: now + self.initial_cooldown,
+                        cooldown: self.initial_cooldown,
+                    }
+                } else {
+                    State::Closed {
+                        consecutive_failures,
+                    }
+                }
+            }
+            (State::HalfOpen { cooldown, .. }, true) => {
+                let cooldown = Duration::min(cooldown * 2, self.max_cooldown);
+                State::Open {
+                    until: now + cooldown,
+                    cooldown,
+                }
+            }
+            // a request that started before the circuit opened
+            (open @ State::Open { .. }, true) => open,
+        };
+        let is_closed = matches!(new_state, State::Closed { .. });
+        if is_closed && !matches!(*state, State::Closed { .. }) {
+            tracing::info!("Twitch API requests are succeeding again");
+        }
+        *state = new_state;
+        TWITCH_API_CIRCUIT_OPEN.set(!is_closed as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, State};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn circuit_breaker() -> CircuitBreaker {
+        CircuitBreaker {
+            state: Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            }),
+            failure_threshold: 3,
+            initial_cooldown: Duration::from_secs(10),
+            max_cooldown: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_and_recovers() {
+        let breaker = circuit_breaker();
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+
+        // a success in between resets the count
+        breaker.after_request(true, start);
+        breaker.after_request(true, start);
+        breaker.after_request(false, start);
+        breaker.after_request(true, start);
+        breaker.after_request(true, start);
+        assert!(breaker.before_request(start));
+        breaker.after_request(true, start);
+        assert!(!breaker.before_request(secs(9)));
+
+        // one probe after the cooldown, which fails and doubles the cooldown
+        assert!(breaker.before_request(secs(10)));
+        assert!(!breaker.before_request(secs(10)));
+        breaker.after_request(true, secs(10));
+        assert!(!breaker.before_request(secs(29)));
+
+        // capped at max_cooldown
+        assert!(breaker.before_request(secs(30)));
+        breaker.after_request(true, secs(30));
+        assert!(!breaker.before_request(secs(59)));
+        assert!(breaker.before_request(secs(60)));
+
+        // successful probe closes the circuit
+        breaker.after_request(false, secs(60));
+        assert!(breaker.before_request(secs(60)));
+        assert!(breaker.before_request(secs(60)));
+    }
+
+    #[test]
+    fn stuck_probe_is_replaced_after_cooldown() {
+        let breaker = circuit_breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.after_request(true, start);
+        }
+        let probe_time = start + Duration::from_secs(10);
+        assert!(breaker.before_request(probe_time));
+        // the probe never finishes
+        assert!(!breaker.before_request(probe_time + Duration::from_secs(9)));
+        assert!(breaker.before_request(probe_time + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::WebConfig;
+use crate::message_rate::{self, SharedChannelRateTracker};
+use crate::web::WebAppData;
+use axum::extract::ConnectInfo;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use http::{HeaderMap, Request};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use tokio_util::sync::CancellationToken;
+
+/// Counts requests per client IP over the last minute, to see which clients drive the traffic.
+/// Only the counts of the last minute are kept.
+#[derive(Debug)]
+pub struct ClientIpTracker {
+    rates: SharedChannelRateTracker,
+    trusted_proxies: HashSet<IpAddr>,
+    top_n: usize,
+}
+
+impl ClientIpTracker {
+    pub fn start(config: &WebConfig, shutdown_signal: CancellationToken) -> ClientIpTracker {
+        let rates = message_rate::new_shared_tracker();
+        tokio::spawn(message_rate::run_window_advancer(
+            rates.clone(),
+            shutdown_signal,
+        ));
+        ClientIpTracker {
+            rates,
+            trusted_proxies: config.trusted_proxies.iter().copied().collect(),
+            top_n: config.client_ip_top_n,
+        }
+    }
+
+    /// The clients with the most requests per second over the last minute, busiest first.
+    pub fn top_clients(&self) -> Vec<(String, f64)> {
+        self.rates
+            .lock()
+            .unwrap()
+            .top_channels(self.top_n)
+            .into_iter()
+            .map(|(ip, rate)| (ip.to_owned(), rate))
+            .collect()
+    }
+}
+
+/// The IP of the client that made the request. `peer` is the address of the connection, which is
+/// unknown for unix sockets. `X-Forwarded-For` is only used if the connection comes from a trusted
+/// proxy (or a unix socket, which is always a local reverse proxy), in which case the rightmost
+/// address that is not a trusted proxy is the client.
+fn client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &HashSet<IpAddr>,
+) -> Option<IpAddr> {
+    if let Some(peer) = peer {
+        if !trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+    }
+
+    let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+    for address in forwarded_for.into_iter().rev() {
+        match address {
+            Ok(address) if trusted_proxies.contains(&address) => continue,
+            Ok(address) => return Some(address),
+            // can't trust anything left of an invalid entry
+            Err(_) => return None,
+        }
+    }
+    // all hops were trusted proxies
+    peer
+}
+
+/// Logs and counts the client IP of every request, if `web.track_client_ips` is enabled.
+pub async fn track_client_ip<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let app_data = req.extensions().get::<WebAppData>().unwrap();
+    if let Some(tracker) = app_data.client_ip_tracker {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        let ip = client_ip(peer, req.headers(), &tracker.trusted_proxies);
+        let ip = ip.map_or_else(|| "unknown".to_owned(), |ip| ip.to_string());
+        tracing::info!(client_ip = %ip, "{} {}", req.method(), req.uri().path());
+        tracker.rates.lock().unwrap().record(&ip);
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client_ip;
+    use http::{HeaderMap, HeaderValue};
+    use std::collections::HashSet;
+    use std::net::IpAddr;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_trusted_proxies() {
+        let trusted_proxies = HashSet::from([ip("10.0.0.1"), ip("10.0.0.2")]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"),
+        );
+
+        // the client can put anything into the header, only the part appended by our proxies counts
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.1")), &headers, &trusted_proxies),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            client_ip(None, &headers, &trusted_proxies),
+            Some(ip("1.2.3.4"))
+        );
+        // not from a trusted proxy
+        assert_eq!(
+            client_ip(Some(ip("5.5.5.5")), &headers, &trusted_proxies),
+            Some(ip("5.5.5.5"))
+        );
+        // no header
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.1")), &HeaderMap::new(), &trusted_proxies),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(client_ip(None, &HeaderMap::new(), &trusted_proxies), None);
+    }
+}
@@ -1,8 +1,10 @@
 use crate::db::StorageError;
+use crate::web::request_id::current_request_id;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use http::header::HeaderName;
-use http::StatusCode;
+use http::{header, HeaderValue, Method, StatusCode};
+use itertools::Itertools;
 use serde::Serialize;
 use thiserror::Error;
 use tracing::error;
@@ -13,20 +15,29 @@ pub enum ApiError {
     NotFound,
     #[error("Request Timeout")]
     RequestTimeout,
+    /// Carries the methods the route does allow, for the `Allow` header
     #[error("Method Not Allowed")]
-    MethodNotAllowed,
+    MethodNotAllowed(&'static [Method]),
     #[error("Invalid or missing path parameters")]
     InvalidPath,
     #[error("Invalid or missing query parameters")]
     InvalidQuery,
     #[error("Invalid or missing payload in request body")]
     InvalidPayload,
+    #[error("Query string is longer than {0} bytes")]
+    QueryTooLong(usize),
+    #[error("Request body is larger than {0} bytes")]
+    PayloadTooLarge(usize),
     #[error("Header value for Header `{0}` was not valid UTF-8")]
     HeaderValueNotUtf8(HeaderName),
     #[error("Missing header `{0}`")]
     MissingHeader(HeaderName),
+    #[error("The `{0}` option is disabled on this instance")]
+    ExportOptionDisabled(&'static str),
+    #[error("Invalid log filter: {0}")]
+    InvalidLogFilter(tracing_subscriber::filter::ParseError),
     #[error("Invalid channel login: {0}")]
-    InvalidChannelLogin(twitch_irc::validate::Error),
+    InvalidChannelLogin(String),
     #[error("The channel login `{0}` is excluded from this service")]
     ChannelIgnored(String),
     #[error("Provided `code` could not be exchanged for a token, it is not valid")]
@@ -35,6 +46,8 @@ pub enum ApiError {
     MalformedAuthorizationHeader,
     #[error("Unauthorized (access token expired or invalid)")]
     Unauthorized,
+    #[error("{0}")]
+    Forbidden(&'static str),
     #[error("Failed to exchange code for an access token: {0}")]
     ExchangeCodeForAccessToken(reqwest::Error),
     #[error("Failed to query details about authorized user: {0}")]
@@ -45,21 +58,53 @@ pub enum ApiError {
     UpdateUserAuthorization(StorageError),
     #[error("Failed to query database for access token: {0}")]
     QueryAccessToken(StorageError),
+    #[error("Failed to validate Twitch OAuth access token: {0}")]
+    ValidateToken(reqwest::Error),
     #[error("Failed to refresh Twitch OAuth access token: {0}")]
     FailedTwitchAccessTokenRefresh(reqwest::Error),
+    #[error("Failed to query channel settings: {0}")]
+    GetChannelSettings(StorageError),
+    #[error("Failed to update channel settings: {0}")]
+    SetChannelSettings(StorageError),
     #[error("Failed to revoke authorization: {0}")]
     AuthorizationRevokeFailed(StorageError),
+    #[error("Failed to revoke all sessions of the user: {0}")]
+    RevokeAllSessions(StorageError),
     #[error("Failed to get channel's ignored status: {0}")]
     GetChannelIgnored(StorageError),
     #[error("Failed to set channel's ignored status: {0}")]
     SetChannelIgnored(StorageError),
     #[error("Failed get a channel's messages: {0}")]
     GetMessages(StorageError),
+    #[error("Failed to count a channel's messages: {0}")]
+    CountMessages(StorageError),
     #[error("Failed to purge a channel's messages: {0}")]
     PurgeMessages(StorageError),
+    #[error("Failed to remove a channel from the channels to join: {0}")]
+    ForgetChannel(StorageError),
+    #[error("The database storing this channel's messages is currently unavailable")]
+    StorageUnavailable,
+    #[error("The Twitch API is currently unavailable, try again later")]
+    TwitchApiUnavailable,
+    #[error("The service is overloaded, try again later")]
+    ServiceUnavailable,
 }
 
 impl ApiError {
+    /// Whether this error means that the Twitch API failed (could not be reached, or responded
+    /// with a server error), as opposed to rejecting the request.
+    pub fn is_twitch_api_outage(&self) -> bool {
+        match self {
+            ApiError::ExchangeCodeForAccessToken(e)
+            | ApiError::QueryUserDetails(e)
+            | ApiError::FailedTwitchAccessTokenRefresh(e)
+            | ApiError::ValidateToken(e) => {
+                e.status().map_or(true, |status| status.is_server_error())
+            }
+            _ => false,
+        }
+    }
+
     fn status_code(&self) -> StatusCode {
         match self {
             ApiError::ExchangeCodeForAccessToken(_)
@@ -68,24 +113,38 @@ impl ApiError {
             | ApiError::UpdateUserAuthorization(_)
             | ApiError::QueryAccessToken(_)
             | ApiError::FailedTwitchAccessTokenRefresh(_)
+            | ApiError::ValidateToken(_)
             | ApiError::AuthorizationRevokeFailed(_)
+            | ApiError::RevokeAllSessions(_)
             | ApiError::GetChannelIgnored(_)
             | ApiError::SetChannelIgnored(_)
+            | ApiError::GetChannelSettings(_)
+            | ApiError::SetChannelSettings(_)
             | ApiError::GetMessages(_)
-            | ApiError::PurgeMessages(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            | ApiError::CountMessages(_)
+            | ApiError::PurgeMessages(_)
+            | ApiError::ForgetChannel(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TwitchApiUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             ApiError::NotFound => StatusCode::NOT_FOUND,
             ApiError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
-            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
             ApiError::InvalidPath => StatusCode::BAD_REQUEST,
             ApiError::InvalidQuery => StatusCode::BAD_REQUEST,
             ApiError::InvalidPayload => StatusCode::BAD_REQUEST,
+            ApiError::QueryTooLong(_) => StatusCode::BAD_REQUEST,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             ApiError::HeaderValueNotUtf8(_) => StatusCode::BAD_REQUEST,
             ApiError::MissingHeader(_) => StatusCode::BAD_REQUEST,
+            ApiError::ExportOptionDisabled(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidLogFilter(_) => StatusCode::BAD_REQUEST,
             ApiError::InvalidChannelLogin(_) => StatusCode::BAD_REQUEST,
             ApiError::ChannelIgnored(_) => StatusCode::FORBIDDEN,
             ApiError::InvalidAuthorizationCode => StatusCode::BAD_REQUEST,
             ApiError::MalformedAuthorizationHeader => StatusCode::BAD_REQUEST,
             ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
         }
     }
 
@@ -99,11 +158,17 @@ impl ApiError {
             | ApiError::UpdateUserAuthorization(_)
             | ApiError::QueryAccessToken(_)
             | ApiError::FailedTwitchAccessTokenRefresh(_)
+            | ApiError::ValidateToken(_)
             | ApiError::AuthorizationRevokeFailed(_)
+            | ApiError::RevokeAllSessions(_)
             | ApiError::GetChannelIgnored(_)
             | ApiError::SetChannelIgnored(_)
+            | ApiError::GetChannelSettings(_)
+            | ApiError::SetChannelSettings(_)
             | ApiError::GetMessages(_)
-            | ApiError::PurgeMessages(_) => "Internal Server Error".to_owned(),
+            | ApiError::CountMessages(_)
+            | ApiError::PurgeMessages(_)
+            | ApiError::ForgetChannel(_) => "Internal Server Error".to_owned(),
             rest => format!("{}", rest),
         }
     }
@@ -116,24 +181,38 @@ impl ApiError {
             | ApiError::UpdateUserAuthorization(_)
             | ApiError::QueryAccessToken(_)
             | ApiError::FailedTwitchAccessTokenRefresh(_)
+            | ApiError::ValidateToken(_)
             | ApiError::AuthorizationRevokeFailed(_)
+            | ApiError::RevokeAllSessions(_)
             | ApiError::GetChannelIgnored(_)
             | ApiError::SetChannelIgnored(_)
+            | ApiError::GetChannelSettings(_)
+            | ApiError::SetChannelSettings(_)
             | ApiError::GetMessages(_)
-            | ApiError::PurgeMessages(_) => "internal_server_error",
+            | ApiError::CountMessages(_)
+            | ApiError::PurgeMessages(_)
+            | ApiError::ForgetChannel(_) => "internal_server_error",
+            ApiError::StorageUnavailable => "storage_unavailable",
+            ApiError::TwitchApiUnavailable => "twitch_api_unavailable",
+            ApiError::ServiceUnavailable => "service_unavailable",
             ApiError::NotFound => "not_found",
             ApiError::RequestTimeout => "request_timeout",
-            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
             ApiError::InvalidPath => "invalid_path",
             ApiError::InvalidQuery => "invalid_query",
             ApiError::InvalidPayload => "invalid_payload",
+            ApiError::QueryTooLong(_) => "query_too_long",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
             ApiError::HeaderValueNotUtf8(_) => "header_value_not_utf8",
             ApiError::MissingHeader(_) => "missing_header",
+            ApiError::ExportOptionDisabled(_) => "export_option_disabled",
+            ApiError::InvalidLogFilter(_) => "invalid_log_filter",
             ApiError::InvalidChannelLogin(_) => "invalid_channel_login",
             ApiError::ChannelIgnored(_) => "channel_ignored",
             ApiError::InvalidAuthorizationCode => "invalid_authorization_code",
             ApiError::MalformedAuthorizationHeader => "malformed_authorization_header",
             ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
         }
     }
 }
@@ -144,24 +223,40 @@ struct ApiErrorResponse {
     status_message: &'static str,
     error: String,
     error_code: &'static str,
+    /// Only present for internal server errors, so users can refer to the request when
+    /// reporting the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         // If error is in the 5xx range, log it.
-        if self.status_code().is_server_error() {
+        let request_id = if self.status_code().is_server_error() {
             error!("Returning Internal Server Error to a user: {}", self);
-        }
+            current_request_id()
+        } else {
+            None
+        };
 
-        (
+        let mut response = (
             self.status_code(),
             Json(ApiErrorResponse {
                 status: self.status_code().as_u16(),
                 status_message: self.status_code().canonical_reason().unwrap(),
                 error: self.user_message(),
                 error_code: self.error_code(),
+                request_id,
             }),
         )
-            .into_response()
+            .into_response();
+        // required for 405 responses
+        if let ApiError::MethodNotAllowed(allowed_methods) = self {
+            let allow = allowed_methods.iter().map(Method::as_str).join(", ");
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::try_from(allow).unwrap());
+        }
+        response
     }
 }
@@ -0,0 +1,74 @@
+use crate::web::error::ApiError;
+use crate::web::get_recent_messages::channel_export_options;
+use crate::web::WebAppData;
+use axum::extract::rejection::PathRejection;
+use axum::extract::Path;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use twitch_irc::message::IRCMessage;
+
+#[derive(Deserialize)]
+pub struct GetMessagePath {
+    channel_login: String,
+    message_id: String,
+}
+
+#[derive(Serialize)]
+pub struct GetMessageResponse {
+    message: String,
+}
+
+// GET /api/v2/message/:channel_login/:message_id
+/// A single stored message, by the Twitch message ID (the `id` tag). Only messages stored with
+/// `irc.deduplicate_messages` enabled can be found, since the ID is not stored otherwise.
+pub async fn get_message(
+    path_options: Result<Path<GetMessagePath>, PathRejection>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<GetMessageResponse>, ApiError> {
+    let Path(GetMessagePath {
+        channel_login,
+        message_id,
+    }) = path_options.map_err(|_| ApiError::InvalidPath)?;
+
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
+    let mut export_options = channel_export_options(&app_data, &channel_login).await?;
+    if !app_data.data_storage.is_channel_available(&channel_login) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let stored_message = app_data
+        .data_storage
+        .get_message_by_id(&channel_login, &message_id)
+        .await
+        .map_err(ApiError::GetMessages)?
+        .ok_or(ApiError::NotFound)?;
+
+    // a later CLEARCHAT/CLEARMSG marks the message as deleted, or hides it with
+    // `hide_moderated_messages`. Only the message itself is returned, not those.
+    let moderation_messages = app_data
+        .data_storage
+        .get_moderation_messages_after(
+            &channel_login,
+            stored_message.time_received,
+            stored_message.id,
+        )
+        .await
+        .map_err(ApiError::GetMessages)?;
+    export_options.hide_moderation_messages = true;
+
+    // messages of types that are not exported are treated as not found
+    let message = crate::message_export::export_stored_messages(
+        std::iter::once(stored_message)
+            .chain(moderation_messages.into_iter().filter(|message| {
+                IRCMessage::parse(&message.message_source).map_or(false, |message| {
+                    matches!(message.command.as_str(), "CLEARCHAT" | "CLEARMSG")
+                })
+            }))
+            .collect(),
+        export_options,
+        None,
+    )
+    .pop()
+    .ok_or(ApiError::NotFound)?;
+    Ok(Json(GetMessageResponse { message }))
+}
@@ -0,0 +1,164 @@
+use crate::web::error::ApiError;
+use crate::web::WebAppData;
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::extract::{Path, Query};
+use axum::{Extension, Json};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Shorter buckets are rejected
+const MIN_BUCKET_LENGTH: Duration = Duration::from_secs(60);
+/// Only the newest buckets are returned if the retention period would need more
+const MAX_BUCKETS: u64 = 1000;
+
+#[derive(Deserialize)]
+pub struct GetMessageHistogramPath {
+    channel_login: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetMessageHistogramQueryOptions {
+    /// e.g. `1h`, parsed by `humantime`
+    bucket: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    /// Milliseconds since the unix epoch
+    start: i64,
+    count: i64,
+}
+
+#[derive(Serialize)]
+pub struct GetMessageHistogramResponse {
+    bucket_seconds: u64,
+    /// Oldest bucket first, the last bucket is the current one
+    buckets: Vec<HistogramBucket>,
+}
+
+/// The requested bucket length, one hour by default. Must be whole seconds, so buckets line up
+/// with the epoch-aligned bucket starts, and not longer than messages are retained for.
+fn parse_bucket_length(bucket: Option<&str>, retained_for: Duration) -> Result<Duration, ApiError> {
+    let bucket_length = match bucket {
+        Some(bucket) => humantime::parse_duration(bucket).map_err(|_| ApiError::InvalidQuery)?,
+        None => Duration::from_secs(60 * 60),
+    };
+    if bucket_length < MIN_BUCKET_LENGTH
+        || bucket_length > Duration::max(retained_for, MIN_BUCKET_LENGTH)
+        || bucket_length.subsec_nanos() != 0
+    {
+        return Err(ApiError::InvalidQuery);
+    }
+    Ok(bucket_length)
+}
+
+/// Start of the bucket containing `time`. Buckets are aligned to the unix epoch, so with hourly
+/// or daily buckets they start at full UTC hours or days.
+fn bucket_start(time: DateTime<Utc>, bucket_length: Duration) -> DateTime<Utc> {
+    let bucket_millis = bucket_length.as_millis() as i64;
+    let millis = time.timestamp_millis();
+    Utc.timestamp_millis_opt(millis - millis.rem_euclid(bucket_millis))
+        .unwrap()
+}
+
+// GET /api/v2/recent-messages/:channel_login/histogram?bucket=1h
+/// Number of stored messages of a channel per time bucket, covering the time messages are
+/// retained for.
+pub async fn get_message_histogram(
+    path_options: Result<Path<GetMessageHistogramPath>, PathRejection>,
+    query_options: Result<Query<GetMessageHistogramQueryOptions>, QueryRejection>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<GetMessageHistogramResponse>, ApiError> {
+    let Path(GetMessageHistogramPath { channel_login }) =
+        path_options.map_err(|_| ApiError::InvalidPath)?;
+    let Query(query_options) = query_options.map_err(|_| ApiError::InvalidQuery)?;
+
+    let retained_for = app_data
+        .config
+        .app
+        .quiet_channel_messages_expire_after
+        .map_or(app_data.config.app.messages_expire_after, |quiet| {
+            Duration::max(quiet, app_data.config.app.messages_expire_after)
+        });
+    let bucket_length = parse_bucket_length(query_options.bucket.as_deref(), retained_for)?;
+
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
+    let is_ignored = app_data
+        .data_storage
+        .is_channel_ignored(&channel_login)
+        .await
+        .map_err(ApiError::GetChannelIgnored)?;
+    if is_ignored {
+        return Err(ApiError::ChannelIgnored(channel_login));
+    }
+    if !app_data.data_storage.is_channel_available(&channel_login) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let num_buckets = u64::min(
+        retained_for.as_secs().div_ceil(bucket_length.as_secs()) + 1,
+        MAX_BUCKETS,
+    );
+    let last_bucket = bucket_start(Utc::now(), bucket_length);
+    let first_bucket = last_bucket
+        - chrono::Duration::from_std(bucket_length * (num_buckets - 1) as u32)
+            .map_err(|_| ApiError::InvalidQuery)?;
+
+    let counts = app_data
+        .data_storage
+        .count_messages_per_bucket(&channel_login, first_bucket, last_bucket, bucket_length)
+        .await
+        .map_err(ApiError::CountMessages)?;
+
+    Ok(Json(GetMessageHistogramResponse {
+        bucket_seconds: bucket_length.as_secs(),
+        buckets: counts
+            .into_iter()
+            .map(|(start, count)| HistogramBucket {
+                start: start.timestamp_millis(),
+                count,
+            })
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_start, parse_bucket_length};
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[test]
+    fn buckets_start_at_full_utc_hours_and_days() {
+        let time = Utc.with_ymd_and_hms(2020, 7, 29, 22, 17, 38).unwrap();
+        assert_eq!(
+            bucket_start(time, Duration::from_secs(60 * 60)),
+            Utc.with_ymd_and_hms(2020, 7, 29, 22, 0, 0).unwrap()
+        );
+        assert_eq!(
+            bucket_start(time, Duration::from_secs(24 * 60 * 60)),
+            Utc.with_ymd_and_hms(2020, 7, 29, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            bucket_start(time, Duration::from_secs(15 * 60)),
+            Utc.with_ymd_and_hms(2020, 7, 29, 22, 15, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn bucket_lengths_are_bounded_by_the_retention() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            parse_bucket_length(None, day).unwrap(),
+            Duration::from_secs(60 * 60)
+        );
+        assert_eq!(parse_bucket_length(Some("1d"), day).unwrap(), day);
+        assert!(parse_bucket_length(Some("0s"), day).is_err());
+        assert!(parse_bucket_length(Some("59s"), day).is_err());
+        assert!(parse_bucket_length(Some("90s 500ms"), day).is_err());
+        assert!(parse_bucket_length(Some("2d"), day).is_err());
+        assert!(parse_bucket_length(Some("1000000000y"), day).is_err());
+        assert!(parse_bucket_length(Some("abc"), day).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::web::error::ApiError;
+use crate::web::get_recent_messages::channel_export_options;
+use crate::web::WebAppData;
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::extract::{Path, Query};
+use axum::{Extension, Json};
+use chrono::serde::ts_milliseconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct GetMessageWindowPath {
+    channel_login: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetMessageWindowQueryOptions {
+    #[serde(with = "ts_milliseconds")]
+    after: DateTime<Utc>,
+    /// Together with `after`, continues after a message with this ID
+    after_id: Option<i64>,
+    #[serde(with = "ts_milliseconds")]
+    before: DateTime<Utc>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct GetMessageWindowResponse {
+    messages: Vec<String>,
+    /// If there are possibly more messages in the window, pass this as `?after=` and
+    /// `next_after_id` as `?after_id=` to get the next page.
+    next_after: Option<i64>,
+    next_after_id: Option<i64>,
+}
+
+// GET /api/v2/message-window/:channel_login?after=&after_id=&before=
+/// All stored messages of a channel between `after` and `before` (both exclusive), oldest first,
+/// in pages of up to `web.message_window_max_limit` messages. Disabled unless that option is set.
+pub async fn get_message_window(
+    path_options: Result<Path<GetMessageWindowPath>, PathRejection>,
+    query_options: Result<Query<GetMessageWindowQueryOptions>, QueryRejection>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<GetMessageWindowResponse>, ApiError> {
+    let max_limit = app_data
+        .config
+        .web
+        .message_window_max_limit
+        .ok_or(ApiError::NotFound)?;
+
+    let Path(GetMessageWindowPath { channel_login }) =
+        path_options.map_err(|_| ApiError::InvalidPath)?;
+    let Query(query_options) = query_options.map_err(|_| ApiError::InvalidQuery)?;
+
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
+    let export_options = channel_export_options(&app_data, &channel_login).await?;
+    if !app_data.data_storage.is_channel_available(&channel_login) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let limit = usize::min(query_options.limit.unwrap_or(max_limit), max_limit);
+    let stored_messages = app_data
+        .data_storage
+        .get_messages_window(
+            &channel_login,
+            query_options.after,
+            query_options.after_id,
+            Some(query_options.before),
+            limit,
+        )
+        .await
+        .map_err(ApiError::GetMessages)?;
+
+    // a full page means there might be more messages after it
+    let (next_after, next_after_id) = match stored_messages.last() {
+        Some(message) if stored_messages.len() == limit => (
+            Some(message.time_received.timestamp_millis()),
+            Some(message.id),
+        ),
+        _ => (None, None),
+    };
+
+    let messages =
+        crate::message_export::export_stored_messages(stored_messages, export_options, None);
+
+    Ok(Json(GetMessageWindowResponse {
+        messages,
+        next_after,
+        next_after_id,
+    }))
+}
@@ -1,14 +1,26 @@
+use crate::config::{AppConfig, ExportOption, IgnoredChannelResponse, WebConfig};
+use crate::db::StoredMessage;
+use crate::irc_listener::JoinStatus;
 use crate::web::error::ApiError;
 use crate::web::WebAppData;
+use axum::body::{Bytes, StreamBody};
 use axum::extract::rejection::{PathRejection, QueryRejection};
 use axum::extract::{Path, Query};
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
 use chrono::serde::ts_milliseconds_option;
 use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use http::header::HeaderName;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use itertools::Itertools;
 use lazy_static::lazy_static;
-use prometheus::{linear_buckets, register_histogram_vec, HistogramVec};
+use prometheus::{linear_buckets, register_histogram_vec, HistogramTimer, HistogramVec};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
 lazy_static! {
@@ -35,7 +47,7 @@ pub struct GetRecentMessagesPath {
     channel_login: String,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Hash, Deserialize)]
 #[serde(default)]
 pub struct GetRecentMessagesQueryOptions {
     // aliases are used to keep compatibility with the API from version 1.
@@ -45,11 +57,31 @@ pub struct GetRecentMessagesQueryOptions {
     pub hide_moderated_messages: bool,
     #[serde(alias = "clearchatToNotice")]
     pub clearchat_to_notice: bool,
+    pub hide_room_mode_notices: bool,
     pub limit: Option<usize>,
     #[serde(with = "ts_milliseconds_option")]
     pub before: Option<DateTime<Utc>>,
     #[serde(with = "ts_milliseconds_option")]
     pub after: Option<DateTime<Utc>>,
+    /// For polling: Only the oldest messages after this position, pass the `watermark` and
+    /// `watermark_id` of the previous response
+    #[serde(with = "ts_milliseconds_option")]
+    pub since: Option<DateTime<Utc>>,
+    /// Messages received at `since` with a higher id are returned as well
+    pub since_id: Option<i64>,
+    pub include_total: bool,
+    pub timestamp_format: TimestampFormat,
+    pub wait_for_join_ms: Option<u64>,
+    pub batch: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// Only `rm-received-ts` (milliseconds since the unix epoch)
+    Millis,
+    /// `rm-received-ts` and additionally `rm-received-time` as an RFC 3339 timestamp
+    Rfc3339,
 }
 
 impl Default for GetRecentMessagesQueryOptions {
@@ -58,9 +90,65 @@ impl Default for GetRecentMessagesQueryOptions {
             hide_moderation_messages: false,
             hide_moderated_messages: false,
             clearchat_to_notice: false,
+            hide_room_mode_notices: false,
             limit: None,
             before: None,
             after: None,
+            since: None,
+            since_id: None,
+            include_total: false,
+            timestamp_format: TimestampFormat::Millis,
+            wait_for_join_ms: None,
+            batch: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChannelStatus {
+    /// The channel has never been requested before (or it expired)
+    NeverRequested,
+    /// The channel is known, but the bot is not (yet) joined to it
+    Joining,
+    /// The bot was joined to the channel and is currently rejoining it after a reconnect
+    Reconnecting,
+    /// The bot is joined to the channel, but no messages are stored
+    JoinedEmpty,
+    /// The bot is joined to the channel and messages are available
+    JoinedWithMessages,
+    /// The channel is excluded from the service, only with
+    /// `web.ignored_channel_response = "empty"`
+    ChannelIgnored,
+}
+
+/// Whether the stored messages cover the recent activity of the channel, see
+/// `web.backlog_filling_for`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BacklogStatus {
+    /// The channel was only joined recently, and there are no older stored messages
+    Filling,
+    Ready,
+    /// The messages of the channel are not recorded, because it is ignored
+    NotRecorded,
+}
+
+impl BacklogStatus {
+    /// Ready once the bot has been joined to the channel for `web.backlog_filling_for`, or the
+    /// stored messages go back that far, e.g. after a restart.
+    fn new(
+        joined_for: Option<Duration>,
+        oldest_stored: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+        web_config: &WebConfig,
+    ) -> BacklogStatus {
+        let stored_for =
+            oldest_stored.and_then(|oldest_stored| (now - oldest_stored).to_std().ok());
+        if joined_for.max(stored_for) >= Some(web_config.backlog_filling_for) {
+            BacklogStatus::Ready
+        } else {
+            BacklogStatus::Filling
         }
     }
 }
@@ -68,107 +156,863 @@ impl Default for GetRecentMessagesQueryOptions {
 #[derive(Debug, Serialize)]
 struct GetRecentMessagesResponse {
     messages: Vec<String>,
+    #[serde(flatten)]
+    metadata: GetRecentMessagesMetadata,
+}
+
+/// Everything in the response except for the messages
+#[derive(Debug, Serialize)]
+struct GetRecentMessagesMetadata {
+    status: ChannelStatus,
+    backlog_status: BacklogStatus,
     error: Option<&'static str>,
     error_code: Option<&'static str>,
+    /// Only present if requested with `?include_total=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_stored: Option<i64>,
+    /// Whether older messages were left out to stay below `web.max_response_bytes`
+    truncated: bool,
+    /// Timestamp of the newest returned message, or `?since=` if there are no newer messages.
+    /// Pass this as `?since=` to only get newer messages on the next request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark: Option<i64>,
+    /// Orders the messages received at `watermark`, pass this as `?since_id=`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark_id: Option<i64>,
+    /// Only with `?since=`: Whether there are newer messages than the returned ones, which the
+    /// next request with the new watermark returns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    has_more: Option<bool>,
 }
 
 pub async fn get_recent_messages(
     path_options: Result<Path<GetRecentMessagesPath>, PathRejection>,
     query_options: Result<Query<GetRecentMessagesQueryOptions>, QueryRejection>,
+    headers: HeaderMap,
     Extension(app_data): Extension<WebAppData>,
-) -> impl IntoResponse {
+) -> Result<Response, ApiError> {
     let Path(GetRecentMessagesPath { channel_login }) =
         path_options.map_err(|_| ApiError::InvalidPath)?;
     let Query(query_options) = query_options.map_err(|_| ApiError::InvalidQuery)?;
+    let mut stage_timings = StageTimings::default();
+    let query_options = apply_disabled_export_options(query_options, &app_data.config.app)?;
 
-    if let Err(e) = twitch_irc::validate::validate_login(&channel_login) {
-        return Err(ApiError::InvalidChannelLogin(e));
-    }
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
 
-    let timer = COMPONENTS_PERFORMANCE_HISTOGRAM
-        .with_label_values(&["is_channel_ignored"])
-        .start_timer();
+    let timer = StageTimer::start("is_channel_ignored");
     let result = app_data
         .data_storage
-        .is_channel_ignored(&channel_login)
+        .get_channel_ignored(&channel_login)
         .await;
-    timer.observe_duration();
-    if result.map_err(ApiError::GetChannelIgnored)? {
-        return Err(ApiError::ChannelIgnored(channel_login));
+    stage_timings.finish(timer);
+    let channel_ignored = result.map_err(ApiError::GetChannelIgnored)?;
+    if channel_ignored == Some(true) {
+        return match app_data.config.web.ignored_channel_response {
+            IgnoredChannelResponse::Error => Err(ApiError::ChannelIgnored(channel_login)),
+            IgnoredChannelResponse::Empty => {
+                let response = Json(GetRecentMessagesResponse {
+                    messages: vec![],
+                    metadata: ignored_channel_metadata(&query_options),
+                })
+                .into_response();
+                Ok(stage_timings.add_header(response, &app_data.config.web))
+            }
+        };
     }
+    let channel_known = channel_ignored.is_some();
 
-    let timer = COMPONENTS_PERFORMANCE_HISTOGRAM
-        .with_label_values(&["get_messages"])
-        .start_timer();
+    let timer = StageTimer::start("get_channel_settings");
     let result = app_data
         .data_storage
-        .get_messages(
-            &channel_login,
-            query_options.limit,
-            query_options.before,
-            query_options.after,
-            app_data.config.app.max_buffer_size,
-        )
+        .get_forced_export_options(&channel_login)
         .await;
-    timer.observe_duration();
-    let stored_messages = result.map_err(ApiError::GetMessages)?;
-    MESSAGE_COUNT_HISTOGRAM
-        .with_label_values(&["from_database"])
-        .observe(stored_messages.len() as f64);
+    stage_timings.finish(timer);
+    let forced_export_options = result.map_err(ApiError::GetChannelSettings)?;
+    let query_options =
+        apply_forced_export_options(query_options, &forced_export_options, &app_data.config.app);
 
-    let timer = COMPONENTS_PERFORMANCE_HISTOGRAM
-        .with_label_values(&["export_stored_messages"])
-        .start_timer();
-    let exported_messages =
-        crate::message_export::export_stored_messages(stored_messages, query_options);
-    timer.observe_duration();
-    MESSAGE_COUNT_HISTOGRAM
-        .with_label_values(&["after_export"])
-        .observe(exported_messages.len() as f64);
+    if !app_data.data_storage.is_channel_available(&channel_login) {
+        return Err(ApiError::StorageUnavailable);
+    }
 
-    let timer = COMPONENTS_PERFORMANCE_HISTOGRAM
-        .with_label_values(&["is_join_confirmed"])
-        .start_timer();
-    let mut is_confirmed_joined = app_data
+    let timer = StageTimer::start("is_join_confirmed");
+    let (is_join_wanted, join_status) = app_data
         .irc_listener
-        .is_join_confirmed(channel_login.clone())
+        .join_status(channel_login.clone())
         .await;
-    timer.observe_duration();
+    stage_timings.finish(timer);
+    let mut is_confirmed_joined = join_status == JoinStatus::Joined;
 
-    tokio::spawn(async move {
+    // optionally give the bot some time to join the channel, so the client gets messages
+    // on its first request
+    let wait_for_join = Duration::from_millis(query_options.wait_for_join_ms.unwrap_or(0))
+        .min(app_data.config.web.max_wait_for_join);
+    if !is_confirmed_joined && !wait_for_join.is_zero() {
+        let timer = StageTimer::start("wait_for_join");
         app_data.irc_listener.join_if_needed(channel_login.clone());
+        is_confirmed_joined = app_data
+            .irc_listener
+            .wait_for_join_confirmed(channel_login.clone(), wait_for_join)
+            .await;
+        stage_timings.finish(timer);
+    }
 
-        if !is_confirmed_joined {
-            // wait 5 seconds then check again
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            is_confirmed_joined = app_data
-                .irc_listener
-                .is_join_confirmed(channel_login.clone())
-                .await;
+    let timer = StageTimer::start("get_messages");
+    let since = polling_cursor(&query_options);
+    let result = match since {
+        // the oldest messages after the cursor, so no messages are skipped if there are more
+        // than the limit
+        Some((since, since_id)) => {
+            let limit = query_options
+                .limit
+                .map_or(app_data.config.max_request_limit(), |limit| {
+                    usize::min(limit, app_data.config.max_request_limit())
+                });
+            app_data
+                .data_storage
+                .get_messages_window(
+                    &channel_login,
+                    since,
+                    since_id,
+                    query_options.before,
+                    limit + 1,
+                )
+                .await
+                .map(|mut stored_messages| {
+                    let has_more = stored_messages.len() > limit;
+                    stored_messages.truncate(limit);
+                    (stored_messages, Some(has_more))
+                })
         }
+        None => app_data
+            .data_storage
+            .get_messages(
+                &channel_login,
+                query_options.limit,
+                query_options.before,
+                query_options.after,
+                app_data.config.max_request_limit(),
+            )
+            .await
+            .map(|stored_messages| (stored_messages, None)),
+    };
+    stage_timings.finish(timer);
+    let (stored_messages, mut has_more) = result.map_err(ApiError::GetMessages)?;
+    MESSAGE_COUNT_HISTOGRAM
+        .with_label_values(&["from_database"])
+        .observe(stored_messages.len() as f64);
 
-        // if we managed to join the channel then add/touch it in the database
-        if is_confirmed_joined {
-            tracing::trace!("Adding/touching channel: {}", channel_login);
-            let res = app_data
-                .data_storage
-                .touch_or_add_channel(&channel_login)
-                .await;
-            if let Err(e) = res {
-                tracing::error!("Failed to touch_or_add_channel: {}", e);
-            }
+    let total_stored = if query_options.include_total {
+        let timer = StageTimer::start("count_messages");
+        let result = app_data.data_storage.count_messages(&channel_login).await;
+        stage_timings.finish(timer);
+        Some(result.map_err(ApiError::CountMessages)?)
+    } else {
+        None
+    };
+
+    let status = if is_confirmed_joined {
+        if stored_messages.is_empty() {
+            ChannelStatus::JoinedEmpty
+        } else {
+            ChannelStatus::JoinedWithMessages
         }
-    });
+    } else if join_status == JoinStatus::Reconnecting {
+        ChannelStatus::Reconnecting
+    } else if channel_known || is_join_wanted {
+        ChannelStatus::Joining
+    } else {
+        ChannelStatus::NeverRequested
+    };
 
-    let (error, error_code) = if is_confirmed_joined {
+    let joined_for = app_data.irc_listener.joined_for(&channel_login);
+    // only needed if the bot wasn't joined for long enough itself
+    let oldest_stored = if joined_for < Some(app_data.config.web.backlog_filling_for) {
+        let timer = StageTimer::start("get_oldest_message_time");
+        let result = app_data
+            .data_storage
+            .get_oldest_message_time(&channel_login)
+            .await;
+        stage_timings.finish(timer);
+        result.map_err(ApiError::GetMessages)?
+    } else {
+        None
+    };
+    let backlog_status =
+        BacklogStatus::new(joined_for, oldest_stored, Utc::now(), &app_data.config.web);
+
+    app_data.irc_listener.join_if_needed(channel_login.clone());
+    app_data
+        .channel_touch_queue
+        .push(channel_login.clone(), is_confirmed_joined);
+
+    // The response only changes if new messages arrive, old ones are vacuumed (changes the count),
+    // the join status changes, or different options are requested.
+    let etag = {
+        let mut hasher = DefaultHasher::new();
+        query_options.hash(&mut hasher);
+        stored_messages.len().hash(&mut hasher);
+        stored_messages
+            .last()
+            .map(|msg| msg.time_received)
+            .hash(&mut hasher);
+        status.hash(&mut hasher);
+        backlog_status.hash(&mut hasher);
+        total_stored.hash(&mut hasher);
+        format!("W/\"{:016x}\"", hasher.finish())
+    };
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "no-cache".to_owned()),
+    ];
+
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if let Some(if_none_match) = if_none_match {
+        if etag_matches(if_none_match, &etag) {
+            let response = (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+            return Ok(stage_timings.add_header(response, &app_data.config.web));
+        }
+    }
+
+    let timer = StageTimer::start("export_stored_messages");
+    let batch_ref = query_options
+        .batch
+        .then(crate::message_export::generate_batch_ref);
+    // of the stored messages, so messages hidden by the export options aren't returned again
+    let newest_stored = stored_messages
+        .last()
+        .map(|message| (message.time_received, message.id));
+    let (mut exported_messages, truncated, newest_included) =
+        match app_data.config.web.max_response_bytes {
+            // exported while the response is streamed
+            None if app_data.config.web.stream_responses => {
+                let exported_messages = crate::message_export::export_stored_messages_lazily(
+                    stored_messages,
+                    query_options,
+                    batch_ref.clone(),
+                );
+                (
+                    ExportedMessages::Lazy(Box::new(exported_messages)),
+                    false,
+                    newest_stored,
+                )
+            }
+            // when polling, the newest messages are left for the next request instead
+            Some(max_response_bytes) if since.is_some() => {
+                let (exported_messages, num_included) = export_oldest_fitting(
+                    &stored_messages,
+                    query_options,
+                    batch_ref.as_deref(),
+                    max_response_bytes,
+                );
+                let truncated = num_included < stored_messages.len();
+                if truncated {
+                    has_more = Some(true);
+                }
+                let newest_included = stored_messages[..num_included].last();
+                (
+                    ExportedMessages::Exported(exported_messages),
+                    truncated,
+                    newest_included.map(|message| (message.time_received, message.id)),
+                )
+            }
+            max_response_bytes => {
+                let mut exported_messages = crate::message_export::export_stored_messages(
+                    stored_messages,
+                    query_options,
+                    batch_ref.as_deref(),
+                );
+                let truncated = max_response_bytes.map_or(false, |max_response_bytes| {
+                    truncate_to_size(&mut exported_messages, max_response_bytes)
+                });
+                (
+                    ExportedMessages::Exported(exported_messages),
+                    truncated,
+                    newest_stored,
+                )
+            }
+        };
+    stage_timings.finish(timer);
+    let (watermark, watermark_id) = match newest_included {
+        Some((time_received, id)) => (Some(time_received.timestamp_millis()), Some(id)),
+        None => (
+            since.map(|(since, _)| since.timestamp_millis()),
+            since.and_then(|(_, since_id)| since_id),
+        ),
+    };
+    // after truncating, so the start of the batch isn't cut off
+    if let Some(batch_ref) = &batch_ref {
+        exported_messages = exported_messages.wrap_in_batch(batch_ref);
+    }
+
+    // a reconnect only briefly interrupts a channel that has been joined before, so it is not
+    // reported as an error
+    let (error, error_code) = if is_confirmed_joined || status == ChannelStatus::Reconnecting {
         (None, None)
     } else {
         (Some("The bot is currently not joined to this channel (in progress or failed previously)"), Some("channel_not_joined"))
     };
 
-    Ok(Json(GetRecentMessagesResponse {
-        messages: exported_messages,
+    let metadata = GetRecentMessagesMetadata {
+        status,
+        backlog_status,
         error,
         error_code,
-    }))
+        total_stored,
+        truncated,
+        watermark,
+        watermark_id,
+        has_more,
+    };
+    let response = if app_data.config.web.stream_responses {
+        (
+            cache_headers,
+            [(header::CONTENT_TYPE, "application/json")],
+            StreamBody::new(stream_response_body(
+                exported_messages.into_messages(),
+                metadata,
+            )),
+        )
+            .into_response()
+    } else {
+        let messages = exported_messages.into_messages().collect_vec();
+        MESSAGE_COUNT_HISTOGRAM
+            .with_label_values(&["after_export"])
+            .observe(messages.len() as f64);
+        (
+            cache_headers,
+            Json(GetRecentMessagesResponse { messages, metadata }),
+        )
+            .into_response()
+    };
+    Ok(stage_timings.add_header(response, &app_data.config.web))
+}
+
+/// Response metadata for an ignored channel with `web.ignored_channel_response = "empty"`, with
+/// the error code of the 403 response.
+fn ignored_channel_metadata(
+    query_options: &GetRecentMessagesQueryOptions,
+) -> GetRecentMessagesMetadata {
+    GetRecentMessagesMetadata {
+        status: ChannelStatus::ChannelIgnored,
+        backlog_status: BacklogStatus::NotRecorded,
+        error: Some("This channel is excluded from this service"),
+        error_code: Some("channel_ignored"),
+        total_stored: query_options.include_total.then_some(0),
+        truncated: false,
+        watermark: None,
+        watermark_id: None,
+        has_more: None,
+    }
+}
+
+/// Times a stage of the endpoint in `COMPONENTS_PERFORMANCE_HISTOGRAM`.
+struct StageTimer {
+    stage: &'static str,
+    timer: HistogramTimer,
+}
+
+impl StageTimer {
+    fn start(stage: &'static str) -> StageTimer {
+        StageTimer {
+            stage,
+            timer: COMPONENTS_PERFORMANCE_HISTOGRAM
+                .with_label_values(&[stage])
+                .start_timer(),
+        }
+    }
+}
+
+/// Durations of the stages of a single request, for the `Server-Timing` header.
+#[derive(Debug, Default)]
+struct StageTimings(Vec<(&'static str, f64)>);
+
+impl StageTimings {
+    fn finish(&mut self, stage_timer: StageTimer) {
+        let seconds = stage_timer.timer.stop_and_record();
+        self.0.push((stage_timer.stage, seconds));
+    }
+
+    /// e.g. `is_channel_ignored;dur=0.8, get_messages;dur=3.1`, in milliseconds
+    fn header_value(&self) -> String {
+        self.0
+            .iter()
+            .map(|(stage, seconds)| format!("{};dur={:.1}", stage, seconds * 1000.0))
+            .join(", ")
+    }
+
+    /// Adds the `Server-Timing` header if enabled with `web.server_timing_header`.
+    fn add_header(&self, mut response: Response, web_config: &WebConfig) -> Response {
+        if web_config.server_timing_header && !self.0.is_empty() {
+            response.headers_mut().insert(
+                HeaderName::from_static("server-timing"),
+                HeaderValue::try_from(self.header_value()).unwrap(),
+            );
+        }
+        response
+    }
+}
+
+/// The exported messages of a response. Unless they have to be exported up front, e.g. to limit
+/// the size of the response, they are only exported while the response is streamed.
+enum ExportedMessages {
+    Exported(Vec<String>),
+    Lazy(Box<dyn Iterator<Item = String> + Send>),
+}
+
+impl ExportedMessages {
+    /// See [`crate::message_export::wrap_in_batch`]
+    fn wrap_in_batch(self, batch_ref: &str) -> ExportedMessages {
+        match self {
+            ExportedMessages::Exported(mut messages) => {
+                crate::message_export::wrap_in_batch(&mut messages, batch_ref);
+                ExportedMessages::Exported(messages)
+            }
+            ExportedMessages::Lazy(messages) => ExportedMessages::Lazy(Box::new(
+                std::iter::once(crate::message_export::batch_start(batch_ref))
+                    .chain(messages)
+                    .chain(std::iter::once(crate::message_export::batch_end(batch_ref))),
+            )),
+        }
+    }
+
+    fn into_messages(self) -> Box<dyn Iterator<Item = String> + Send> {
+        match self {
+            ExportedMessages::Exported(messages) => Box::new(messages.into_iter()),
+            ExportedMessages::Lazy(messages) => messages,
+        }
+    }
+}
+
+/// Number of messages serialized into a single chunk of a streamed response
+const MESSAGES_PER_STREAM_CHUNK: usize = 50;
+
+/// Serializes the response in chunks as it is sent, instead of into one buffer for the entire
+/// response. The output is identical to serializing [`GetRecentMessagesResponse`].
+fn stream_response_body(
+    messages: impl Iterator<Item = String> + Send,
+    metadata: GetRecentMessagesMetadata,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let mut messages = messages.peekable();
+    let mut num_messages = 0;
+    let message_chunks = std::iter::from_fn(move || {
+        if messages.peek().is_none() {
+            MESSAGE_COUNT_HISTOGRAM
+                .with_label_values(&["after_export"])
+                .observe(num_messages as f64);
+            return None;
+        }
+        let mut buf = Vec::new();
+        for message in messages.by_ref().take(MESSAGES_PER_STREAM_CHUNK) {
+            if num_messages > 0 {
+                buf.push(b',');
+            }
+            serde_json::to_writer(&mut buf, &message).unwrap();
+            num_messages += 1;
+        }
+        Some(Bytes::from(buf))
+    });
+
+    // `{"status":...}` becomes `],"status":...}`
+    let mut end = serde_json::to_vec(&metadata).unwrap();
+    end[0] = b',';
+    end.insert(0, b']');
+
+    stream::iter(
+        std::iter::once(Bytes::from_static(b"{\"messages\":["))
+            .chain(message_chunks)
+            .chain(std::iter::once(Bytes::from(end)))
+            .map(Ok),
+    )
+}
+
+/// The position after which messages are returned when polling: `?since=` and `?since_id=`, or
+/// `?after=` if it is later.
+fn polling_cursor(
+    query_options: &GetRecentMessagesQueryOptions,
+) -> Option<(DateTime<Utc>, Option<i64>)> {
+    let since = query_options.since?;
+    match query_options.after {
+        Some(after) if after >= since => Some((after, None)),
+        _ => Some((since, query_options.since_id)),
+    }
+}
+
+/// The JSON-encoded string (quotes and escapes) plus the separating comma
+fn json_size(message: &str) -> usize {
+    serde_json::to_string(message).unwrap().len() + 1
+}
+
+/// Exports as many of the oldest stored messages as fit into `max_bytes` when serialized as a
+/// JSON array. Returns the exported messages and the number of stored messages they include.
+fn export_oldest_fitting(
+    stored_messages: &[StoredMessage],
+    options: GetRecentMessagesQueryOptions,
+    batch_ref: Option<&str>,
+    max_bytes: usize,
+) -> (Vec<String>, usize) {
+    let mut num_included = stored_messages.len();
+    loop {
+        let exported_messages = crate::message_export::export_stored_messages(
+            stored_messages[..num_included].to_vec(),
+            options,
+            batch_ref,
+        );
+        let size: usize = exported_messages
+            .iter()
+            .map(|message| json_size(message))
+            .sum();
+        if size <= max_bytes || num_included == 0 {
+            return (exported_messages, num_included);
+        }
+        // exporting can change the size of messages, so this is only an estimate
+        num_included = usize::min(num_included - 1, num_included * max_bytes / size);
+    }
+}
+
+/// Drop the oldest messages until the rest fits into `max_bytes` when serialized as a JSON array.
+/// Returns whether any messages were dropped.
+fn truncate_to_size(messages: &mut Vec<String>, max_bytes: usize) -> bool {
+    let mut total_bytes = 0;
+    let mut num_fitting = 0;
+    for message in messages.iter().rev() {
+        let message_bytes = json_size(message);
+        if total_bytes + message_bytes > max_bytes {
+            break;
+        }
+        total_bytes += message_bytes;
+        num_fitting += 1;
+    }
+
+    let num_dropped = messages.len() - num_fitting;
+    messages.drain(..num_dropped);
+    num_dropped > 0
+}
+
+/// Turns off the export options disabled with `app.disabled_export_options`, or rejects the
+/// request if `app.reject_disabled_export_options` is set and one of them was requested.
+fn apply_disabled_export_options(
+    mut options: GetRecentMessagesQueryOptions,
+    app_config: &AppConfig,
+) -> Result<GetRecentMessagesQueryOptions, ApiError> {
+    for disabled_option in app_config.disabled_export_options.iter() {
+        let requested = export_option_mut(&mut options, *disabled_option);
+        if *requested {
+            if app_config.reject_disabled_export_options {
+                return Err(ApiError::ExportOptionDisabled(
+                    disabled_option.query_parameter(),
+                ));
+            }
+            *requested = false;
+        }
+    }
+    Ok(options)
+}
+
+/// Turns on the export options the channel owner forces on with their channel settings, so they
+/// win over what the client requested. Options disabled with `app.disabled_export_options` stay
+/// off, the operator's configuration wins over both.
+fn apply_forced_export_options(
+    mut options: GetRecentMessagesQueryOptions,
+    forced_export_options: &HashSet<ExportOption>,
+    app_config: &AppConfig,
+) -> GetRecentMessagesQueryOptions {
+    for forced_option in forced_export_options.difference(&app_config.disabled_export_options) {
+        *export_option_mut(&mut options, *forced_option) = true;
+    }
+    options
+}
+
+/// The export options for messages of a channel that are fetched without the query options of
+/// this endpoint: the options the channel owner forces on, except the disabled ones. Fails with
+/// `ApiError::ChannelIgnored` if the channel is ignored, both are looked up in one query.
+pub(crate) async fn channel_export_options(
+    app_data: &WebAppData,
+    channel_login: &str,
+) -> Result<GetRecentMessagesQueryOptions, ApiError> {
+    let (is_ignored, forced_export_options) = app_data
+        .data_storage
+        .get_channel_ignored_and_forced_export_options(channel_login)
+        .await
+        .map_err(ApiError::GetChannelIgnored)?;
+    if is_ignored {
+        return Err(ApiError::ChannelIgnored(channel_login.to_owned()));
+    }
+    Ok(apply_forced_export_options(
+        GetRecentMessagesQueryOptions::default(),
+        &forced_export_options,
+        &app_data.config.app,
+    ))
+}
+
+fn export_option_mut(
+    options: &mut GetRecentMessagesQueryOptions,
+    export_option: ExportOption,
+) -> &mut bool {
+    match export_option {
+        ExportOption::HideModerationMessages => &mut options.hide_moderation_messages,
+        ExportOption::HideModeratedMessages => &mut options.hide_moderated_messages,
+        ExportOption::ClearchatToNotice => &mut options.clearchat_to_notice,
+        ExportOption::HideRoomModeNotices => &mut options.hide_room_mode_notices,
+    }
+}
+
+/// Weak comparison of an `If-None-Match` header value against our (weak) ETag.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_disabled_export_options, apply_forced_export_options, export_oldest_fitting,
+        ignored_channel_metadata, json_size, polling_cursor, stream_response_body, BacklogStatus,
+        ChannelStatus, GetRecentMessagesMetadata, GetRecentMessagesQueryOptions,
+        GetRecentMessagesResponse, StageTimings,
+    };
+    use crate::config::{test_web_config, AppConfig, ExportOption, IgnoredChannelResponse};
+    use crate::db::StoredMessage;
+    use crate::web::error::ApiError;
+    use chrono::{TimeZone, Utc};
+    use futures::TryStreamExt;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn metadata() -> GetRecentMessagesMetadata {
+        GetRecentMessagesMetadata {
+            status: ChannelStatus::JoinedWithMessages,
+            backlog_status: BacklogStatus::Ready,
+            error: None,
+            error_code: None,
+            total_stored: Some(120),
+            truncated: false,
+            watermark: Some(1596061327989),
+            watermark_id: Some(42),
+            has_more: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_response_is_identical_to_buffered_response() {
+        for num_messages in [0, 1, 50, 51, 120] {
+            let messages = (0..num_messages)
+                .map(|i| {
+                    format!(
+                        "@historical=1 :tmi.twitch.tv NOTICE #pajlada :\"message\" {}",
+                        i
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let buffered = serde_json::to_vec(&GetRecentMessagesResponse {
+                messages: messages.clone(),
+                metadata: metadata(),
+            })
+            .unwrap();
+            let streamed = stream_response_body(messages.into_iter(), metadata())
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+                .concat();
+
+            assert_eq!(
+                String::from_utf8(streamed).unwrap(),
+                String::from_utf8(buffered).unwrap(),
+                "{} messages",
+                num_messages
+            );
+        }
+    }
+
+    #[test]
+    fn polling_continues_after_the_watermark() {
+        let since = Utc.timestamp_millis_opt(1596061058008).unwrap();
+        let mut options = GetRecentMessagesQueryOptions {
+            since: Some(since),
+            since_id: Some(42),
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert_eq!(polling_cursor(&options), Some((since, Some(42))));
+
+        // a later ?after= wins, an earlier one doesn't matter
+        options.after = Some(since - chrono::Duration::seconds(1));
+        assert_eq!(polling_cursor(&options), Some((since, Some(42))));
+        options.after = Some(since);
+        assert_eq!(polling_cursor(&options), Some((since, None)));
+
+        options.since = None;
+        assert_eq!(polling_cursor(&options), None);
+    }
+
+    #[test]
+    fn polling_leaves_the_newest_messages_for_the_next_request() {
+        let stored_messages = (0..10)
+            .map(|i| StoredMessage {
+                id: i,
+                // all in the same millisecond
+                time_received: Utc.timestamp_millis_opt(1596061058008).unwrap(),
+                message_source: format!(":tmi.twitch.tv NOTICE #pajlada :message {}", i),
+            })
+            .collect::<Vec<_>>();
+        let options = GetRecentMessagesQueryOptions::default();
+
+        let (all, num_included) = export_oldest_fitting(&stored_messages, options, None, 100_000);
+        assert_eq!(num_included, 10);
+        let message_size = json_size(&all[0]);
+
+        let (exported, num_included) =
+            export_oldest_fitting(&stored_messages, options, None, message_size * 4 + 1);
+        assert_eq!(num_included, 4);
+        assert_eq!(exported, all[..4]);
+
+        let (exported, num_included) = export_oldest_fitting(&stored_messages, options, None, 1);
+        assert_eq!(num_included, 0);
+        assert!(exported.is_empty());
+    }
+
+    #[test]
+    fn disabled_export_options_are_forced_off_or_rejected() {
+        let mut app_config = AppConfig::default();
+        app_config
+            .disabled_export_options
+            .insert(ExportOption::ClearchatToNotice);
+        let requested = GetRecentMessagesQueryOptions {
+            clearchat_to_notice: true,
+            hide_moderated_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+
+        let applied = apply_disabled_export_options(requested, &app_config).unwrap();
+        assert!(!applied.clearchat_to_notice);
+        assert!(applied.hide_moderated_messages);
+
+        app_config.reject_disabled_export_options = true;
+        assert!(matches!(
+            apply_disabled_export_options(requested, &app_config),
+            Err(ApiError::ExportOptionDisabled("clearchat_to_notice"))
+        ));
+        let not_requested = GetRecentMessagesQueryOptions {
+            hide_moderated_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert!(apply_disabled_export_options(not_requested, &app_config).is_ok());
+    }
+
+    #[test]
+    fn forced_export_options_win_unless_disabled() {
+        let mut app_config = AppConfig::default();
+        let forced = HashSet::from([
+            ExportOption::HideModeratedMessages,
+            ExportOption::ClearchatToNotice,
+        ]);
+
+        let applied = apply_forced_export_options(
+            GetRecentMessagesQueryOptions::default(),
+            &forced,
+            &app_config,
+        );
+        assert!(applied.hide_moderated_messages);
+        assert!(applied.clearchat_to_notice);
+        assert!(!applied.hide_moderation_messages);
+
+        // requested options that aren't forced stay on
+        let requested = GetRecentMessagesQueryOptions {
+            hide_moderation_messages: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert!(
+            apply_forced_export_options(requested, &forced, &app_config).hide_moderation_messages
+        );
+
+        app_config
+            .disabled_export_options
+            .insert(ExportOption::ClearchatToNotice);
+        let applied = apply_forced_export_options(
+            GetRecentMessagesQueryOptions::default(),
+            &forced,
+            &app_config,
+        );
+        assert!(applied.hide_moderated_messages);
+        assert!(!applied.clearchat_to_notice);
+    }
+
+    #[test]
+    fn server_timing_header_lists_stages_in_milliseconds() {
+        let stage_timings = StageTimings(vec![
+            ("is_channel_ignored", 0.00081),
+            ("get_messages", 0.0123),
+        ]);
+        assert_eq!(
+            stage_timings.header_value(),
+            "is_channel_ignored;dur=0.8, get_messages;dur=12.3"
+        );
+    }
+
+    #[test]
+    fn backlog_is_filling_until_joined_or_stored_for_long_enough() {
+        let web_config = test_web_config(r#"backlog_filling_for = "5 minutes""#);
+        let now = Utc::now();
+        assert_eq!(
+            BacklogStatus::new(None, None, now, &web_config),
+            BacklogStatus::Filling
+        );
+        assert_eq!(
+            BacklogStatus::new(Some(Duration::from_secs(30)), None, now, &web_config),
+            BacklogStatus::Filling
+        );
+        assert_eq!(
+            BacklogStatus::new(Some(Duration::from_secs(5 * 60)), None, now, &web_config),
+            BacklogStatus::Ready
+        );
+        // e.g. after a restart, the stored messages still cover the recent activity
+        assert_eq!(
+            BacklogStatus::new(
+                None,
+                Some(now - chrono::Duration::minutes(5)),
+                now,
+                &web_config
+            ),
+            BacklogStatus::Ready
+        );
+        assert_eq!(
+            BacklogStatus::new(
+                Some(Duration::from_secs(30)),
+                Some(now - chrono::Duration::minutes(1)),
+                now,
+                &web_config
+            ),
+            BacklogStatus::Filling
+        );
+    }
+
+    #[test]
+    fn ignored_channels_can_get_an_empty_response() {
+        let web_config = test_web_config("");
+        assert_eq!(
+            web_config.ignored_channel_response,
+            IgnoredChannelResponse::Error
+        );
+
+        let response = GetRecentMessagesResponse {
+            messages: vec![],
+            metadata: ignored_channel_metadata(&GetRecentMessagesQueryOptions::default()),
+        };
+        let response = serde_json::to_value(&response).unwrap();
+        assert_eq!(response["messages"], serde_json::json!([]));
+        assert_eq!(response["status"], "channel_ignored");
+        assert_eq!(response["error_code"], "channel_ignored");
+        assert!(response.get("total_stored").is_none());
+
+        let options = GetRecentMessagesQueryOptions {
+            include_total: true,
+            ..GetRecentMessagesQueryOptions::default()
+        };
+        assert_eq!(ignored_channel_metadata(&options).total_stored, Some(0));
+    }
 }
@@ -0,0 +1,163 @@
+use crate::db::StoredMessage;
+use crate::web::error::ApiError;
+use crate::web::WebAppData;
+use axum::extract::rejection::PathRejection;
+use axum::extract::Path;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use twitch_irc::message::{AsRawIRC, IRCMessage, IRCPrefix, IRCTags};
+
+/// Tags that describe the message rather than the room, they are not merged
+const NON_ROOM_TAGS: &[&str] = &["historical", "rm-received-ts", "rm-received-time", "batch"];
+/// Only the newest stored `ROOMSTATE`s are merged. Twitch sends all tags after every join, so
+/// older ones rarely contribute anything.
+const MAX_MERGED_ROOMSTATES: usize = 100;
+
+#[derive(Deserialize)]
+pub struct GetRoomStatePath {
+    channel_login: String,
+}
+
+#[derive(Serialize)]
+pub struct GetRoomStateResponse {
+    /// The merged `ROOMSTATE` in raw IRC format, `None` if no `ROOMSTATE` of the channel is stored
+    roomstate: Option<String>,
+    /// The tags of `roomstate`, e.g. `"slow": "10"`
+    tags: BTreeMap<String, String>,
+}
+
+/// The current room state of the channel: the tags of all stored `ROOMSTATE`s, oldest first, with
+/// newer values replacing older ones (Twitch only sends the changed tags after the first one), and
+/// the time the newest of them was received.
+fn merge_roomstates(
+    messages: Vec<StoredMessage>,
+) -> Option<(BTreeMap<String, String>, DateTime<Utc>)> {
+    let mut tags = BTreeMap::new();
+    let mut last_received = None;
+    for message in messages {
+        let irc_message = match IRCMessage::parse(&message.message_source) {
+            Ok(irc_message) if irc_message.command == "ROOMSTATE" => irc_message,
+            // the query only roughly filters for ROOMSTATEs
+            _ => continue,
+        };
+        for (key, value) in irc_message.tags.0 {
+            if !NON_ROOM_TAGS.contains(&key.as_str()) {
+                tags.insert(key, value.unwrap_or_default());
+            }
+        }
+        last_received = Some(message.time_received);
+    }
+    last_received.map(|last_received| (tags, last_received))
+}
+
+/// The merged state as a `ROOMSTATE` message, tagged like exported messages.
+fn roomstate_message(
+    channel_login: &str,
+    tags: &BTreeMap<String, String>,
+    last_received: DateTime<Utc>,
+) -> String {
+    let mut irc_tags = tags
+        .iter()
+        .map(|(key, value)| (key.clone(), Some(value.clone())))
+        .collect::<HashMap<_, _>>();
+    irc_tags.insert("historical".to_owned(), Some("1".to_owned()));
+    irc_tags.insert(
+        "rm-received-ts".to_owned(),
+        Some(last_received.timestamp_millis().to_string()),
+    );
+    IRCMessage::new(
+        IRCTags(irc_tags),
+        Some(IRCPrefix::HostOnly {
+            host: "tmi.twitch.tv".to_owned(),
+        }),
+        "ROOMSTATE".to_owned(),
+        vec![format!("#{}", channel_login)],
+    )
+    .as_raw_irc()
+}
+
+// GET /api/v2/recent-messages/:channel_login/roomstate
+/// The current `ROOMSTATE` of the channel, merged from the stored `ROOMSTATE`s, so clients don't
+/// have to look for them in the recent messages.
+pub async fn get_roomstate(
+    path_options: Result<Path<GetRoomStatePath>, PathRejection>,
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<GetRoomStateResponse>, ApiError> {
+    let Path(GetRoomStatePath { channel_login }) =
+        path_options.map_err(|_| ApiError::InvalidPath)?;
+
+    let channel_login = super::normalize_channel_login(channel_login, &app_data.config.web)?;
+    let is_ignored = app_data
+        .data_storage
+        .is_channel_ignored(&channel_login)
+        .await
+        .map_err(ApiError::GetChannelIgnored)?;
+    if is_ignored {
+        return Err(ApiError::ChannelIgnored(channel_login));
+    }
+    if !app_data.data_storage.is_channel_available(&channel_login) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let stored_roomstates = app_data
+        .data_storage
+        .get_roomstates(&channel_login, MAX_MERGED_ROOMSTATES)
+        .await
+        .map_err(ApiError::GetMessages)?;
+
+    Ok(Json(match merge_roomstates(stored_roomstates) {
+        Some((tags, last_received)) => GetRoomStateResponse {
+            roomstate: Some(roomstate_message(&channel_login, &tags, last_received)),
+            tags,
+        },
+        None => GetRoomStateResponse {
+            roomstate: None,
+            tags: BTreeMap::new(),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_roomstates, roomstate_message};
+    use crate::db::StoredMessage;
+    use chrono::{TimeZone, Utc};
+    use twitch_irc::message::IRCMessage;
+
+    fn stored(id: i64, message_source: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            time_received: Utc.timestamp_millis_opt(1596061229295 + id).unwrap(),
+            message_source: message_source.to_owned(),
+        }
+    }
+
+    #[test]
+    fn partial_roomstates_are_merged() {
+        let (tags, last_received) = merge_roomstates(vec![
+            stored(0, "@emote-only=0;followers-only=-1;r9k=0;room-id=71092938;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #xqcow"),
+            stored(1, "@room-id=71092938;slow=5 :tmi.twitch.tv ROOMSTATE #xqcow"),
+            // mentions ROOMSTATE, but is not one
+            stored(2, ":randers!randers@randers.tmi.twitch.tv PRIVMSG #xqcow :ROOMSTATE #xqcow slow=99"),
+            stored(3, "@followers-only=10;room-id=71092938 :tmi.twitch.tv ROOMSTATE #xqcow"),
+        ])
+        .unwrap();
+        assert_eq!(tags["slow"], "5");
+        assert_eq!(tags["followers-only"], "10");
+        assert_eq!(tags["emote-only"], "0");
+        assert_eq!(
+            last_received,
+            Utc.timestamp_millis_opt(1596061229298).unwrap()
+        );
+
+        let message = IRCMessage::parse(&roomstate_message("xqcow", &tags, last_received)).unwrap();
+        assert_eq!(message.command, "ROOMSTATE");
+        assert_eq!(message.params, vec!["#xqcow"]);
+        assert_eq!(message.tags.0["slow"].as_deref(), Some("5"));
+        assert_eq!(message.tags.0["historical"].as_deref(), Some("1"));
+
+        assert!(merge_roomstates(vec![]).is_none());
+    }
+}
@@ -0,0 +1,23 @@
+use crate::web::WebAppData;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use http::StatusCode;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct HealthResponse {
+    irc_connected: bool,
+}
+
+// GET /api/v2/health
+/// Responds with `503 Service Unavailable` if no messages are being received from Twitch IRC, so
+/// monitoring can tell apart "up" from "up and ingesting messages".
+pub async fn get_health(Extension(app_data): Extension<WebAppData>) -> Response {
+    let irc_connected = app_data.irc_listener.is_connected();
+    let status_code = if irc_connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status_code, Json(HealthResponse { irc_connected })).into_response()
+}
@@ -4,7 +4,6 @@ use axum::extract::rejection::JsonRejection;
 use axum::{Extension, Json};
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
 #[derive(Serialize)]
 pub struct GetIgnoredResponse {
@@ -40,45 +39,42 @@ pub async fn set_ignored(
         ignored: should_be_ignored,
     }) = options.map_err(|_| ApiError::InvalidPayload)?;
 
-    app_data
+    let modified = app_data
         .data_storage
         .set_channel_ignored(&authorization.user_login, should_be_ignored)
         .await
         .map_err(ApiError::SetChannelIgnored)?;
+    if !modified && !should_be_ignored {
+        // ignoring a channel that is already ignored by an operator is fine, it stays ignored
+        return Err(ApiError::Forbidden(
+            "This channel was excluded from the service by an operator and can't be un-ignored",
+        ));
+    }
 
     if should_be_ignored {
-        // TODO: There can be messages getting added to the message store between the purge
-        // and the time that the PART command reaches the Twitch server. The 3 second time delay
-        // "solution" is a hack, needs a better solution
-        // maybe put a "blocker"/poison type into the db storage
-        app_data
-            .irc_listener
-            .irc_client
-            .part(authorization.user_login.clone());
-
-        app_data
-            .data_storage
-            .purge_messages(&authorization.user_login)
-            .await
-            .map_err(ApiError::PurgeMessages)?;
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(3)).await;
-            let result = app_data
-                .data_storage
-                .purge_messages(&authorization.user_login)
-                .await;
-            if let Err(e) = result {
-                tracing::error!("Failed to purge messages a second time: {}", e);
-            }
-        });
+        part_and_purge(app_data, authorization.user_login).await?;
     } else {
         app_data
             .irc_listener
-            .irc_client
-            .join(authorization.user_login)
-            .unwrap();
+            .join_unignored(authorization.user_login);
     }
 
     // 204 No Content, empty body
     Ok(StatusCode::NO_CONTENT)
 }
+
+/// Parts the channel and deletes its messages, returning the number of messages deleted. The
+/// channel must already be ignored, so no new messages are stored after this.
+pub async fn part_and_purge(app_data: WebAppData, channel_login: String) -> Result<u64, ApiError> {
+    // messages of the channel that are still received are dropped by the forwarder from now on.
+    // Inserts that already started are waited for, the ones that didn't start yet drop them.
+    app_data.irc_listener.part_ignored(channel_login.clone());
+    app_data.data_storage.wait_for_pending_inserts().await;
+
+    let num_purged = app_data
+        .data_storage
+        .purge_messages(&channel_login)
+        .await
+        .map_err(ApiError::PurgeMessages)?;
+    Ok(num_purged)
+}
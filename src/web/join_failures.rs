@@ -0,0 +1,37 @@
+use crate::web::error::ApiError;
+use crate::web::WebAppData;
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct JoinFailureResponse {
+    channel_login: String,
+    /// The `msg-id` of the `NOTICE`, e.g. `msg_channel_suspended`
+    reason: String,
+    time: DateTime<Utc>,
+}
+
+// GET /api/v2/join-failures
+/// Why channels recently failed to be joined, so operators can tell why a channel isn't being
+/// recorded. Disabled unless `web.expose_join_failures` is set, requires the `web.admin_token`.
+pub async fn get_join_failures(
+    Extension(app_data): Extension<WebAppData>,
+) -> Result<Json<Vec<JoinFailureResponse>>, ApiError> {
+    if !app_data.config.web.expose_join_failures {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(Json(
+        app_data
+            .irc_listener
+            .join_failures()
+            .into_iter()
+            .map(|(channel_login, failure)| JoinFailureResponse {
+                channel_login,
+                reason: failure.reason,
+                time: failure.time,
+            })
+            .collect(),
+    ))
+}
@@ -0,0 +1,40 @@
+use crate::web::error::ApiError;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use http::Request;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use tokio::sync::Semaphore;
+
+lazy_static! {
+    static ref HTTP_REQUESTS_SHED: IntCounter = register_int_counter!(
+        "http_requests_shed",
+        "Total number of HTTP requests rejected because web.max_concurrent_requests were already being handled"
+    )
+    .unwrap();
+}
+
+/// Not limited, so the service can still be monitored while it is overloaded.
+const UNLIMITED_PATHS: &[&str] = &["/api/v2/health", "/api/v2/metrics"];
+
+/// Rejects requests with `503 Service Unavailable` while `web.max_concurrent_requests` requests
+/// are already being handled, instead of letting them pile up waiting for database connections.
+pub async fn shed_load<B>(
+    req: Request<B>,
+    next: Next<B>,
+    request_permits: Option<&'static Semaphore>,
+) -> impl IntoResponse {
+    let request_permits = match request_permits {
+        Some(request_permits) if !UNLIMITED_PATHS.contains(&req.uri().path()) => request_permits,
+        _ => return Ok(next.run(req).await),
+    };
+
+    let _permit = match request_permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            HTTP_REQUESTS_SHED.inc();
+            return Err(ApiError::ServiceUnavailable);
+        }
+    };
+    Ok(next.run(req).await)
+}
@@ -1,16 +1,22 @@
-use crate::config::ListenAddr;
+use crate::config::{ListenAddr, WebConfig};
 use crate::irc_listener::IrcListener;
+use crate::logging::LogFilterHandle;
+use crate::web::channel_touch::ChannelTouchQueue;
+use crate::web::circuit_breaker::CircuitBreaker;
+use crate::web::client_ips::ClientIpTracker;
 use crate::web::error::ApiError;
 use crate::{Config, DataStorage};
+use axum::extract::DefaultBodyLimit;
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{middleware, Extension, Router};
 use futures::future::BoxFuture;
-use http::{header, Method, Request, StatusCode};
+use http::header::HeaderName;
+use http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
 use hyper::Body;
-use lazy_static::lazy_static;
 use std::net::SocketAddr;
 use thiserror::Error;
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 use tower::Service;
 use tower::ServiceBuilder;
@@ -22,15 +28,31 @@ use {
     std::path::Path,
 };
 
+mod account;
+mod admin;
 pub mod auth;
 mod auth_endpoints;
 mod auth_middleware;
+mod channel_settings;
+mod channel_touch;
+mod circuit_breaker;
+mod client_ips;
 pub mod error;
+mod get_message;
+mod get_message_histogram;
+mod get_message_window;
 mod get_metrics;
 pub mod get_recent_messages;
+mod get_roomstate;
+mod health;
 mod ignored;
+mod join_failures;
+mod load_shedding;
 mod purge;
 mod record_metrics;
+mod request_id;
+mod request_limits;
+mod security_headers;
 mod timeout;
 
 #[derive(Clone, Copy)]
@@ -38,10 +60,40 @@ pub struct WebAppData {
     data_storage: &'static DataStorage,
     irc_listener: &'static IrcListener,
     config: &'static Config,
+    http_client: &'static reqwest::Client,
+    twitch_api_circuit_breaker: &'static CircuitBreaker,
+    /// Only set if `web.track_client_ips` is enabled
+    client_ip_tracker: Option<&'static ClientIpTracker>,
+    channel_touch_queue: &'static ChannelTouchQueue,
+    log_filter: &'static LogFilterHandle,
 }
 
-lazy_static! {
-    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+/// Methods allowed by routes that only have a `GET` handler (which also answers `HEAD`), for the
+/// `Allow` header of 405 responses
+const ALLOW_GET: &[Method] = &[Method::GET, Method::HEAD];
+const ALLOW_POST: &[Method] = &[Method::POST];
+const ALLOW_GET_POST: &[Method] = &[Method::GET, Method::HEAD, Method::POST];
+
+const STATIC_FILES_DIR: &str = "web/dist";
+/// Served for all paths without a static file, so the frontend can handle its routes
+const SPA_INDEX_FILE: &str = "web/dist/index.html";
+
+const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " (+",
+    env!("CARGO_PKG_REPOSITORY"),
+    ")"
+);
+
+/// Builds the client used for all outgoing requests to the Twitch API.
+fn build_http_client(config: &Config) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(config.web.twitch_api_connect_timeout)
+        .timeout(config.web.twitch_api_timeout)
+        .build()
 }
 
 #[derive(Error, Debug)]
@@ -54,18 +106,91 @@ pub enum BindError {
     #[cfg(unix)]
     #[error("Failed to alter permissions on unix socket `{}` to `{1:?}`: {2}", .0.display())]
     SetPermissions(&'static Path, Permissions, std::io::Error),
+    #[error("Failed to build HTTP client: {0}")]
+    BuildHttpClient(reqwest::Error),
+    #[error("Invalid security header `{0}: {1}`")]
+    InvalidSecurityHeader(String, String),
+}
+
+/// Twitch logins are lowercase, so `Forsen` is the same channel as `forsen`. Lowercases the login
+/// unless `web.reject_uppercase_channel_logins` is set, then validates it.
+fn normalize_channel_login(channel_login: String, config: &WebConfig) -> Result<String, ApiError> {
+    let channel_login = if config.reject_uppercase_channel_logins {
+        channel_login
+    } else {
+        crate::db::normalize_channel_login(&channel_login).into_owned()
+    };
+    // e.g. from a client library sending an empty parameter, validate_login's error would be
+    // confusing for these
+    if channel_login.trim().is_empty() {
+        return Err(ApiError::InvalidChannelLogin(
+            "Channel login must not be empty".to_owned(),
+        ));
+    }
+    twitch_irc::validate::validate_login(&channel_login)
+        .map_err(|e| ApiError::InvalidChannelLogin(e.to_string()))?;
+    Ok(channel_login)
+}
+
+/// Whether unmatched requests to `path` get an API 404 instead of going to the frontend. Only
+/// `/api/v2` as a whole path segment counts, e.g. `/api/v2abc` is a frontend path.
+fn is_api_path(path: &str) -> bool {
+    path == "/api/v2" || path.starts_with("/api/v2/")
+}
+
+/// Parses the configured `web.security_headers`.
+fn build_security_headers(config: &Config) -> Result<HeaderMap, BindError> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.web.security_headers {
+        let invalid = || BindError::InvalidSecurityHeader(name.clone(), value.clone());
+        headers.insert(
+            HeaderName::try_from(name).map_err(|_| invalid())?,
+            HeaderValue::try_from(value).map_err(|_| invalid())?,
+        );
+    }
+    Ok(headers)
 }
 
 pub async fn run(
     data_storage: &'static DataStorage,
     irc_listener: &'static IrcListener,
     config: &'static Config,
+    log_filter: &'static LogFilterHandle,
     shutdown_signal: CancellationToken,
 ) -> Result<BoxFuture<'static, hyper::Result<()>>, BindError> {
+    let http_client = build_http_client(config).map_err(BindError::BuildHttpClient)?;
+    let http_client: &'static reqwest::Client = Box::leak(Box::new(http_client));
+    let twitch_api_circuit_breaker: &'static CircuitBreaker =
+        Box::leak(Box::new(CircuitBreaker::new(&config.web)));
+    let client_ip_tracker: Option<&'static ClientIpTracker> = if config.web.track_client_ips {
+        Some(Box::leak(Box::new(ClientIpTracker::start(
+            &config.web,
+            shutdown_signal.clone(),
+        ))))
+    } else {
+        None
+    };
+    let channel_touch_queue: &'static ChannelTouchQueue = Box::leak(Box::new(
+        ChannelTouchQueue::start(data_storage, irc_listener, config, shutdown_signal.clone()),
+    ));
+    let request_permits: Option<&'static Semaphore> =
+        config
+            .web
+            .max_concurrent_requests
+            .map(|max_concurrent_requests| {
+                &*Box::leak(Box::new(Semaphore::new(max_concurrent_requests)))
+            });
+    let security_headers: &'static HeaderMap = Box::leak(Box::new(build_security_headers(config)?));
+
     let shared_state = WebAppData {
         data_storage,
         irc_listener,
         config,
+        http_client,
+        twitch_api_circuit_breaker,
+        client_ip_tracker,
+        channel_touch_queue,
+        log_filter,
     };
 
     let cors = CorsLayer::new()
@@ -74,7 +199,9 @@ pub async fn run(
             header::AUTHORIZATION,
             header::ACCEPT,
             header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
         ])
+        .expose_headers(vec![header::ETAG, HeaderName::from_static("x-request-id")])
         .allow_origin(cors::Any);
 
     let auth_middleware = || {
@@ -82,56 +209,149 @@ pub async fn run(
             auth_middleware::with_authorization(req, next, shared_state)
         })
     };
-    let method_fallback = || (|| async { ApiError::MethodNotAllowed });
+    let admin_middleware = || {
+        middleware::from_fn(move |req, next| {
+            auth_middleware::with_admin_token(req, next, shared_state)
+        })
+    };
+    let method_fallback = |allowed_methods: &'static [Method]| {
+        move || async move { ApiError::MethodNotAllowed(allowed_methods) }
+    };
     let api = Router::new()
         .route(
             "/recent-messages/:channel_login",
-            get(get_recent_messages::get_recent_messages).fallback(method_fallback()),
+            get(get_recent_messages::get_recent_messages).fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/recent-messages/:channel_login/histogram",
+            get(get_message_histogram::get_message_histogram).fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/recent-messages/:channel_login/roomstate",
+            get(get_roomstate::get_roomstate).fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/message/:channel_login/:message_id",
+            get(get_message::get_message).fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/message-window/:channel_login",
+            get(get_message_window::get_message_window).fallback(method_fallback(ALLOW_GET)),
         )
         .route(
             "/ignored",
             get(ignored::get_ignored)
                 .post(ignored::set_ignored)
                 .route_layer(auth_middleware())
-                .fallback(method_fallback()),
+                .fallback(method_fallback(ALLOW_GET_POST)),
+        )
+        .route(
+            "/channel-settings",
+            get(channel_settings::get_channel_settings)
+                .post(channel_settings::set_channel_settings)
+                .route_layer(auth_middleware())
+                .fallback(method_fallback(ALLOW_GET_POST)),
         )
         .route(
             "/purge",
             post(purge::purge_messages)
                 .route_layer(auth_middleware())
-                .fallback(method_fallback()),
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/account/delete",
+            post(account::delete_account)
+                .route_layer(auth_middleware())
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/auth/authorize-url",
+            get(auth_endpoints::get_authorize_url).fallback(method_fallback(ALLOW_GET)),
         )
         .route(
             "/auth/create",
-            post(auth_endpoints::create_token).fallback(method_fallback()),
+            post(auth_endpoints::create_token).fallback(method_fallback(ALLOW_POST)),
         )
         .route(
             "/auth/extend",
             post(auth_endpoints::extend_token)
                 .route_layer(auth_middleware())
-                .fallback(method_fallback()),
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/auth/validate",
+            get(auth_endpoints::validate_token)
+                .route_layer(auth_middleware())
+                .fallback(method_fallback(ALLOW_GET)),
         )
         .route(
             "/auth/revoke",
             post(auth_endpoints::revoke_token)
                 .route_layer(auth_middleware())
-                .fallback(method_fallback()),
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/join-failures",
+            get(join_failures::get_join_failures)
+                .route_layer(admin_middleware())
+                .fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/admin/rejoin",
+            post(admin::force_rejoin)
+                .route_layer(admin_middleware())
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/admin/ignored/bulk",
+            post(admin::set_ignored_bulk)
+                .route_layer(admin_middleware())
+                .fallback(method_fallback(ALLOW_POST)),
+        )
+        .route(
+            "/admin/log-level",
+            get(admin::get_log_level)
+                .post(admin::set_log_level)
+                .route_layer(admin_middleware())
+                .fallback(method_fallback(ALLOW_GET_POST)),
+        )
+        .route(
+            "/admin/top-clients",
+            get(admin::get_top_clients)
+                .route_layer(admin_middleware())
+                .fallback(method_fallback(ALLOW_GET)),
+        )
+        .route(
+            "/health",
+            get(health::get_health).fallback(method_fallback(ALLOW_GET)),
         )
         .route(
             "/metrics",
-            get(get_metrics::get_metrics).fallback(method_fallback()),
+            get(get_metrics::get_metrics).fallback(method_fallback(ALLOW_GET)),
         )
         .layer(cors);
 
-    let mut servedir = ServeDir::new("web/dist")
+    let mut servedir = ServeDir::new(STATIC_FILES_DIR)
         .append_index_html_on_directories(true)
-        .fallback(ServeFile::new("web/dist/index.html"));
+        .fallback(ServeFile::new(SPA_INDEX_FILE));
+    // for API-only deployments, don't try (and fail) to serve the frontend on every request
+    let spa_index_exists = std::path::Path::new(SPA_INDEX_FILE).is_file();
+    if !spa_index_exists {
+        tracing::warn!(
+            "{} does not exist, the web frontend is not served (all requests outside of /api/v2 get a 404 Not Found)",
+            SPA_INDEX_FILE
+        );
+    }
 
     let app = Router::new()
         .nest("/api/v2", api)
         .fallback(|request: Request<Body>| async move {
-            if request.uri().path().starts_with("/api/v2/") || request.uri().path() == "/api/v2" {
+            // path parameters never match empty segments, so this is also where e.g.
+            // `/api/v2/recent-messages/` (without a channel login) ends up
+            if is_api_path(request.uri().path()) {
                 ApiError::NotFound.into_response()
+            } else if !spa_index_exists {
+                StatusCode::NOT_FOUND.into_response()
             } else {
                 // try for a file
                 match servedir.call(request).await {
@@ -146,7 +366,17 @@ pub async fn run(
         .layer(
             ServiceBuilder::new()
                 .layer(Extension(shared_state))
+                .layer(middleware::from_fn(move |req, next| {
+                    security_headers::add_security_headers(req, next, security_headers)
+                }))
+                .layer(middleware::from_fn(request_id::request_id))
+                .layer(middleware::from_fn(client_ips::track_client_ip))
                 .layer(middleware::from_fn(record_metrics::record_metrics))
+                .layer(middleware::from_fn(request_limits::limit_request_size))
+                .layer(DefaultBodyLimit::max(config.web.max_body_size))
+                .layer(middleware::from_fn(move |req, next| {
+                    load_shedding::shed_load(req, next, request_permits)
+                }))
                 .layer(middleware::from_fn(timeout::timeout)),
         );
 
@@ -154,7 +384,7 @@ pub async fn run(
         ListenAddr::Tcp { address } => Box::pin(
             axum::Server::try_bind(address)
                 .map_err(|e| BindError::BindTcp(address, e))?
-                .serve(app.into_make_service())
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .with_graceful_shutdown(async move {
                     shutdown_signal.cancelled().await;
                 }),
@@ -177,3 +407,68 @@ pub async fn run(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_api_path, normalize_channel_login};
+    use crate::config::test_web_config;
+    use crate::web::error::ApiError;
+
+    #[test]
+    fn mixed_case_channel_logins_are_lowercased_or_rejected() {
+        let mut config = test_web_config("");
+        assert_eq!(
+            normalize_channel_login("Forsen".to_owned(), &config).unwrap(),
+            normalize_channel_login("forsen".to_owned(), &config).unwrap()
+        );
+        assert!(matches!(
+            normalize_channel_login("for sen".to_owned(), &config),
+            Err(ApiError::InvalidChannelLogin(_))
+        ));
+
+        config.reject_uppercase_channel_logins = true;
+        assert!(matches!(
+            normalize_channel_login("Forsen".to_owned(), &config),
+            Err(ApiError::InvalidChannelLogin(_))
+        ));
+        assert_eq!(
+            normalize_channel_login("forsen".to_owned(), &config).unwrap(),
+            "forsen"
+        );
+    }
+
+    #[test]
+    fn empty_channel_logins_are_rejected() {
+        let config = test_web_config("");
+        for channel_login in ["", " ", "\t \n"] {
+            let err = normalize_channel_login(channel_login.to_owned(), &config).unwrap_err();
+            assert!(matches!(err, ApiError::InvalidChannelLogin(_)));
+            assert_eq!(
+                err.to_string(),
+                "Invalid channel login: Channel login must not be empty"
+            );
+        }
+    }
+
+    #[test]
+    fn only_paths_under_the_api_prefix_are_api_paths() {
+        for path in [
+            "/api/v2",
+            "/api/v2/",
+            "/api/v2/does-not-exist",
+            "/api/v2/recent-messages/",
+        ] {
+            assert!(is_api_path(path), "{}", path);
+        }
+        for path in [
+            "/",
+            "/api",
+            "/api/",
+            "/api/v2abc",
+            "/api/v2x/foo",
+            "/api/v1/foo",
+        ] {
+            assert!(!is_api_path(path), "{}", path);
+        }
+    }
+}
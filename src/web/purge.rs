@@ -1,13 +1,49 @@
 use crate::web::auth::UserAuthorization;
 use crate::web::error::ApiError;
 use crate::web::WebAppData;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::Query;
 use axum::Extension;
 use http::StatusCode;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PurgeQueryOptions {
+    /// Also part the channel, so no new messages are stored until it is requested again
+    part: bool,
+}
 
 pub async fn purge_messages(
     Extension(authorization): Extension<UserAuthorization>,
     app_data: Extension<WebAppData>,
+    query_options: Result<Query<PurgeQueryOptions>, QueryRejection>,
 ) -> Result<StatusCode, ApiError> {
+    let Query(query_options) = query_options.map_err(|_| ApiError::InvalidQuery)?;
+    if !app_data
+        .data_storage
+        .is_channel_available(&authorization.user_login)
+    {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    if query_options.part {
+        // otherwise the channel is joined again by the next join/part run
+        app_data
+            .data_storage
+            .forget_channel(&authorization.user_login)
+            .await
+            .map_err(ApiError::ForgetChannel)?;
+        let res =
+            super::ignored::part_and_purge(app_data.0, authorization.user_login.clone()).await;
+        // the channel isn't ignored, so its messages are stored again once it is requested again
+        app_data
+            .irc_listener
+            .stop_dropping_messages(&authorization.user_login);
+        res?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
     app_data
         .data_storage
         .purge_messages(&authorization.user_login)
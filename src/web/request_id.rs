@@ -0,0 +1,68 @@
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use http::header::HeaderName;
+use http::{HeaderValue, Request};
+use tracing::Instrument;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request currently being handled, if called from within a request handler.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+/// Random version 4 UUID, e.g. `0a5c2e3d-7f4b-4c1e-9d2a-6b8e1f0c3a7d`
+fn generate_request_id() -> String {
+    // set the version (4) and variant (RFC 4122) bits
+    let bits = (rand::random::<u128>() & !(0xf_u128 << 76) & !(0xc_u128 << 60))
+        | (0x4_u128 << 76)
+        | (0x8_u128 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Assigns every request a random ID, which is returned in the `X-Request-Id` header and attached
+/// to all log messages emitted while handling the request.
+pub async fn request_id<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let request_id = generate_request_id();
+    let span = tracing::info_span!("request", request_id = %request_id);
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(req).instrument(span))
+        .await;
+    response.headers_mut().insert(
+        HeaderName::from_static("x-request-id"),
+        HeaderValue::from_str(&request_id).unwrap(),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate_request_id;
+
+    #[test]
+    fn request_ids_are_v4_uuids() {
+        let request_id = generate_request_id();
+        assert_eq!(request_id.len(), 36);
+        let groups = request_id.split('-').collect::<Vec<_>>();
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            vec![8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(
+            groups[3].chars().next(),
+            Some('8' | '9' | 'a' | 'b')
+        ));
+    }
+}
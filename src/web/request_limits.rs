@@ -0,0 +1,68 @@
+use crate::web::error::ApiError;
+use crate::web::WebAppData;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::{header, HeaderMap, Request};
+
+/// Checks the query string and the declared `Content-Length` against `web.max_query_length` and
+/// `web.max_body_size`. Bodies without a `Content-Length` are limited while they are read, see
+/// `DefaultBodyLimit` in `web::run`.
+fn check_request_size(
+    query: Option<&str>,
+    headers: &HeaderMap,
+    max_query_length: usize,
+    max_body_size: usize,
+) -> Result<(), ApiError> {
+    if query.map_or(0, str::len) > max_query_length {
+        return Err(ApiError::QueryTooLong(max_query_length));
+    }
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.map_or(false, |length| length > max_body_size as u64) {
+        return Err(ApiError::PayloadTooLarge(max_body_size));
+    }
+    Ok(())
+}
+
+/// Rejects requests with an overly large query string or body before they are parsed.
+pub async fn limit_request_size<B>(req: Request<B>, next: Next<B>) -> Response {
+    let web_config = &req.extensions().get::<WebAppData>().unwrap().config.web;
+    match check_request_size(
+        req.uri().query(),
+        req.headers(),
+        web_config.max_query_length,
+        web_config.max_body_size,
+    ) {
+        Ok(()) => next.run(req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_request_size;
+    use crate::web::error::ApiError;
+    use http::{header, HeaderMap, HeaderValue};
+
+    #[test]
+    fn requests_over_the_limits_are_rejected() {
+        let no_headers = HeaderMap::new();
+        assert!(check_request_size(None, &no_headers, 10, 100).is_ok());
+        assert!(check_request_size(Some("limit=1234"), &no_headers, 10, 100).is_ok());
+        assert!(matches!(
+            check_request_size(Some("limit=12345"), &no_headers, 10, 100),
+            Err(ApiError::QueryTooLong(10))
+        ));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("100"));
+        assert!(check_request_size(None, &headers, 10, 100).is_ok());
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("101"));
+        assert!(matches!(
+            check_request_size(None, &headers, 10, 100),
+            Err(ApiError::PayloadTooLarge(100))
+        ));
+    }
+}
@@ -0,0 +1,20 @@
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use http::{HeaderMap, Request};
+
+/// Adds the configured `web.security_headers` to the response, unless the handler already set
+/// them.
+pub async fn add_security_headers<B>(
+    req: Request<B>,
+    next: Next<B>,
+    security_headers: &'static HeaderMap,
+) -> impl IntoResponse {
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    for (name, value) in security_headers {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
@@ -1,11 +1,11 @@
 let cfg = {
-  client_id: "nld8y6rt7f5u7l1xuq4eni8pzp8mjo",
-  redirect_uri: "https://recent-messages.robotty.de/authorized",
   // human readable strings for the home page and API documentation
   messages_expire_after: "24 hours",
   channels_expire_after: "24 hours",
   sessions_expire_after: "7 days",
   max_buffer_size: "800",
+  // maximum number of messages returned by a single API request
+  max_request_limit: "800",
   github_link: "https://github.com/robotty/recent-messages2",
   // used for both the documentation as well as the actual API calls made by the web app. Don't include a trailing slash
   api_base_url: "https://recent-messages.robotty.de/api/v2",
@@ -38,8 +38,6 @@ export type Config = typeof cfg;
 if (process.env.NODE_ENV === "development") {
   cfg = {
     ...cfg,
-    client_id: "199iyze11rzmuu05ddxqzix4g8soou",
-    redirect_uri: "http://localhost:2790/authorized",
     api_base_url: "http://localhost:2790/api/v2",
   };
 }
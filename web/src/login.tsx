@@ -11,8 +11,10 @@ class Login extends React.Component<
     updateAuthState: (newAuthState: AuthState) => void;
     location: Location;
   },
-  {}
+  { failed: boolean }
 > {
+  state = { failed: false };
+
   componentDidMount() {
     let randomBytes = window.crypto.getRandomValues(new Uint8Array(32)).buffer; // 256 bits of entropy (32 * 8 bits)
     let csrfToken = arrayBufferToHex(randomBytes);
@@ -35,12 +37,30 @@ class Login extends React.Component<
 
     this.props.updateAuthState({ type: "loading" });
 
-    let authorizeUrl = `https://id.twitch.tv/oauth2/authorize?client_id=${encodeURIComponent(
-      config.client_id
-    )}&redirect_uri=${encodeURIComponent(
-      config.redirect_uri
-    )}&response_type=code&scope=&state=${encodeURIComponent(csrfToken)}`;
-    window.location.replace(authorizeUrl);
+    // the client ID and redirect URI are configured on the server, it builds the URL for us
+    (async () => {
+      try {
+        const response = await fetch(
+          `${config.api_base_url}/auth/authorize-url?state=${encodeURIComponent(
+            csrfToken
+          )}`,
+          {
+            headers: {
+              Accept: "application/json",
+            },
+          }
+        );
+        if (!response.ok) {
+          throw Error(response.statusText);
+        }
+        const json = await response.json();
+        window.location.replace(json["authorize_url"]);
+      } catch (err) {
+        console.error("API Request to get the authorize URL failed", err);
+        this.setState({ failed: true });
+        this.props.updateAuthState({ type: "missing" });
+      }
+    })();
   }
 
   componentWillUnmount() {
@@ -48,6 +68,19 @@ class Login extends React.Component<
   }
 
   render() {
+    if (this.state.failed) {
+      return (
+        <>
+          <h1>Login</h1>
+          <Alert fade={false} color="danger">
+            <h4 className="alert-heading">Failed to log in</h4>
+            API Request to get the authorize URL failed.{" "}
+            <Link to="/">Return to the home page</Link>
+          </Alert>
+        </>
+      );
+    }
+
     return (
       <>
         <h1>Login</h1>
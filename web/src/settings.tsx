@@ -31,6 +31,12 @@ class SettingsLoggedIn extends React.Component<
     purgeSuccess: boolean;
     purgeFailed: boolean;
     purgeButtonTooltipOpen: boolean;
+    forcedExportOptions: string[];
+    loadingChannelSettings: boolean;
+    loadingChannelSettingsFailed: boolean;
+    savingChannelSettings: boolean;
+    savingChannelSettingsSuccess: boolean;
+    savingChannelSettingsFailed: boolean;
   }
 > {
   constructor(props: SettingsLoggedInComponentProps) {
@@ -46,10 +52,18 @@ class SettingsLoggedIn extends React.Component<
       purgeSuccess: false,
       purgeFailed: false,
       purgeButtonTooltipOpen: false,
+      forcedExportOptions: [],
+      loadingChannelSettings: false,
+      loadingChannelSettingsFailed: false,
+      savingChannelSettings: false,
+      savingChannelSettingsSuccess: false,
+      savingChannelSettingsFailed: false,
     };
     this.updateIgnored = this.updateIgnored.bind(this);
     this.purgeMessages = this.purgeMessages.bind(this);
     this.togglePurgeButtonTooltip = this.togglePurgeButtonTooltip.bind(this);
+    this.updateHideModeratedMessages =
+      this.updateHideModeratedMessages.bind(this);
   }
 
   componentDidMount() {
@@ -92,6 +106,99 @@ class SettingsLoggedIn extends React.Component<
         });
       }
     })();
+
+    this.setState(() => {
+      return {
+        loadingChannelSettings: true,
+        loadingChannelSettingsFailed: false,
+      };
+    });
+
+    (async () => {
+      try {
+        let response = await fetch(`${config.api_base_url}/channel-settings`, {
+          method: "GET",
+          headers: {
+            Authorization: `Bearer ${this.props.auth.accessToken}`,
+            Accept: "application/json",
+          },
+        });
+        if (!response.ok) {
+          throw Error(response.statusText);
+        }
+
+        let json = await response.json();
+
+        this.setState(() => {
+          return {
+            forcedExportOptions: json["forced_export_options"],
+            loadingChannelSettings: false,
+            loadingChannelSettingsFailed: false,
+          };
+        });
+      } catch (err) {
+        console.error("Failed to load channel settings", err);
+        this.setState(() => {
+          return {
+            loadingChannelSettings: false,
+            loadingChannelSettingsFailed: true,
+          };
+        });
+      }
+    })();
+  }
+
+  updateHideModeratedMessages(e: ChangeEvent<HTMLInputElement>) {
+    let previousSetting = this.state.forcedExportOptions;
+    // other options (only settable via the API) are kept
+    let newSetting = previousSetting.filter(
+      (option) => option !== "hide_moderated_messages"
+    );
+    if (e.target.checked) {
+      newSetting.push("hide_moderated_messages");
+    }
+
+    this.setState(() => {
+      return {
+        forcedExportOptions: newSetting,
+        savingChannelSettings: true,
+        savingChannelSettingsFailed: false,
+        savingChannelSettingsSuccess: false,
+      };
+    });
+
+    (async () => {
+      try {
+        const response = await fetch(`${config.api_base_url}/channel-settings`, {
+          method: "POST",
+          headers: {
+            Authorization: `Bearer ${this.props.auth.accessToken}`,
+            Accept: "application/json",
+            "Content-Type": "application/json",
+          },
+          body: JSON.stringify({ forced_export_options: newSetting }),
+        });
+        if (!response.ok) {
+          throw Error(response.statusText);
+        }
+
+        this.setState(() => {
+          return {
+            savingChannelSettings: false,
+            savingChannelSettingsSuccess: true,
+          };
+        });
+      } catch (err) {
+        console.error("Failed to save channel settings", err);
+        this.setState(() => {
+          return {
+            forcedExportOptions: previousSetting,
+            savingChannelSettings: false,
+            savingChannelSettingsFailed: true,
+          };
+        });
+      }
+    })();
   }
 
   updateIgnored(e: ChangeEvent<HTMLInputElement>) {
@@ -251,6 +358,61 @@ class SettingsLoggedIn extends React.Component<
                 your channel will be deleted.
               </small>
             </FormGroup>
+            <FormGroup>
+              <div>
+                <CustomInput
+                  inline
+                  type="switch"
+                  id="hide-moderated-messages"
+                  label="Hide deleted messages"
+                  disabled={
+                    this.state.savingChannelSettings ||
+                    this.state.loadingChannelSettings ||
+                    this.state.loadingChannelSettingsFailed
+                  }
+                  checked={this.state.forcedExportOptions.includes(
+                    "hide_moderated_messages"
+                  )}
+                  onChange={this.updateHideModeratedMessages}
+                  aria-describedby="hide-moderated-messages-help-block"
+                />
+                {(this.state.savingChannelSettings ||
+                  this.state.loadingChannelSettings) && (
+                  <Spinner className="mr-2" size="sm" color="primary" />
+                )}
+                {this.state.savingChannelSettings && (
+                  <span className="text-primary">Saving changes…</span>
+                )}
+                {this.state.loadingChannelSettings && (
+                  <span className="text-primary">Loading settings…</span>
+                )}
+                {this.state.savingChannelSettingsSuccess && (
+                  <span className="text-success">
+                    <i className="fas fa-check mr-1" /> Saved.
+                  </span>
+                )}
+                {this.state.savingChannelSettingsFailed && (
+                  <span className="text-danger">
+                    <i className="fas fa-times mr-1" /> Failed to save. Please
+                    try again.
+                  </span>
+                )}
+                {this.state.loadingChannelSettingsFailed && (
+                  <span className="text-danger">
+                    <i className="fas fa-times mr-1" /> Failed to load current
+                    setting. Please try again.
+                  </span>
+                )}
+              </div>
+              <small
+                className="form-text text-muted"
+                id="hide-moderated-messages-help-block"
+              >
+                Messages that were deleted, or whose sender was timed out or
+                banned, are never shown in your channel's recent messages,
+                regardless of the settings of the chat client loading them.
+              </small>
+            </FormGroup>
             <FormGroup>
               <div>
                 <span className="d-inline-block" id="purge-button">
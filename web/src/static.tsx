@@ -236,6 +236,13 @@ export function API() {
             or <code>CLEARMSG</code> message. Optional, defaults to{" "}
             <code>false</code>.
           </li>
+          <li>
+            <code>?hide_room_mode_notices=true/false</code>: Omits the{" "}
+            <code>NOTICE</code>s announcing room mode changes (emote-only,
+            followers-only, slow mode, subscribers-only and unique chat turned
+            on or off). The <code>ROOMSTATE</code> messages with the modes
+            are still returned. Optional, defaults to <code>false</code>.
+          </li>
           <li>
             <p>
               <code>?clearchat_to_notice=true/false</code>: Converts{" "}
@@ -285,7 +292,7 @@ export function API() {
             more than <code>n</code> messages are available for the requested
             channel, the response is limited to the <code>n</code> newest
             messages. Optional, defaults to no limit (up to{" "}
-            {config.max_buffer_size} messages). Can be combined with{" "}
+            {config.max_request_limit} messages). Can be combined with{" "}
             <code>?before=</code> and <code>?after=</code>, the three
             requirements combine using a logical AND.
             <br />
@@ -311,6 +318,54 @@ export function API() {
             <code>?before=</code>, the three requirements combine using a
             logical AND.
           </li>
+          <li>
+            <code>?since=n&amp;since_id=m</code>: For polling clients: Only
+            return messages that were received after (<code>&gt;</code>) this
+            timestamp (in milliseconds since the unix epoch), oldest first.
+            Every response has a <code>watermark</code> and a{" "}
+            <code>watermark_id</code> field identifying the newest returned
+            message, pass them as <code>?since=</code> and{" "}
+            <code>?since_id=</code> on the next request to only get the messages
+            received since, including other messages from the same millisecond.
+            If there are more new messages than fit into one response (see{" "}
+            <code>?limit=</code>), the response has{" "}
+            <code>"has_more": true</code> and the next request returns the
+            rest. If there are no newer messages, <code>messages</code> is empty
+            and the watermark stays the same. <code>?since_id=</code> is
+            optional, can be combined with the other options like{" "}
+            <code>?before=</code>.
+          </li>
+          <li>
+            <code>?include_total=true/false</code>: Additionally return the
+            total number of messages currently stored for the channel in the{" "}
+            <code>total_stored</code> field of the response, regardless of{" "}
+            <code>?limit=</code>, <code>?before=</code> and{" "}
+            <code>?after=</code>. Optional, defaults to <code>false</code>.
+          </li>
+          <li>
+            <code>?timestamp_format=millis/rfc3339</code>: If set to{" "}
+            <code>rfc3339</code>, all messages additionally carry the{" "}
+            <code>rm-received-time</code> tag, containing the same timestamp as{" "}
+            <code>rm-received-ts</code> in RFC 3339 format (e.g.{" "}
+            <code>2020-07-29T22:17:37.185Z</code>). Optional, defaults to{" "}
+            <code>millis</code>.
+          </li>
+          <li>
+            <code>?wait_for_join_ms=n</code>: If the service is not yet
+            listening to the channel, wait up to <code>n</code> milliseconds for
+            it to join the channel before responding. The wait is capped by the
+            server. Optional, defaults to not waiting.
+          </li>
+          <li>
+            <code>?batch=true/false</code>: If set to <code>true</code>, the
+            messages are wrapped in an{" "}
+            <a href="https://ircv3.net/specs/extensions/batch">IRCv3 batch</a>{" "}
+            of type <code>rm.recent-messages</code>: The first message is{" "}
+            <code>BATCH +ref rm.recent-messages</code>, the last message is{" "}
+            <code>BATCH -ref</code>, and all messages in between carry the{" "}
+            <code>batch=ref</code> tag. <code>ref</code> is random for every
+            response. Optional, defaults to <code>false</code>.
+          </li>
         </ul>
         <h6>Response format:</h6>
         <pre>
@@ -327,18 +382,28 @@ export function API() {
         "@badge-info=subscriber/29;badges=subscriber/24;color=#7AC2A7;display-name=gw_ua;emotes=;flags=;historical=1;id=3391449d-3427-490f-836b-f5b8c1c98b93;mod=0;rm-deleted=1;rm-received-ts=1596059993412;room-id=71092938;subscriber=1;tmi-sent-ts=1596059993026;turbo=0;user-id=81302568;user-type= :gw_ua!gw_ua@gw_ua.tmi.twitch.tv PRIVMSG #xqcow :gn i guess",
         "@historical=1;login=gw_ua;rm-received-ts=1596061327989;room-id=;target-msg-id=3391449d-3427-490f-836b-f5b8c1c98b93;tmi-sent-ts=1596061327703 :tmi.twitch.tv CLEARMSG #xqcow :gn i guess",
     ],
+    "status": "joined_with_messages",
+    "backlog_status": "ready",
     "error": null,
-    "error_code": null
+    "error_code": null,
+    "truncated": false,
+    "watermark": 1596061327989,
+    "watermark_id": 81526273
 }`}
           </code>
         </pre>
         <p>
-          Returns up to {config.max_buffer_size} messages. Messages are ordered
+          Returns up to {config.max_request_limit} messages. Messages are ordered
           oldest-to-newest. Messages are retured in raw IRC format, without
           trailing newline(s). The API returns <code>PRIVMSG</code>,{" "}
           <code>CLEARCHAT</code>, <code>CLEARMSG</code>, <code>USERNOTICE</code>
           , <code>NOTICE</code> and <code>ROOMSTATE</code> messages.
         </p>
+        <p>
+          If the messages would exceed the size limit configured on the server,
+          the oldest messages are left out and <code>truncated</code> is{" "}
+          <code>true</code>.
+        </p>
         <p>
           All messages returned carry the <code>historical=1</code> tag.
         </p>
@@ -435,7 +500,53 @@ export function API() {
           if this request is the first request for that channel. However there
           are many more combinations of internal events that can cause ta
           channel to currently not be joined, such as a service restart, a
-          reconnect, etc.
+          new join that failed, etc. While the service is only rejoining a
+          channel after reconnecting, no error is returned and the{" "}
+          <code>status</code> is <code>reconnecting</code>.
+        </p>
+        <p>
+          The <code>status</code> field describes the state of the channel in
+          more detail, it is one of:
+        </p>
+        <ul>
+          <li>
+            <code>never_requested</code>: The channel has not been requested
+            before (or not recently enough), the service will now start
+            listening to it.
+          </li>
+          <li>
+            <code>joining</code>: The service is currently trying to join the
+            channel.
+          </li>
+          <li>
+            <code>reconnecting</code>: The service was listening to the
+            channel and is currently rejoining it after a reconnect. Messages
+            sent during the reconnect may be missing, but this is not reported
+            as an error.
+          </li>
+          <li>
+            <code>joined_empty</code>: The service is listening to the channel,
+            but there are no messages to return.
+          </li>
+          <li>
+            <code>joined_with_messages</code>: The service is listening to the
+            channel and messages were returned.
+          </li>
+          <li>
+            <code>channel_ignored</code>: The channel is excluded from the
+            service, no messages are returned. Only on instances configured to
+            answer requests for ignored channels like this instead of with the
+            403 error below.
+          </li>
+        </ul>
+        <p>
+          <code>backlog_status</code> is <code>filling</code> while the service
+          has only been listening to the channel for a short time, so there may
+          be fewer messages than the recent activity in the channel suggests.
+          Clients can show that messages are still being collected instead of
+          showing that there are no messages. It is <code>ready</code> once the
+          service has been listening for a while, or the stored messages go back
+          that far. For ignored channels, it is <code>not_recorded</code>.
         </p>
         <h6>Errors</h6>
         If the provided channel is blacklisted from the service (ignored), HTTP
@@ -459,6 +570,75 @@ export function API() {
     "status_message": "Bad Request",
     "error": "Invalid channel login \`this_name_is_way_tooooo_long\`",
     "error_code": "invalid_channel_login"
+}`}
+          </code>
+        </pre>
+      </section>
+      <section>
+        <h5>Get message counts over time</h5>
+        <p>
+          <code>
+            GET {config.api_base_url}/recent-messages/:channel_login/histogram
+          </code>
+        </p>
+        <p>
+          Returns the number of stored messages of the channel per time bucket,
+          covering the time messages are kept for (at most 1000 buckets, the
+          newest ones). Buckets are aligned to the unix epoch, so hourly and
+          daily buckets start at full UTC hours and days. Buckets without
+          messages have a <code>count</code> of 0.
+        </p>
+        <h6>Query parameters:</h6>
+        <ul>
+          <li>
+            <code>?bucket=1h</code>: Length of a bucket, e.g.{" "}
+            <code>15m</code>, <code>1h</code> or <code>1d</code>. Must be whole
+            seconds, at least one minute and at most as long as messages are
+            kept for. Optional, defaults to <code>1h</code>.
+          </li>
+        </ul>
+        <h6>Example response</h6>
+        <pre>
+          <code>
+            {`{
+    "bucket_seconds": 3600,
+    "buckets": [
+        { "start": 1596056400000, "count": 0 },
+        { "start": 1596060000000, "count": 1312 }
+    ]
+}`}
+          </code>
+        </pre>
+      </section>
+      <section>
+        <h5>Get the current room state</h5>
+        <p>
+          <code>
+            GET {config.api_base_url}/recent-messages/:channel_login/roomstate
+          </code>
+        </p>
+        <p>
+          Returns the current <code>ROOMSTATE</code> of the channel (slow mode,
+          followers-only mode, etc.), merged from the stored{" "}
+          <code>ROOMSTATE</code> messages. Twitch only sends the changed
+          settings after the first <code>ROOMSTATE</code>, so this saves
+          clients from looking through the recent messages for all of them.{" "}
+          <code>roomstate</code> is <code>null</code> if no{" "}
+          <code>ROOMSTATE</code> of the channel is stored.
+        </p>
+        <h6>Example response</h6>
+        <pre>
+          <code>
+            {`{
+    "roomstate": "@emote-only=0;followers-only=10;historical=1;r9k=0;rm-received-ts=1596061229295;room-id=71092938;slow=5;subs-only=0 :tmi.twitch.tv ROOMSTATE #xqcow",
+    "tags": {
+        "emote-only": "0",
+        "followers-only": "10",
+        "r9k": "0",
+        "room-id": "71092938",
+        "slow": "5",
+        "subs-only": "0"
+    }
 }`}
           </code>
         </pre>